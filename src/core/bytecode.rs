@@ -0,0 +1,461 @@
+use crate::core::*;
+
+/// A slot in the frame of a function, every var has the slot with its number.
+type Slot = u32;
+
+/// A bytecode instruction. They are all the same size, jumps go straight to an
+/// instruction, and the ops are picked for the types they work on.
+#[derive(Debug, Clone, Copy)]
+pub enum Code {
+    AddI32(Slot, Slot, Slot),
+    SubI32(Slot, Slot, Slot),
+    MulI32(Slot, Slot, Slot),
+    DivI32(Slot, Slot, Slot),
+    EqI32(Slot, Slot, Slot),
+    NeI32(Slot, Slot, Slot),
+    LtI32(Slot, Slot, Slot),
+    LeI32(Slot, Slot, Slot),
+    GtI32(Slot, Slot, Slot),
+    GeI32(Slot, Slot, Slot),
+    NegI32(Slot, Slot),
+
+    AddF64(Slot, Slot, Slot),
+    SubF64(Slot, Slot, Slot),
+    MulF64(Slot, Slot, Slot),
+    DivF64(Slot, Slot, Slot),
+    EqF64(Slot, Slot, Slot),
+    NeF64(Slot, Slot, Slot),
+    LtF64(Slot, Slot, Slot),
+    LeF64(Slot, Slot, Slot),
+    GtF64(Slot, Slot, Slot),
+    GeF64(Slot, Slot, Slot),
+    NegF64(Slot, Slot),
+
+    /// An op on types that don't have their own instruction.
+    Op(Slot, Op, Slot, Slot),
+    UOp(Slot, UOp, Slot),
+
+    ConstI32(Slot, i32),
+    ConstF64(Slot, f64),
+    ConstBool(Slot, bool),
+    /// Copy a const of the chunk, like a string.
+    Const(Slot, u32),
+    Move(Slot, Slot),
+
+    Jump(u32),
+    /// Go to the first instruction if the slot is true, else to the second.
+    Branch(Slot, u32, u32),
+    /// Call a function with the args starting at an index of the args of the chunk.
+    Call(Slot, u32, u32),
+    /// Call the function again in place of this call, with its result as the result.
+    TailCall(u32, u32),
+    CallExtern(Slot, u32, u32),
+    Return(Slot),
+    /// The end of a function, which can't be reached if it always returns.
+    Unreachable,
+}
+
+/// The bytecode of a function.
+pub struct Chunk {
+    pub name: String,
+    pub code: Vec<Code>,
+    /// The slots passed to calls, each list of them starts with how many there are.
+    pub args: Vec<Slot>,
+    pub consts: Vec<Value>,
+    /// How many slots the frame has, the vars and then some for moving block args.
+    pub slots: usize,
+    /// How many bytes the vars take up, for the memory limit.
+    pub bytes: usize,
+}
+
+impl Chunk {
+    /// Compile <func>, which has the id <func_id>.
+    pub fn new(func_id: FuncId, func: &Func) -> Chunk {
+        let mut chunk = Chunk {
+            name: func.name.clone(),
+            code: vec![],
+            args: vec![],
+            consts: vec![],
+            slots: func.ir.num_vars,
+            bytes: func.ir.var_type.iter().map(|typ| typ.size()).sum(),
+        };
+
+        // where the code of each instruction starts, jumps are fixed up after
+        let mut starts = vec![];
+        for (i, inst) in func.ir.insts.iter().enumerate() {
+            starts.push(chunk.code.len() as u32);
+            let next = func.ir.insts.get(i + 1);
+            chunk.add_inst(func_id, func, inst, next);
+        }
+        starts.push(chunk.code.len() as u32);
+        chunk.code.push(Code::Unreachable);
+
+        let start = |block: u32| starts[func.ir.blocks[block as usize]];
+        for code in &mut chunk.code {
+            match code {
+                Code::Jump(block) => *block = start(*block),
+                Code::Branch(_, a, b) => {
+                    *a = start(*a);
+                    *b = start(*b);
+                }
+                _ => {}
+            }
+        }
+
+        return chunk;
+    }
+
+    fn add_inst(&mut self, func_id: FuncId, func: &Func, inst: &Inst, next: Option<&Inst>) {
+        let slot = |var: &Var| *var as Slot;
+
+        let code = match inst {
+            Inst::Op(var, op, a, b) => {
+                let (var, a, b) = (slot(var), slot(a), slot(b));
+                let types = (func.ir.var_type[a as usize], func.ir.var_type[b as usize]);
+                match (op, types) {
+                    (Op::Add, (TypeDef::I32, TypeDef::I32)) => Code::AddI32(var, a, b),
+                    (Op::Sub, (TypeDef::I32, TypeDef::I32)) => Code::SubI32(var, a, b),
+                    (Op::Mul, (TypeDef::I32, TypeDef::I32)) => Code::MulI32(var, a, b),
+                    (Op::Div, (TypeDef::I32, TypeDef::I32)) => Code::DivI32(var, a, b),
+                    (Op::Eq, (TypeDef::I32, TypeDef::I32)) => Code::EqI32(var, a, b),
+                    (Op::Ne, (TypeDef::I32, TypeDef::I32)) => Code::NeI32(var, a, b),
+                    (Op::Lt, (TypeDef::I32, TypeDef::I32)) => Code::LtI32(var, a, b),
+                    (Op::Le, (TypeDef::I32, TypeDef::I32)) => Code::LeI32(var, a, b),
+                    (Op::Gt, (TypeDef::I32, TypeDef::I32)) => Code::GtI32(var, a, b),
+                    (Op::Ge, (TypeDef::I32, TypeDef::I32)) => Code::GeI32(var, a, b),
+
+                    (Op::Add, (TypeDef::F64, TypeDef::F64)) => Code::AddF64(var, a, b),
+                    (Op::Sub, (TypeDef::F64, TypeDef::F64)) => Code::SubF64(var, a, b),
+                    (Op::Mul, (TypeDef::F64, TypeDef::F64)) => Code::MulF64(var, a, b),
+                    (Op::Div, (TypeDef::F64, TypeDef::F64)) => Code::DivF64(var, a, b),
+                    (Op::Eq, (TypeDef::F64, TypeDef::F64)) => Code::EqF64(var, a, b),
+                    (Op::Ne, (TypeDef::F64, TypeDef::F64)) => Code::NeF64(var, a, b),
+                    (Op::Lt, (TypeDef::F64, TypeDef::F64)) => Code::LtF64(var, a, b),
+                    (Op::Le, (TypeDef::F64, TypeDef::F64)) => Code::LeF64(var, a, b),
+                    (Op::Gt, (TypeDef::F64, TypeDef::F64)) => Code::GtF64(var, a, b),
+                    (Op::Ge, (TypeDef::F64, TypeDef::F64)) => Code::GeF64(var, a, b),
+
+                    _ => Code::Op(var, *op, a, b),
+                }
+            }
+            Inst::UOp(var, op, a) => match (op, func.ir.var_type[*a]) {
+                (UOp::Neg, TypeDef::I32) => Code::NegI32(slot(var), slot(a)),
+                (UOp::Neg, TypeDef::F64) => Code::NegF64(slot(var), slot(a)),
+                _ => Code::UOp(slot(var), *op, slot(a)),
+            },
+            Inst::Const(var, value) => match value {
+                Value::I32(value) => Code::ConstI32(slot(var), *value),
+                Value::F64(value) => Code::ConstF64(slot(var), *value),
+                Value::Bool(value) => Code::ConstBool(slot(var), *value),
+                _ => {
+                    self.consts.push(value.clone());
+                    Code::Const(slot(var), self.consts.len() as u32 - 1)
+                }
+            },
+            Inst::Call(var, callee, params) => {
+                let args = self.add_args(params);
+                // only calls to itself reuse the frame, so stack traces show every caller
+                match next {
+                    Some(Inst::Return(ret)) if ret == var && *callee == func_id => {
+                        Code::TailCall(*callee as u32, args)
+                    }
+                    _ => Code::Call(slot(var), *callee as u32, args),
+                }
+            }
+            Inst::CallExtern(var, extern_id, params) => {
+                let args = self.add_args(params);
+                Code::CallExtern(slot(var), *extern_id as u32, args)
+            }
+            Inst::JumpTo(block, args) => {
+                let (first_param, _) = func.ir.block_params[*block];
+                let moves: Vec<(Slot, Slot)> = args
+                    .iter()
+                    .enumerate()
+                    .map(|(i, arg)| (slot(&(first_param + i)), slot(arg)))
+                    .filter(|(param, arg)| param != arg)
+                    .collect();
+
+                // a param can be passed to another param, then every arg is read before
+                // any are written, by moving them through slots after the vars first
+                let params_read = moves
+                    .iter()
+                    .any(|(_, arg)| moves.iter().any(|(param, _)| param == arg));
+                if params_read {
+                    let temp = func.ir.num_vars as Slot;
+                    self.slots = self.slots.max(func.ir.num_vars + moves.len());
+                    for (i, (_, arg)) in moves.iter().enumerate() {
+                        self.code.push(Code::Move(temp + i as Slot, *arg));
+                    }
+                    for (i, (param, _)) in moves.iter().enumerate() {
+                        self.code.push(Code::Move(*param, temp + i as Slot));
+                    }
+                } else {
+                    for (param, arg) in moves {
+                        self.code.push(Code::Move(param, arg));
+                    }
+                }
+                Code::Jump(*block as u32)
+            }
+            Inst::Branch(cond, (a, b)) => Code::Branch(slot(cond), *a as u32, *b as u32),
+            Inst::Return(var) => Code::Return(slot(var)),
+        };
+
+        self.code.push(code);
+    }
+
+    fn add_args(&mut self, params: &[Var]) -> u32 {
+        let start = self.args.len() as u32;
+        self.args.push(params.len() as Slot);
+        self.args.extend(params.iter().map(|param| *param as Slot));
+        return start;
+    }
+
+    /// The slots passed to the call with the args at <start>.
+    fn get_args(&self, start: u32) -> &[Slot] {
+        let start = start as usize;
+        let len = self.args[start] as usize;
+        return &self.args[start + 1..start + 1 + len];
+    }
+}
+
+/// A function waiting for a call to return.
+struct Frame {
+    chunk: usize,
+    pc: usize,
+    base: usize,
+    var: usize,
+}
+
+/// Make an error for a trap in <chunk>, called from <callers>.
+fn error(trap: Trap, chunks: &[Chunk], chunk: usize, callers: &[Frame]) -> RuntimeError {
+    let mut stack = vec![chunks[chunk].name.clone()];
+    stack.extend(
+        callers
+            .iter()
+            .rev()
+            .map(|frame| chunks[frame.chunk].name.clone()),
+    );
+    return RuntimeError { trap, stack };
+}
+
+/// Run the bytecode of the function <func_id>.
+pub fn exec_bytecode(
+    module: &Module,
+    func_id: FuncId,
+    args: Vec<Value>,
+    limits: &Limits,
+) -> Result<Value, RuntimeError> {
+    let chunks = module.chunks();
+
+    let mut chunk = &chunks[func_id];
+    let mut chunk_id = func_id;
+    let mut pc = 0;
+    let mut base = 0;
+    let mut callers: Vec<Frame> = vec![];
+    let mut fuel = limits.fuel;
+    let mut memory = chunk.bytes;
+
+    let mut slots = args;
+    slots.resize(chunk.slots, Value::Unit);
+
+    if memory > limits.max_memory {
+        return Err(error(Trap::OutOfMemory, chunks, chunk_id, &callers));
+    }
+
+    loop {
+        // every instruction costs one fuel
+        if let Some(fuel) = &mut fuel {
+            if *fuel == 0 {
+                return Err(error(Trap::OutOfFuel, chunks, chunk_id, &callers));
+            }
+            *fuel -= 1;
+        }
+
+        let code = chunk.code[pc];
+        pc += 1;
+
+        // the ops for a type are picked from the types of the vars, but a call can
+        // still pass a value of another type, then the op is done the slow way
+        let i32_op = |op: Op, a: Slot, b: Slot, f: fn(i32, i32) -> Value| match (
+            &slots[base + a as usize],
+            &slots[base + b as usize],
+        ) {
+            (Value::I32(a), Value::I32(b)) => Ok(f(*a, *b)),
+            (a, b) => do_op(&op, a.clone(), b.clone()),
+        };
+        let f64_op = |op: Op, a: Slot, b: Slot, f: fn(f64, f64) -> Value| match (
+            &slots[base + a as usize],
+            &slots[base + b as usize],
+        ) {
+            (Value::F64(a), Value::F64(b)) => Ok(f(*a, *b)),
+            (a, b) => do_op(&op, a.clone(), b.clone()),
+        };
+
+        let (var, value) = match code {
+            Code::AddI32(var, a, b) => (
+                var,
+                i32_op(Op::Add, a, b, |a, b| Value::I32(a.wrapping_add(b))),
+            ),
+            Code::SubI32(var, a, b) => (
+                var,
+                i32_op(Op::Sub, a, b, |a, b| Value::I32(a.wrapping_sub(b))),
+            ),
+            Code::MulI32(var, a, b) => (
+                var,
+                i32_op(Op::Mul, a, b, |a, b| Value::I32(a.wrapping_mul(b))),
+            ),
+            Code::DivI32(var, a, b) => match (&slots[base + a as usize], &slots[base + b as usize])
+            {
+                (Value::I32(_), Value::I32(0)) => (var, Err(Trap::DivideByZero)),
+                (Value::I32(a), Value::I32(b)) => (
+                    var,
+                    a.checked_div(*b)
+                        .map(Value::I32)
+                        .ok_or(Trap::IntegerOverflow),
+                ),
+                (a, b) => (var, do_op(&Op::Div, a.clone(), b.clone())),
+            },
+            Code::EqI32(var, a, b) => (var, i32_op(Op::Eq, a, b, |a, b| Value::Bool(a == b))),
+            Code::NeI32(var, a, b) => (var, i32_op(Op::Ne, a, b, |a, b| Value::Bool(a != b))),
+            Code::LtI32(var, a, b) => (var, i32_op(Op::Lt, a, b, |a, b| Value::Bool(a < b))),
+            Code::LeI32(var, a, b) => (var, i32_op(Op::Le, a, b, |a, b| Value::Bool(a <= b))),
+            Code::GtI32(var, a, b) => (var, i32_op(Op::Gt, a, b, |a, b| Value::Bool(a > b))),
+            Code::GeI32(var, a, b) => (var, i32_op(Op::Ge, a, b, |a, b| Value::Bool(a >= b))),
+            Code::NegI32(var, a) => match &slots[base + a as usize] {
+                Value::I32(a) => (var, Ok(Value::I32(a.wrapping_neg()))),
+                a => (var, do_uop(&UOp::Neg, a.clone())),
+            },
+
+            Code::AddF64(var, a, b) => (var, f64_op(Op::Add, a, b, |a, b| Value::F64(a + b))),
+            Code::SubF64(var, a, b) => (var, f64_op(Op::Sub, a, b, |a, b| Value::F64(a - b))),
+            Code::MulF64(var, a, b) => (var, f64_op(Op::Mul, a, b, |a, b| Value::F64(a * b))),
+            Code::DivF64(var, a, b) => (var, f64_op(Op::Div, a, b, |a, b| Value::F64(a / b))),
+            Code::EqF64(var, a, b) => (var, f64_op(Op::Eq, a, b, |a, b| Value::Bool(a == b))),
+            Code::NeF64(var, a, b) => (var, f64_op(Op::Ne, a, b, |a, b| Value::Bool(a != b))),
+            Code::LtF64(var, a, b) => (var, f64_op(Op::Lt, a, b, |a, b| Value::Bool(a < b))),
+            Code::LeF64(var, a, b) => (var, f64_op(Op::Le, a, b, |a, b| Value::Bool(a <= b))),
+            Code::GtF64(var, a, b) => (var, f64_op(Op::Gt, a, b, |a, b| Value::Bool(a > b))),
+            Code::GeF64(var, a, b) => (var, f64_op(Op::Ge, a, b, |a, b| Value::Bool(a >= b))),
+            Code::NegF64(var, a) => match &slots[base + a as usize] {
+                Value::F64(a) => (var, Ok(Value::F64(-a))),
+                a => (var, do_uop(&UOp::Neg, a.clone())),
+            },
+
+            Code::Op(var, op, a, b) => {
+                let a = slots[base + a as usize].clone();
+                let b = slots[base + b as usize].clone();
+                (var, do_op(&op, a, b))
+            }
+            Code::UOp(var, op, a) => (var, do_uop(&op, slots[base + a as usize].clone())),
+
+            Code::ConstI32(var, value) => (var, Ok(Value::I32(value))),
+            Code::ConstF64(var, value) => (var, Ok(Value::F64(value))),
+            Code::ConstBool(var, value) => (var, Ok(Value::Bool(value))),
+            Code::Const(var, i) => (var, Ok(chunk.consts[i as usize].clone())),
+            Code::Move(var, a) => (var, Ok(slots[base + a as usize].clone())),
+
+            Code::Jump(target) => {
+                pc = target as usize;
+                continue;
+            }
+            Code::Branch(cond, a, b) => {
+                match slots[base + cond as usize] {
+                    Value::Bool(true) => pc = a as usize,
+                    Value::Bool(false) => pc = b as usize,
+                    ref cond => {
+                        let msg = format!("can't branch on {:?}", cond.get_type());
+                        let trap = Trap::TypeMismatch(msg);
+                        return Err(error(trap, chunks, chunk_id, &callers));
+                    }
+                }
+                continue;
+            }
+            Code::Call(var, callee, args) => {
+                if callers.len() + 1 >= limits.max_call_depth {
+                    return Err(error(Trap::StackOverflow, chunks, chunk_id, &callers));
+                }
+                callers.push(Frame {
+                    chunk: chunk_id,
+                    pc,
+                    base,
+                    var: var as usize,
+                });
+
+                // the args are the first vars of the new frame
+                let caller = base;
+                let callee_chunk = &chunks[callee as usize];
+                base = slots.len();
+                slots.resize(base + callee_chunk.slots, Value::Unit);
+                for (i, arg) in chunk.get_args(args).iter().enumerate() {
+                    slots[base + i] = slots[caller + *arg as usize].clone();
+                }
+
+                memory += callee_chunk.bytes;
+                chunk = callee_chunk;
+                chunk_id = callee as usize;
+                pc = 0;
+
+                if memory > limits.max_memory {
+                    return Err(error(Trap::OutOfMemory, chunks, chunk_id, &callers));
+                }
+                continue;
+            }
+            Code::TailCall(callee, args) => {
+                let callee_chunk = &chunks[callee as usize];
+                let args = chunk.get_args(args);
+
+                // read all the args before any of the vars are overwritten
+                for arg in args {
+                    slots.push(slots[base + *arg as usize].clone());
+                }
+                for i in (0..args.len()).rev() {
+                    slots[base + i] = slots.pop().unwrap();
+                }
+                slots.truncate(base + args.len());
+                slots.resize(base + callee_chunk.slots, Value::Unit);
+
+                memory = memory - chunk.bytes + callee_chunk.bytes;
+                chunk = callee_chunk;
+                chunk_id = callee as usize;
+                pc = 0;
+
+                if memory > limits.max_memory {
+                    return Err(error(Trap::OutOfMemory, chunks, chunk_id, &callers));
+                }
+                continue;
+            }
+            Code::CallExtern(var, extern_id, args) => {
+                let args: Vec<Value> = chunk
+                    .get_args(args)
+                    .iter()
+                    .map(|arg| slots[base + *arg as usize].clone())
+                    .collect();
+                (var, call_extern(module, extern_id as usize, &args))
+            }
+            Code::Return(var) => {
+                let value = slots[base + var as usize].clone();
+
+                // go back to the caller, if there is one
+                let Some(caller) = callers.pop() else {
+                    return Ok(value);
+                };
+                memory -= chunk.bytes;
+                slots.truncate(base);
+                chunk_id = caller.chunk;
+                chunk = &chunks[chunk_id];
+                pc = caller.pc;
+                base = caller.base;
+                slots[base + caller.var] = value;
+                continue;
+            }
+            Code::Unreachable => {
+                return Err(error(Trap::Unreachable, chunks, chunk_id, &callers));
+            }
+        };
+
+        match value {
+            Ok(value) => slots[base + var as usize] = value,
+            Err(trap) => return Err(error(trap, chunks, chunk_id, &callers)),
+        }
+    }
+}
@@ -0,0 +1,237 @@
+use std::io::{BufRead, Write};
+
+use crate::core::*;
+
+/// Where the debugger stops next.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Mode {
+    /// Stop at the very first instruction.
+    Start,
+    /// Only stop at breakpoints.
+    Continue,
+    /// Stop at the next line, even in a function it calls.
+    Step,
+    /// Stop at the next line of a function at this depth or one that called it.
+    Next(usize),
+    /// Stop when the function at this depth returns.
+    Finish(usize),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Breakpoint {
+    Func(String),
+    Line(usize),
+}
+
+/// A debugger that reads commands from <input> every time it stops, and writes
+/// what it finds to <out>:
+///
+/// - `break <func>` or `break <line>` (`b`) stops there
+/// - `continue` (`c`) runs to the next breakpoint
+/// - `step` (`s`) runs to the next line, going in to calls
+/// - `next` (`n`) runs to the next line, going over calls
+/// - `finish` (`f`) runs until the function returns
+/// - `print` (`p`) writes the vars by their source names, or just `print <name>`
+/// - `backtrace` (`bt`) writes the functions that are running
+/// - `quit` (`q`) stops running
+pub struct Session<R: BufRead, W: Write> {
+    src: Vec<String>,
+    input: R,
+    out: W,
+    mode: Mode,
+    breakpoints: Vec<Breakpoint>,
+    /// When each var of each running function was last assigned, the first
+    /// function first. Vars that haven't been assigned yet are `None`.
+    assigned: Vec<Vec<Option<u64>>>,
+    clock: u64,
+}
+
+impl<R: BufRead, W: Write> Session<R, W> {
+    /// Make a debugger for the program <src>, so it can show the lines it stops at.
+    pub fn new(src: &str, input: R, out: W) -> Self {
+        return Session {
+            src: src.lines().map(|line| line.to_string()).collect(),
+            input,
+            out,
+            mode: Mode::Start,
+            breakpoints: vec![],
+            assigned: vec![],
+            clock: 0,
+        };
+    }
+
+    fn should_stop(&self, paused: &Paused) -> bool {
+        let is_line_start = paused.func.ir.is_line_start(paused.step);
+
+        let at_breakpoint = self.breakpoints.iter().any(|breakpoint| match breakpoint {
+            Breakpoint::Func(name) => paused.step == 0 && *name == paused.func.name,
+            Breakpoint::Line(line) => is_line_start && paused.line() == Some(*line),
+        });
+
+        return at_breakpoint
+            || match self.mode {
+                Mode::Start => true,
+                Mode::Continue => false,
+                Mode::Step => is_line_start,
+                Mode::Next(depth) => is_line_start && paused.depth() <= depth,
+                Mode::Finish(depth) => paused.depth() < depth,
+            };
+    }
+
+    /// Keep track of the functions that are running.
+    fn track_frames(&mut self, paused: &Paused) {
+        let depth = paused.depth();
+        self.assigned.truncate(depth + 1);
+
+        // a new call, or a tail call that reused the frame, starts with just the params
+        if self.assigned.len() == depth || paused.step == 0 {
+            let mut vars = vec![None; paused.vars.len()];
+            vars[..paused.func.num_params].fill(Some(self.clock));
+            self.assigned.truncate(depth);
+            self.assigned.push(vars);
+        }
+    }
+
+    /// Keep track of the vars the inst that's about to run assigns, and when.
+    fn track_assign(&mut self, paused: &Paused) {
+        self.clock += 1;
+        let vars = &mut self.assigned[paused.depth()];
+        let ir = &paused.func.ir;
+        match &ir.insts.get(paused.step) {
+            Some(
+                Inst::Op(var, ..)
+                | Inst::UOp(var, ..)
+                | Inst::Const(var, _)
+                | Inst::Call(var, ..)
+                | Inst::CallExtern(var, ..),
+            ) => vars[*var] = Some(self.clock),
+            Some(Inst::JumpTo(block, args)) => {
+                let (first_param, _) = ir.block_params[*block];
+                vars[first_param..first_param + args.len()].fill(Some(self.clock));
+            }
+            _ => {}
+        }
+    }
+
+    /// Get the source vars of the function that is running, with the value of
+    /// the var they were assigned to last.
+    fn vars(&self, paused: &Paused) -> Vec<(String, Value)> {
+        let names = &paused.func.ir.var_name;
+        let assigned = &self.assigned[paused.depth()];
+
+        let mut vars: Vec<(String, Var, u64)> = vec![];
+        for (var, (name, time)) in names.iter().zip(assigned).enumerate() {
+            let (Some(name), Some(time)) = (name, *time) else {
+                continue;
+            };
+            match vars.iter_mut().find(|(other, ..)| other == name) {
+                Some(latest) if latest.2 < time => *latest = (name.clone(), var, time),
+                Some(_) => {}
+                None => vars.push((name.clone(), var, time)),
+            }
+        }
+
+        return vars
+            .into_iter()
+            .map(|(name, var, _)| (name, paused.vars[var].clone()))
+            .collect();
+    }
+
+    fn write_location(&mut self, paused: &Paused) -> std::io::Result<()> {
+        let name = &paused.func.name;
+        return match paused.line() {
+            Some(line) => {
+                let src = self.src.get(line - 1).map(|src| src.trim()).unwrap_or("");
+                writeln!(self.out, "{name}:{line}  {src}")
+            }
+            None => writeln!(self.out, "{name}"),
+        };
+    }
+
+    /// Run the commands until one of them says to keep going.
+    fn prompt(&mut self, paused: &Paused) -> std::io::Result<Result<(), Trap>> {
+        self.write_location(paused)?;
+
+        loop {
+            write!(self.out, "(debug) ")?;
+            self.out.flush()?;
+
+            let mut command = String::new();
+            if self.input.read_line(&mut command)? == 0 {
+                // there's nothing left to do, so run it to the end
+                writeln!(self.out)?;
+                self.mode = Mode::Continue;
+                self.breakpoints.clear();
+                return Ok(Ok(()));
+            }
+
+            let command: Vec<&str> = command.split_whitespace().collect();
+            match command[..] {
+                [] => {}
+                ["b" | "break", at] => match at.parse() {
+                    Ok(line) if !paused.funcs.iter().any(|func| func.ir.has_line(line)) => {
+                        writeln!(self.out, "ERR no statement starts on line {line}")?
+                    }
+                    Ok(line) => {
+                        self.breakpoints.push(Breakpoint::Line(line));
+                        writeln!(self.out, "breakpoint at {at}")?;
+                    }
+                    Err(_) => {
+                        self.breakpoints.push(Breakpoint::Func(at.to_string()));
+                        writeln!(self.out, "breakpoint at {at}")?;
+                    }
+                },
+                ["c" | "continue"] => {
+                    self.mode = Mode::Continue;
+                    return Ok(Ok(()));
+                }
+                ["s" | "step"] => {
+                    self.mode = Mode::Step;
+                    return Ok(Ok(()));
+                }
+                ["n" | "next"] => {
+                    self.mode = Mode::Next(paused.depth());
+                    return Ok(Ok(()));
+                }
+                ["f" | "finish"] => {
+                    self.mode = Mode::Finish(paused.depth());
+                    return Ok(Ok(()));
+                }
+                ["p" | "print"] => {
+                    for (name, value) in self.vars(paused) {
+                        writeln!(self.out, "{name} = {value}")?;
+                    }
+                }
+                ["p" | "print", name] => match self.vars(paused).iter().find(|var| var.0 == name) {
+                    Some((name, value)) => writeln!(self.out, "{name} = {value}")?,
+                    None => writeln!(self.out, "ERR no var {name}")?,
+                },
+                ["bt" | "backtrace"] => {
+                    for (name, line) in paused.backtrace() {
+                        match line {
+                            Some(line) => writeln!(self.out, "  at {name}:{line}")?,
+                            None => writeln!(self.out, "  at {name}")?,
+                        }
+                    }
+                }
+                ["q" | "quit"] => return Ok(Err(Trap::Interrupted)),
+                _ => writeln!(self.out, "ERR unknown command {}", command.join(" "))?,
+            }
+        }
+    }
+}
+
+impl<R: BufRead, W: Write> Debugger for Session<R, W> {
+    fn step(&mut self, paused: &Paused) -> Result<(), Trap> {
+        self.track_frames(paused);
+
+        if self.should_stop(paused) {
+            // if the input or output breaks there's no one left to debug it
+            self.prompt(paused).unwrap_or(Err(Trap::Interrupted))?;
+        }
+
+        // the vars the inst assigns only have their values once it has run
+        self.track_assign(paused);
+        return Ok(());
+    }
+}
@@ -0,0 +1,440 @@
+use std::fmt::{Display, Formatter};
+
+use crate::core::*;
+
+/// How much running atlas code can do before it's stopped.
+#[derive(Debug, Clone)]
+pub struct Limits {
+    /// How many instructions can run, there's no limit if it's `None`.
+    pub fuel: Option<u64>,
+    /// How deep calls can go before running out of stack.
+    pub max_call_depth: usize,
+    /// How many bytes the vars of all the running functions can take up.
+    pub max_memory: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        return Limits {
+            fuel: None,
+            max_call_depth: 10_000,
+            max_memory: 64 << 20,
+        };
+    }
+}
+
+/// What runs atlas code for `Module::exec`.
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Engine {
+    /// Walk the ir.
+    #[default]
+    Ir,
+    /// Compile the ir to bytecode first, with typed ops and jumps resolved ahead of time.
+    Bytecode,
+    /// Compile the module to wasm and run it with wasmtime.
+    Wasm,
+}
+
+/// Why running atlas code stopped, the same traps wasm raises, and the mistakes
+/// only the interpreter can run in to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trap {
+    DivideByZero,
+    IntegerOverflow,
+    StackOverflow,
+    OutOfFuel,
+    OutOfMemory,
+    /// The end of a function was reached without returning.
+    Unreachable,
+    TypeMismatch(String),
+    UnknownFunction(String),
+    /// An extern was called without a host function registered for it.
+    MissingHost(String),
+    /// A debugger stopped it.
+    Interrupted,
+    /// A trap only wasm raises, like an out of bounds memory access, or wasmtime
+    /// failing to run the module.
+    Wasm(String),
+}
+
+impl Display for Trap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Trap::DivideByZero => write!(f, "integer divide by zero"),
+            Trap::IntegerOverflow => write!(f, "integer overflow"),
+            Trap::StackOverflow => write!(f, "call stack exhausted"),
+            Trap::OutOfFuel => write!(f, "out of fuel"),
+            Trap::OutOfMemory => write!(f, "out of memory"),
+            Trap::Unreachable => write!(f, "unreachable"),
+            Trap::TypeMismatch(msg) => write!(f, "type mismatch, {msg}"),
+            Trap::UnknownFunction(name) => write!(f, "unknown function {name}"),
+            Trap::MissingHost(name) => write!(f, "no host function registered for {name}"),
+            Trap::Interrupted => write!(f, "interrupted"),
+            Trap::Wasm(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+impl std::error::Error for Trap {}
+
+/// A trap, and the atlas functions that were running when it happened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub trap: Trap,
+    /// The names of the functions, the one that trapped first.
+    pub stack: Vec<String>,
+}
+
+impl Display for RuntimeError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.trap)?;
+        for name in &self.stack {
+            write!(f, "\n  at {name}")?;
+        }
+        return Ok(());
+    }
+}
+
+impl RuntimeError {
+    /// Make an error for a trap in <func>, called from <callers>.
+    fn new(trap: Trap, func: &Func, callers: &[Frame]) -> RuntimeError {
+        let mut stack = vec![func.name.clone()];
+        stack.extend(callers.iter().rev().map(|frame| frame.func.name.clone()));
+        return RuntimeError { trap, stack };
+    }
+}
+
+/// Where a function keeps its vars while it runs. Every var gets the slot with
+/// its number, after the slots of the functions that called it.
+pub(crate) struct FrameLayout {
+    slots: usize,
+    /// How many bytes the vars take up, for the memory limit.
+    bytes: usize,
+}
+
+impl FrameLayout {
+    pub(crate) fn new(func: &Func) -> FrameLayout {
+        return FrameLayout {
+            slots: func.ir.num_vars,
+            bytes: func.ir.var_type.iter().map(|typ| typ.size()).sum(),
+        };
+    }
+}
+
+/// A function waiting for a call to return.
+struct Frame<'a> {
+    func: &'a Func,
+    step: usize,
+    /// Where the slots of the function start.
+    base: usize,
+    /// How many bytes its vars take up.
+    bytes: usize,
+    /// Where the result of the call goes.
+    var: Var,
+}
+
+/// Looks at the interpreter before every instruction it runs, see `Module::debug`.
+pub trait Debugger {
+    /// Called before the inst at <paused>'s step runs, it stops running if this
+    /// returns a trap.
+    fn step(&mut self, paused: &Paused) -> Result<(), Trap>;
+
+    /// Called when running stops because of <trap>, with <paused>'s step at the
+    /// inst that trapped, or the inst that was about to run.
+    fn trapped(&mut self, _paused: &Paused, _trap: &Trap) {}
+}
+
+/// The interpreter right before it runs an instruction.
+pub struct Paused<'a> {
+    pub func: &'a Func,
+    /// The inst that is about to run.
+    pub step: usize,
+    /// The slots of the function's vars.
+    pub vars: &'a [Value],
+    callers: &'a [Frame<'a>],
+    /// Every function of the module, by id.
+    pub funcs: &'a [Func],
+}
+
+impl Paused<'_> {
+    /// Get how many calls are waiting on this function to return.
+    pub fn depth(&self) -> usize {
+        return self.callers.len();
+    }
+
+    pub fn line(&self) -> Option<usize> {
+        return self.func.ir.line(self.step);
+    }
+
+    /// Get the running functions and the line they are on, this one first.
+    pub fn backtrace(&self) -> Vec<(&str, Option<usize>)> {
+        let mut frames = vec![(self.func.name.as_str(), self.line())];
+        for frame in self.callers.iter().rev() {
+            // the caller is waiting on the call before its step
+            let line = frame.func.ir.line(frame.step - 1);
+            frames.push((frame.func.name.as_str(), line));
+        }
+        return frames;
+    }
+}
+
+pub fn exec_ir(
+    func: &Func,
+    module: &Module,
+    args: Vec<Value>,
+    limits: &Limits,
+    mut debugger: Option<&mut dyn Debugger>,
+) -> Result<Value, RuntimeError> {
+    let funcs = module.funcs();
+    let layouts = module.layouts();
+
+    let layout = FrameLayout::new(func);
+
+    let mut func = func;
+    let mut step = 0;
+    let mut base = 0;
+    let mut bytes = layout.bytes;
+    let mut callers: Vec<Frame> = vec![];
+    let mut fuel = limits.fuel;
+    let mut memory = bytes;
+
+    let mut slots = args;
+    slots.resize(layout.slots, Value::Unit);
+
+    if memory > limits.max_memory {
+        return Err(RuntimeError::new(Trap::OutOfMemory, func, &callers));
+    }
+
+    // where it stopped, the inst that trapped or the one that was about to run
+    let (trap, step) = loop {
+        // every instruction costs one fuel
+        if let Some(fuel) = &mut fuel {
+            if *fuel == 0 {
+                break (Trap::OutOfFuel, step);
+            }
+            *fuel -= 1;
+        }
+
+        if let Some(debugger) = &mut debugger {
+            let paused = Paused {
+                func,
+                step,
+                vars: &slots[base..],
+                callers: &callers,
+                funcs,
+            };
+            if let Err(trap) = debugger.step(&paused) {
+                break (trap, step);
+            }
+        }
+
+        step += 1;
+        let Some(inst) = func.ir.insts.get(step - 1) else {
+            break (Trap::Unreachable, step - 1);
+        };
+
+        match inst {
+            Inst::Op(var, op, a, b) => {
+                let a = slots[base + a].clone();
+                let b = slots[base + b].clone();
+
+                match do_op(op, a, b) {
+                    Ok(value) => slots[base + var] = value,
+                    Err(trap) => break (trap, step - 1),
+                }
+            }
+            Inst::UOp(var, op, a) => {
+                let a = slots[base + a].clone();
+
+                match do_uop(op, a) {
+                    Ok(value) => slots[base + var] = value,
+                    Err(trap) => break (trap, step - 1),
+                }
+            }
+            Inst::Const(var, val) => {
+                slots[base + var] = val.clone();
+            }
+            Inst::Call(var, callee, params) => {
+                let layout = &layouts[*callee];
+
+                // a function returning the result of calling itself can reuse its
+                // frame, calls to others keep theirs so stack traces show every caller
+                let is_tail_call = std::ptr::eq(func, &funcs[*callee])
+                    && matches!(func.ir.insts.get(step), Some(Inst::Return(ret)) if ret == var);
+                if !is_tail_call {
+                    if callers.len() + 1 >= limits.max_call_depth {
+                        break (Trap::StackOverflow, step - 1);
+                    }
+                    memory += layout.bytes;
+
+                    callers.push(Frame {
+                        func,
+                        step,
+                        base,
+                        bytes,
+                        var: *var,
+                    });
+
+                    // the args are the first vars of the new frame
+                    let caller = base;
+                    base = slots.len();
+                    slots.resize(base + layout.slots, Value::Unit);
+                    for (i, param) in params.iter().enumerate() {
+                        slots[base + i] = slots[caller + param].clone();
+                    }
+                } else {
+                    memory = memory - bytes + layout.bytes;
+
+                    // read all the args before any of the vars are overwritten
+                    for param in params {
+                        slots.push(slots[base + param].clone());
+                    }
+                    for i in (0..params.len()).rev() {
+                        slots[base + i] = slots.pop().unwrap();
+                    }
+                    slots.truncate(base + params.len());
+                    slots.resize(base + layout.slots, Value::Unit);
+                }
+
+                func = &funcs[*callee];
+                step = 0;
+                bytes = layout.bytes;
+
+                if memory > limits.max_memory {
+                    break (Trap::OutOfMemory, step);
+                }
+            }
+            Inst::CallExtern(var, extern_id, params) => {
+                let args: Vec<Value> = params.iter().map(|var| slots[base + var].clone()).collect();
+                match call_extern(module, *extern_id, &args) {
+                    Ok(value) => slots[base + var] = value,
+                    Err(trap) => break (trap, step - 1),
+                }
+            }
+            Inst::JumpTo(block, args) => {
+                step = func.ir.blocks[*block];
+
+                let (first_param, _) = func.ir.block_params[*block];
+
+                // read all the args before assigning any, a param can be passed to another param
+                for arg in args {
+                    slots.push(slots[base + arg].clone());
+                }
+                for i in (0..args.len()).rev() {
+                    slots[base + first_param + i] = slots.pop().unwrap();
+                }
+            }
+            Inst::Branch(cond, (a, b)) => match slots[base + cond] {
+                Value::Bool(true) => step = func.ir.blocks[*a],
+                Value::Bool(false) => step = func.ir.blocks[*b],
+                ref cond => {
+                    let msg = format!("can't branch on {:?}", cond.get_type());
+                    break (Trap::TypeMismatch(msg), step - 1);
+                }
+            },
+            Inst::Return(var) => {
+                let value = slots[base + var].clone();
+
+                // go back to the caller, if there is one
+                let Some(caller) = callers.pop() else {
+                    return Ok(value);
+                };
+                memory -= bytes;
+                slots.truncate(base);
+                func = caller.func;
+                step = caller.step;
+                base = caller.base;
+                bytes = caller.bytes;
+                slots[base + caller.var] = value;
+            }
+        }
+    };
+
+    if let Some(debugger) = &mut debugger {
+        let paused = Paused {
+            func,
+            step,
+            vars: &slots[base..],
+            callers: &callers,
+            funcs,
+        };
+        debugger.trapped(&paused, &trap);
+    }
+    return Err(RuntimeError::new(trap, func, &callers));
+}
+
+/// Call the host function registered for the extern <extern_id>.
+pub fn call_extern(module: &Module, extern_id: ExternId, args: &[Value]) -> Result<Value, Trap> {
+    let extern_def = &module.externs[extern_id];
+    let key = (extern_def.module.clone(), extern_def.name.clone());
+    let Some(host) = module.hosts.get(&key) else {
+        return Err(Trap::MissingHost(format!("{}.{}", key.0, key.1)));
+    };
+
+    // the host can return anything, so check it's what atlas expects
+    let value = host(args);
+    if value.get_type() != extern_def.return_type {
+        let msg = format!(
+            "{}.{} returned {:?} instead of {:?}",
+            key.0,
+            key.1,
+            value.get_type(),
+            extern_def.return_type
+        );
+        return Err(Trap::TypeMismatch(msg));
+    }
+    return Ok(value);
+}
+
+/// Apply <op> to <a> and <b>, i32s wrap around like they do in wasm.
+pub fn do_op(op: &Op, a: Value, b: Value) -> Result<Value, Trap> {
+    let value = match (op, a.get_type(), b.get_type()) {
+        (Op::Eq, TypeDef::Bool, TypeDef::Bool) => Value::bool(a.as_bool() == b.as_bool()),
+        (Op::Ne, TypeDef::Bool, TypeDef::Bool) => Value::bool(a.as_bool() != b.as_bool()),
+
+        (Op::Add, TypeDef::I32, TypeDef::I32) => Value::i32(a.as_i32().wrapping_add(b.as_i32())),
+        (Op::Sub, TypeDef::I32, TypeDef::I32) => Value::i32(a.as_i32().wrapping_sub(b.as_i32())),
+        (Op::Mul, TypeDef::I32, TypeDef::I32) => Value::i32(a.as_i32().wrapping_mul(b.as_i32())),
+        (Op::Div, TypeDef::I32, TypeDef::I32) => match (a.as_i32(), b.as_i32()) {
+            (_, 0) => return Err(Trap::DivideByZero),
+            (a, b) => Value::i32(a.checked_div(b).ok_or(Trap::IntegerOverflow)?),
+        },
+
+        (Op::Eq, TypeDef::I32, TypeDef::I32) => Value::bool(a.as_i32() == b.as_i32()),
+        (Op::Ne, TypeDef::I32, TypeDef::I32) => Value::bool(a.as_i32() != b.as_i32()),
+        (Op::Le, TypeDef::I32, TypeDef::I32) => Value::bool(a.as_i32() <= b.as_i32()),
+        (Op::Lt, TypeDef::I32, TypeDef::I32) => Value::bool(a.as_i32() < b.as_i32()),
+        (Op::Ge, TypeDef::I32, TypeDef::I32) => Value::bool(a.as_i32() >= b.as_i32()),
+        (Op::Gt, TypeDef::I32, TypeDef::I32) => Value::bool(a.as_i32() > b.as_i32()),
+
+        (Op::Add, TypeDef::F64, TypeDef::F64) => Value::f64(a.as_f64() + b.as_f64()),
+        (Op::Sub, TypeDef::F64, TypeDef::F64) => Value::f64(a.as_f64() - b.as_f64()),
+        (Op::Mul, TypeDef::F64, TypeDef::F64) => Value::f64(a.as_f64() * b.as_f64()),
+        (Op::Div, TypeDef::F64, TypeDef::F64) => Value::f64(a.as_f64() / b.as_f64()),
+
+        (Op::Eq, TypeDef::F64, TypeDef::F64) => Value::bool(a.as_f64() == b.as_f64()),
+        (Op::Ne, TypeDef::F64, TypeDef::F64) => Value::bool(a.as_f64() != b.as_f64()),
+        (Op::Le, TypeDef::F64, TypeDef::F64) => Value::bool(a.as_f64() <= b.as_f64()),
+        (Op::Lt, TypeDef::F64, TypeDef::F64) => Value::bool(a.as_f64() < b.as_f64()),
+        (Op::Ge, TypeDef::F64, TypeDef::F64) => Value::bool(a.as_f64() >= b.as_f64()),
+        (Op::Gt, TypeDef::F64, TypeDef::F64) => Value::bool(a.as_f64() > b.as_f64()),
+
+        (op, a, b) => {
+            let msg = format!("can't {op:?} {a:?} and {b:?}");
+            return Err(Trap::TypeMismatch(msg));
+        }
+    };
+    return Ok(value);
+}
+
+pub fn do_uop(op: &UOp, a: Value) -> Result<Value, Trap> {
+    let value = match (op, a.get_type()) {
+        (UOp::Neg, TypeDef::I32) => Value::i32(a.as_i32().wrapping_neg()),
+        (UOp::Neg, TypeDef::F64) => Value::f64(-a.as_f64()),
+
+        (UOp::Not, TypeDef::Bool) => Value::bool(!a.as_bool()),
+
+        (op, a) => return Err(Trap::TypeMismatch(format!("can't {op:?} {a:?}"))),
+    };
+    return Ok(value);
+}
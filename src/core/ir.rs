@@ -1,12 +1,13 @@
 use crate::core::*;
 
-type Var = usize;
+pub type Var = usize;
 pub type FuncId = usize;
+pub type ExternId = usize;
 pub type Block = usize;
 
 const NO_VALUE: Var = usize::MAX;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Func {
     pub name: String,
     pub num_params: usize,
@@ -26,10 +27,22 @@ impl Func {
             scope.declair(param.name.clone(), i);
             ir.var_decl.push(0); // TODO: !!
             ir.var_type.push(param.param_type.clone());
+            ir.var_name.push(Some(param.name.clone()));
+
+            // a unit param holds nothing, so it has nowhere to go in wasm
+            if param.param_type == TypeDef::Unit {
+                ir.errors
+                    .push(format!("the param {} can't be Unit", param.name));
+            }
         }
 
         ir.add(&func_def.body, scope);
 
+        // there's no value to give back if it runs off the end
+        if ir.falls_off() {
+            ir.errors.push("it can end without returning".to_string());
+        }
+
         return Func {
             name: func_def.name.clone(),
             num_params,
@@ -42,13 +55,16 @@ impl Func {
         writeln!(buffer, "function {} ():", self.name)?;
         self.ir.log(buffer)
     }
+}
 
-    pub fn get_var_type(&self, var: usize) -> TypeDef {
-        return self.ir.var_type[var];
-    }
+/// What a call in the source calls.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Callee {
+    Func(FuncId),
+    Extern(ExternId),
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Op {
     // math ops
     Add,
@@ -65,7 +81,7 @@ pub enum Op {
     Gt,
 }
 
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum UOp {
     Neg,
     Not,
@@ -79,6 +95,7 @@ pub enum Inst {
 
     // misc
     Call(Var, FuncId, Vec<Var>),
+    CallExtern(Var, ExternId, Vec<Var>),
     Const(Var, Value),
 
     // control flow
@@ -87,16 +104,100 @@ pub enum Inst {
     Return(Var),
 }
 
-#[derive(Debug)]
+impl Inst {
+    /// Get the var this instruction assigns to, if any.
+    pub fn def(&self) -> Option<Var> {
+        match self {
+            Inst::Op(var, ..)
+            | Inst::UOp(var, ..)
+            | Inst::Call(var, ..)
+            | Inst::CallExtern(var, ..)
+            | Inst::Const(var, _) => Some(*var),
+            Inst::Branch(..) | Inst::JumpTo(..) | Inst::Return(..) => None,
+        }
+    }
+
+    /// Get the vars this instruction reads.
+    pub fn uses(&self) -> Vec<Var> {
+        match self {
+            Inst::Op(_, _, a, b) => vec![*a, *b],
+            Inst::UOp(_, _, a) => vec![*a],
+            Inst::Call(_, _, args) | Inst::CallExtern(_, _, args) => args.clone(),
+            Inst::Const(..) => vec![],
+            Inst::Branch(cond, _) => vec![*cond],
+            Inst::JumpTo(_, args) => args.clone(),
+            Inst::Return(var) => vec![*var],
+        }
+    }
+
+    /// Replace every var this instruction reads with <f(var)>.
+    pub fn map_uses(&mut self, f: impl Fn(Var) -> Var) {
+        match self {
+            Inst::Op(_, _, a, b) => {
+                *a = f(*a);
+                *b = f(*b);
+            }
+            Inst::UOp(_, _, a) => *a = f(*a),
+            Inst::Call(_, _, args) | Inst::CallExtern(_, _, args) | Inst::JumpTo(_, args) => {
+                for arg in args.iter_mut() {
+                    *arg = f(*arg);
+                }
+            }
+            Inst::Const(..) => {}
+            Inst::Branch(cond, _) => *cond = f(*cond),
+            Inst::Return(var) => *var = f(*var),
+        }
+    }
+
+    /// Replace the var this instruction assigns to with <f(var)>.
+    pub fn map_def(&mut self, f: impl Fn(Var) -> Var) {
+        match self {
+            Inst::Op(var, ..)
+            | Inst::UOp(var, ..)
+            | Inst::Call(var, ..)
+            | Inst::CallExtern(var, ..)
+            | Inst::Const(var, _) => *var = f(*var),
+            Inst::Branch(..) | Inst::JumpTo(..) | Inst::Return(..) => {}
+        }
+    }
+
+    /// Replace every block this instruction can jump to with <f(block)>.
+    pub fn map_targets(&mut self, f: impl Fn(Block) -> Block) {
+        match self {
+            Inst::Branch(_, (a, b)) => {
+                *a = f(*a);
+                *b = f(*b);
+            }
+            Inst::JumpTo(block, _) => *block = f(*block),
+            _ => {}
+        }
+    }
+
+    /// Does this instruction end a block?
+    pub fn is_exit(&self) -> bool {
+        matches!(self, Inst::Branch(..) | Inst::JumpTo(..) | Inst::Return(..))
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Blocks {
     pub insts: Vec<Inst>,
 
     pub num_vars: usize,
     pub var_decl: Vec<usize>,
     pub var_type: Vec<TypeDef>,
+    /// The name of the source variable each var was first stored in.
+    pub var_name: Vec<Option<String>>,
 
     pub blocks: Vec<usize>,
     pub block_params: Vec<(usize, usize)>,
+
+    /// The inst each statement starts at and its line in the source, in order.
+    /// Passes that move insts around drop them.
+    pub lines: Vec<(usize, usize)>,
+
+    /// Mistakes in the source found while lowering it, the ir can't run if there are any.
+    pub errors: Vec<String>,
 }
 
 impl Blocks {
@@ -107,20 +208,26 @@ impl Blocks {
             num_vars: params.len(),
             var_decl: vec![],
             var_type: vec![],
+            var_name: vec![],
 
             blocks: vec![0],
             block_params: vec![(0, 0)],
+
+            lines: vec![],
+
+            errors: vec![],
         };
     }
 
-    fn new_var(&mut self, t: TypeDef) -> Var {
+    pub fn new_var(&mut self, t: TypeDef) -> Var {
         self.var_type.push(t);
+        self.var_name.push(None);
         self.var_decl.push(self.insts.len() + 1);
         self.num_vars += 1;
         return self.num_vars - 1;
     }
 
-    fn new_block(&mut self) -> Block {
+    pub fn new_block(&mut self) -> Block {
         self.blocks.push(0);
         self.block_params.push((0, 0));
         return self.blocks.len() - 1;
@@ -131,6 +238,18 @@ impl Blocks {
     }
 
     fn add_op(&mut self, op: Op, a: Var, b: Var) -> usize {
+        // the same ops the interpreter and wasm have
+        let types = (self.var_type[a], self.var_type[b]);
+        let is_math = matches!(
+            types,
+            (TypeDef::I32, TypeDef::I32) | (TypeDef::F64, TypeDef::F64)
+        );
+        let is_bool_eq = matches!(op, Op::Eq | Op::Ne) && types == (TypeDef::Bool, TypeDef::Bool);
+        if !is_math && !is_bool_eq {
+            let (a, b) = types;
+            self.errors.push(format!("can't {op:?} {a:?} and {b:?}"));
+        }
+
         let var = self.new_var(match op {
             Op::Add | Op::Div | Op::Sub | Op::Mul => self.var_type[a].clone(),
             Op::Eq | Op::Ne | Op::Ge | Op::Gt | Op::Le | Op::Lt => TypeDef::Bool,
@@ -140,6 +259,15 @@ impl Blocks {
     }
 
     fn add_uop(&mut self, op: UOp, a: Var) -> usize {
+        let works = matches!(
+            (&op, self.var_type[a]),
+            (UOp::Neg, TypeDef::I32 | TypeDef::F64) | (UOp::Not, TypeDef::Bool)
+        );
+        if !works {
+            let a = self.var_type[a];
+            self.errors.push(format!("can't {op:?} {a:?}"));
+        }
+
         let var = self.new_var(self.var_type[a].clone());
         self.insts.push(Inst::UOp(var, op, a));
         return var;
@@ -162,6 +290,13 @@ impl Blocks {
         };
     }
 
+    /// Name <var> after the source variable <name>, unless it already has a name.
+    fn name_var(&mut self, var: Var, name: &str) {
+        if let Some(var_name @ None) = self.var_name.get_mut(var) {
+            *var_name = Some(name.to_string());
+        }
+    }
+
     fn add_param_to_block(&mut self, block: Block, t: TypeDef) -> Var {
         let var = self.new_var(t);
         self.block_params[block].1 += 1;
@@ -176,15 +311,9 @@ impl Blocks {
                         self.insts[i] = Inst::Branch(new, paths.clone())
                     }
                 }
-                Inst::Call(var, func, args) => {
+                Inst::Call(_, _, args) | Inst::CallExtern(_, _, args) => {
                     if args.contains(&old) {
-                        self.insts[i] = Inst::Call(
-                            *var,
-                            *func,
-                            args.iter()
-                                .map(|arg| if *arg == old { new } else { *arg })
-                                .collect(),
-                        )
+                        self.insts[i].map_uses(|arg| if arg == old { new } else { arg });
                     }
                 }
                 Inst::JumpTo(block, args) => {
@@ -219,10 +348,11 @@ impl Blocks {
 
     fn add(&mut self, ast: &Ast, scope: &mut Scope) -> usize {
         match ast {
-            Ast::FuncDef(..) => unreachable!(),
+            Ast::FuncDef(..) | Ast::Extern(..) => unreachable!(),
             Ast::I32(num) => self.add_consts(Value::i32(*num)),
             Ast::F64(num) => self.add_consts(Value::f64(*num)),
             Ast::Bool(val) => self.add_consts(Value::bool(*val)),
+            Ast::Str(val) => self.add_consts(Value::str(val)),
             Ast::Add(a, b) => {
                 let a = self.add(a, scope);
                 let b = self.add(b, scope);
@@ -308,25 +438,20 @@ impl Blocks {
                 // phi nodes
                 self.block_params[out_block].0 = self.num_vars;
 
-                for key in a_vars.keys() {
-                    self.add_arg_to_jump(a_jump, *a_vars.get(key).unwrap());
-
-                    if !b_vars.contains_key(key) {
-                        self.add_arg_to_jump(b_jump, scope.get(key).unwrap());
-                    }
-
-                    let t = self.var_type[scope.get(key).unwrap()].clone();
-                    scope.assign(key.clone(), self.add_param_to_block(out_block, t));
-                }
+                // every var assigned in either branch, in the same order for both jumps
+                let mut keys = a_vars.keys().chain(b_vars.keys()).collect::<Vec<&String>>();
+                keys.sort();
+                keys.dedup();
 
-                for key in b_vars.keys() {
-                    self.add_arg_to_jump(b_jump, *b_vars.get(key).unwrap());
+                for key in keys {
+                    let old = scope.get(key).unwrap();
+                    self.add_arg_to_jump(a_jump, *a_vars.get(key).unwrap_or(&old));
+                    self.add_arg_to_jump(b_jump, *b_vars.get(key).unwrap_or(&old));
 
-                    if !a_vars.contains_key(key) {
-                        self.add_arg_to_jump(a_jump, scope.get(key).unwrap());
-                        let t = self.var_type[scope.get(key).unwrap()].clone();
-                        scope.assign(key.clone(), self.add_param_to_block(out_block, t));
-                    }
+                    let t = self.var_type[old].clone();
+                    let param = self.add_param_to_block(out_block, t);
+                    self.name_var(param, key);
+                    scope.assign(key.clone(), param);
                 }
 
                 if a_ret != NO_VALUE {
@@ -338,12 +463,38 @@ impl Blocks {
                     NO_VALUE
                 }
             }
-            Ast::Ident(name) => scope.get(name).unwrap_or(usize::MAX),
+            Ast::Ident(name) => match scope.get(name) {
+                Some(var) => var,
+                None => {
+                    self.errors.push(format!("unknown variable {name}"));
+                    self.add_consts(Value::unit())
+                }
+            },
             Ast::FuncCall(func, args) => {
-                let func = self.add(func, scope);
+                let callee = match func.as_ref() {
+                    Ast::Ident(name) => match scope.get_func(name) {
+                        Some((_, _, num_params)) if num_params != args.len() => {
+                            let len = args.len();
+                            Err(format!("{name} takes {num_params} args but got {len}"))
+                        }
+                        Some((callee, return_type, _)) => Ok((callee, return_type)),
+                        None => Err(format!("unknown function {name}")),
+                    },
+                    _ => Err("only functions can be called by name".to_string()),
+                };
                 let arg_regs = args.iter().map(|arg| self.add(arg, scope)).collect();
-                let var = self.new_var(TypeDef::I32); // TODO: fix type
-                self.insts.push(Inst::Call(var, func, arg_regs));
+                let (callee, return_type) = match callee {
+                    Ok(callee) => callee,
+                    Err(msg) => {
+                        self.errors.push(msg);
+                        return self.add_consts(Value::unit());
+                    }
+                };
+                let var = self.new_var(return_type);
+                self.insts.push(match callee {
+                    Callee::Func(func) => Inst::Call(var, func, arg_regs),
+                    Callee::Extern(func) => Inst::CallExtern(var, func, arg_regs),
+                });
                 var
             }
             Ast::Block(nodes) => {
@@ -357,13 +508,23 @@ impl Blocks {
                 }
                 NO_VALUE
             }
+            Ast::Line(line, node) => {
+                self.lines.push((self.insts.len(), *line));
+                self.add(node, scope)
+            }
             Ast::Declair(name, node) => {
                 let var = self.add(&node, scope);
+                self.name_var(var, name);
                 scope.declair(name.clone(), var);
                 var
             }
             Ast::Assign(name, node) => {
                 let var = self.add(&node, scope);
+                if scope.get(name).is_none() {
+                    self.errors.push(format!("unknown variable {name}"));
+                    return var;
+                }
+                self.name_var(var, name);
                 scope.assign(name.clone(), var);
                 var
             }
@@ -388,16 +549,25 @@ impl Blocks {
 
                 // cond block params
                 self.block_params[cond_block].0 = self.num_vars;
-                for name in body_vars.keys() {
+                let mut names = body_vars.keys().collect::<Vec<&String>>();
+                names.sort();
+
+                let mut updates = vec![];
+                for name in names {
                     let old = scope.get(name).unwrap();
                     let arg = *body_vars.get(name).unwrap();
                     let new = self.add_param_to_block(cond_block, self.var_type[old].clone());
+                    self.name_var(new, name);
 
                     self.add_arg_to_jump(entry_jump, old);
                     self.add_arg_to_jump(body_jump, arg);
 
                     scope.assign(name.clone(), new);
+                    updates.push((old, new));
+                }
 
+                // only once every arg is added, so all of them get updated
+                for (old, new) in updates {
                     self.update(cond_block, old, new);
                 }
 
@@ -410,16 +580,106 @@ impl Blocks {
                 self.insts.push(Inst::Return(reg));
                 0
             }
-            Ast::Error => unimplemented!(),
+            Ast::Error => {
+                self.errors.push("can't parse the code".to_string());
+                self.add_consts(Value::unit())
+            }
             Ast::Array(nodes) => {
                 let vars = nodes
                     .iter()
                     .map(|node| self.add(&node, scope))
                     .collect::<Vec<usize>>();
 
-                return vars[0];
+                match vars.first() {
+                    Some(var) => *var,
+                    None => {
+                        self.errors.push("arrays can't be empty".to_string());
+                        self.add_consts(Value::unit())
+                    }
+                }
+            }
+        }
+    }
+
+    /// Get the instructions of <block>, up to and including its exit instruction.
+    ///
+    /// A block that is never jumped to might not have an exit (e.g. the block
+    /// after an if where both branches return), then it runs to the end.
+    pub fn block_insts(&self, block: Block) -> &[Inst] {
+        let start = self.blocks[block];
+        let end = self.insts[start..]
+            .iter()
+            .position(|inst| inst.is_exit())
+            .map(|len| start + len + 1)
+            .unwrap_or(self.insts.len());
+        return &self.insts[start..end];
+    }
+
+    /// Check if a block that can run has no exit, so it runs off the end.
+    pub fn falls_off(&self) -> bool {
+        let mut seen = vec![false; self.blocks.len()];
+        let mut todo = vec![0];
+        while let Some(block) = todo.pop() {
+            if std::mem::replace(&mut seen[block], true) {
+                continue;
+            }
+
+            match self.block_insts(block).last() {
+                Some(Inst::Return(..)) => {}
+                Some(Inst::Branch(_, (a, b))) => todo.extend([*a, *b]),
+                Some(Inst::JumpTo(target, _)) => todo.push(*target),
+                _ => return true,
             }
         }
+        return false;
+    }
+
+    /// Get the blocks in the order they are laid out in <insts>.
+    pub fn layout(&self) -> Vec<Block> {
+        let mut order = (0..self.blocks.len()).collect::<Vec<Block>>();
+        order.sort_by_key(|block| self.blocks[*block]);
+        return order;
+    }
+
+    /// Replace the instructions of every block, keeping the current layout.
+    ///
+    /// Anything that isn't part of a block (such as code after a return) is dropped.
+    pub fn set_block_insts(&mut self, mut insts: Vec<Vec<Inst>>) {
+        let layout = self
+            .layout()
+            .into_iter()
+            .map(|block| (block, std::mem::take(&mut insts[block])))
+            .collect();
+        self.set_layout(layout);
+    }
+
+    /// Get the line in the source of the statement the inst <step> is part of.
+    pub fn line(&self, step: usize) -> Option<usize> {
+        let i = self.lines.partition_point(|(inst, _)| *inst <= step);
+        return i.checked_sub(1).map(|i| self.lines[i].1);
+    }
+
+    /// Check if a statement starts at the inst <step>.
+    pub fn is_line_start(&self, step: usize) -> bool {
+        return self
+            .lines
+            .binary_search_by_key(&step, |(inst, _)| *inst)
+            .is_ok();
+    }
+
+    /// Check if a statement starts on the source line <line>.
+    pub fn has_line(&self, line: usize) -> bool {
+        return self.lines.iter().any(|(_, other)| *other == line);
+    }
+
+    /// Replace the instructions of every block, laying them out in the given order.
+    pub fn set_layout(&mut self, layout: Vec<(Block, Vec<Inst>)>) {
+        self.lines = vec![];
+        self.insts = vec![];
+        for (block, mut insts) in layout {
+            self.blocks[block] = self.insts.len();
+            self.insts.append(&mut insts);
+        }
     }
 
     pub fn log(&self, f: &mut impl std::io::Write) -> std::io::Result<()> {
@@ -446,6 +706,9 @@ impl Blocks {
                 Inst::UOp(var, op, a) => writeln!(f, "  v{var} = ({op:?} v{a})"),
                 Inst::Return(var) => writeln!(f, "  return v{var}"),
                 Inst::Call(var, func_id, args) => writeln!(f, "  v{var} = ${func_id}{args:?}"),
+                Inst::CallExtern(var, extern_id, args) => {
+                    writeln!(f, "  v{var} = extern ${extern_id}{args:?}")
+                }
                 Inst::JumpTo(block, args) => writeln!(
                     f,
                     "  '{block}({})",
@@ -8,6 +8,7 @@ pub enum Token<'a> {
     // literals
     I32(i32),
     F64(f64),
+    Str(&'a str),
 
     // punctuation
     Open(char),
@@ -60,16 +61,15 @@ fn parse_token(src: &str) -> (Token, usize) {
                     '0'..='9' => 1,
                     'a'..='z' | 'A'..='Z' | '_' => 4,
                     '.' => 3,
+                    '"' => 11,
                     _ => return (Token::Err, 1),
                 }
             }
             1 /* number */ => match chr {
                 '0'..='9' => 1,
                 '.' => 2,
-                _ => return (Token::I32(src[..len]
-                    .parse()
-                    .expect("unexpecter error parsing int token")
-                ), len)
+                // ints too big for an i32 are a mistake
+                _ => return (src[..len].parse().map_or(Token::Err, Token::I32), len)
             },
             2 /* float */ => match chr {
                 '0'..='9' => 2,
@@ -111,6 +111,16 @@ fn parse_token(src: &str) -> (Token, usize) {
                 '\x00' => return (Token::Comment, len - 1),
                 _ => 10
             }
+            11 /* string */ => match chr {
+                '"' => return (Token::Str(&src[1..len]), len + 1),
+                '\\' => 12,
+                '\x00' => return (Token::Err, len),
+                _ => 11
+            }
+            12 /* escaped character */ => match chr {
+                '\x00' => return (Token::Err, len),
+                _ => 11
+            }
             _ => unreachable!()
         };
 
@@ -128,9 +138,11 @@ pub struct Lexer<'a> {
 
 impl<'a> Lexer<'a> {
     pub fn new(src: &'a str) -> Self {
+        // skip the whitespace at the start instead of trimming it, so the lines stay the same
+        let src = src.trim_end();
         return Lexer {
-            src: src.trim(),
-            index: 0,
+            src,
+            index: calc_whitespace(src, 0),
             token: Token::Err,
         };
     }
@@ -148,6 +160,12 @@ impl<'a> Lexer<'a> {
     }
 
     fn _next(&mut self) {
+        // there's nothing after the end
+        if self.is_done() {
+            self.token = Token::Err;
+            return;
+        }
+
         let (tok, len) = parse_token(&self.src[self.index..]);
         self.index += len + calc_whitespace(&self.src, self.index + len);
         self.token = tok;
@@ -157,8 +175,13 @@ impl<'a> Lexer<'a> {
         self.index = index
     }
 
+    /// Get the line the next token is on, starting at 1.
+    pub fn line(&self) -> usize {
+        return self.src[..self.index].matches('\n').count() + 1;
+    }
+
     pub fn is_done(&self) -> bool {
-        return self.index == self.src.len();
+        return self.index >= self.src.len();
     }
 }
 
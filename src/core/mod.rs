@@ -1,13 +1,19 @@
+pub mod bytecode;
+pub mod debugger;
+pub mod interpreter;
 pub mod ir;
 pub mod lexer;
 pub mod module;
 pub mod parser;
+pub mod profiler;
 pub mod repl;
+pub mod tracer;
 pub mod value;
 
+pub use bytecode::*;
+pub use interpreter::*;
 pub use ir::*;
 pub use lexer::*;
 pub use module::*;
 pub use parser::*;
-pub use repl::*;
 pub use value::*;
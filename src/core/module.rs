@@ -1,20 +1,42 @@
 use crate::core::*;
-use crate::utils::*;
 
+use std::cell::OnceCell;
 use std::collections::HashMap;
 
+/// A rust function atlas code can call through an extern.
+pub type HostFunc = Box<dyn Fn(&[Value]) -> Value>;
+
 #[derive(Default)]
 pub struct Module<'a> {
+    /// The name the module is known by outside of atlas, like in stack traces.
+    pub name: String,
     pub scope: Scope<'a>,
-    pub funcs: Vec<Func>,
+    funcs: Vec<Func>,
+    /// The functions the host provides, imported before any of <funcs> in wasm.
+    pub externs: Vec<ExternDef>,
+    /// What the interpreter runs for each extern, by module and name.
+    pub hosts: HashMap<(String, String), HostFunc>,
+    /// How much the interpreter can do before it stops.
+    pub limits: Limits,
+    pub engine: Engine,
+    /// Where each function keeps its vars when the interpreter runs it, worked
+    /// out on the first run.
+    layouts: OnceCell<Vec<FrameLayout>>,
+    /// The bytecode of each function for the bytecode engine, compiled on the
+    /// first run.
+    chunks: OnceCell<Vec<Chunk>>,
+    /// Mistakes found in the source, it can't run if there are any.
+    pub errors: Vec<String>,
 }
 
 impl<'a> Module<'a> {
     pub fn from_src(src: &str) -> Self {
-        let mut module = Module::default();
+        return Module::from_defs(&parse(src));
+    }
 
-        // parse the source
-        let defs = parse(src);
+    /// Make a module out of the functions and externs in <defs>.
+    pub fn from_defs(defs: &[Ast]) -> Self {
+        let mut module = Module::default();
 
         // get all the funcions
         let funcs = defs
@@ -25,28 +47,134 @@ impl<'a> Module<'a> {
             })
             .collect::<Vec<&FuncDef>>();
 
+        // get all the externs
+        module.externs = defs
+            .iter()
+            .filter_map(|ast| match ast {
+                Ast::Extern(extern_def) => Some(extern_def.clone()),
+                _ => None,
+            })
+            .collect();
+
         // register the functions in the scope
         for i in 0..funcs.len() {
             module
                 .scope
                 .declair(funcs[i].name.clone(), module.funcs.len() + i);
+            let callee = Callee::Func(module.funcs.len() + i);
+            module.scope.funcs.insert(
+                funcs[i].name.clone(),
+                (callee, funcs[i].return_type, funcs[i].params.len()),
+            );
+        }
+        for (i, extern_def) in module.externs.iter().enumerate() {
+            let callee = Callee::Extern(i);
+            module.scope.funcs.insert(
+                extern_def.name.clone(),
+                (callee, extern_def.return_type, extern_def.params.len()),
+            );
+            for param in &extern_def.params {
+                if param.param_type == TypeDef::Unit {
+                    let name = &extern_def.name;
+                    let msg = format!("the param {} can't be Unit in {name}", param.name);
+                    module.errors.push(msg);
+                }
+            }
         }
 
         // turn the functions in to ir
         for func_def in funcs {
-            module.funcs.push(Func::new(&module, func_def));
+            let mut func = Func::new(&module, func_def);
+            for err in std::mem::take(&mut func.ir.errors) {
+                module.errors.push(format!("{err} in {}", func.name));
+            }
+            module.funcs.push(func);
         }
 
         return module;
     }
 
-    pub fn exec(&self, name: &str, args: Vec<Value>) -> Value {
-        if let Some(func) = self.get(name) {
-            let memory = &mut Mem::default();
-            return exec_ir(func, &self.funcs, memory, args);
-        } else {
-            unimplemented!();
+    /// Run the function <name>, stopping it if it goes past the limits.
+    pub fn exec(&self, name: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let func = self.get_callable(name, &args)?;
+        return match self.engine {
+            Engine::Ir => exec_ir(func, self, args, &self.limits, None),
+            Engine::Bytecode => {
+                let func_id = self.scope.get(name).unwrap();
+                exec_bytecode(self, func_id, args, &self.limits)
+            }
+            Engine::Wasm => self.exec_wasm(name, args),
+        };
+    }
+
+    /// Run the function <name> with the ir engine, letting <debugger> look at
+    /// every instruction before it runs.
+    pub fn debug(
+        &self,
+        name: &str,
+        args: Vec<Value>,
+        debugger: &mut dyn Debugger,
+    ) -> Result<Value, RuntimeError> {
+        let func = self.get_callable(name, &args)?;
+        return exec_ir(func, self, args, &self.limits, Some(debugger));
+    }
+
+    /// Get the function <name>, if it can be called with <args>.
+    pub(crate) fn get_callable(&self, name: &str, args: &[Value]) -> Result<&Func, RuntimeError> {
+        let error = |trap| RuntimeError {
+            trap,
+            stack: vec![],
+        };
+
+        let Some(func) = self.get(name) else {
+            return Err(error(Trap::UnknownFunction(name.to_string())));
+        };
+
+        // the args have to be what the function takes
+        let types: Vec<TypeDef> = args.iter().map(|arg| arg.get_type()).collect();
+        if types[..] != func.ir.var_type[..func.num_params] {
+            let msg = format!("{name} can't be called with {types:?}");
+            return Err(error(Trap::TypeMismatch(msg)));
         }
+        return Ok(func);
+    }
+
+    /// Run <host> when the interpreter calls the extern <name> from <module>.
+    pub fn register(
+        &mut self,
+        module: &str,
+        name: &str,
+        host: impl Fn(&[Value]) -> Value + 'static,
+    ) {
+        self.hosts
+            .insert((module.to_string(), name.to_string()), Box::new(host));
+    }
+
+    pub fn funcs(&self) -> &[Func] {
+        return &self.funcs;
+    }
+
+    /// Change the functions, anything worked out from them is thrown away.
+    pub fn funcs_mut(&mut self) -> &mut Vec<Func> {
+        self.layouts = OnceCell::new();
+        self.chunks = OnceCell::new();
+        return &mut self.funcs;
+    }
+
+    pub(crate) fn layouts(&self) -> &[FrameLayout] {
+        return self
+            .layouts
+            .get_or_init(|| self.funcs.iter().map(FrameLayout::new).collect());
+    }
+
+    pub(crate) fn chunks(&self) -> &[Chunk] {
+        return self.chunks.get_or_init(|| {
+            self.funcs
+                .iter()
+                .enumerate()
+                .map(|(func_id, func)| Chunk::new(func_id, func))
+                .collect()
+        });
     }
 
     pub fn get(&self, name: &str) -> Option<&Func> {
@@ -65,6 +193,9 @@ impl<'a> Module<'a> {
 pub struct Scope<'a> {
     pub assign: HashMap<String, usize>,
     pub locals: HashMap<String, usize>,
+    /// The functions that can be called, the types they return and how many
+    /// params they take.
+    pub funcs: HashMap<String, (Callee, TypeDef, usize)>,
     parent: Option<&'a Scope<'a>>,
 }
 
@@ -81,6 +212,16 @@ impl<'a> Scope<'a> {
         }
     }
 
+    pub fn get_func(&self, name: &str) -> Option<(Callee, TypeDef, usize)> {
+        if let Some(func) = self.funcs.get(name) {
+            return Some(*func);
+        } else if let Some(parent) = self.parent {
+            return parent.get_func(name);
+        } else {
+            return None;
+        }
+    }
+
     pub fn declair(&mut self, name: String, value: usize) {
         self.locals.insert(name, value);
     }
@@ -99,6 +240,7 @@ impl<'a> Scope<'a> {
         return Scope {
             assign: HashMap::new(),
             locals: HashMap::new(),
+            funcs: HashMap::new(),
             parent: Some(self),
         };
     }
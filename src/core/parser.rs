@@ -7,12 +7,15 @@ pub enum Ast {
     Ident(String),
     FuncCall(Box<Ast>, Vec<Ast>),
     Block(Vec<Ast>),
+    /// A statement in a block, and the line it starts on.
+    Line(usize, Box<Ast>),
     Error,
 
     // literals
     I32(i32),
     F64(f64),
     Bool(bool),
+    Str(String),
 
     // unary operator
     Negative(Box<Ast>),
@@ -45,6 +48,7 @@ pub enum Ast {
 
     // defintions
     FuncDef(FuncDef),
+    Extern(ExternDef),
 }
 
 #[derive(Debug, Clone)]
@@ -55,6 +59,16 @@ pub struct FuncDef {
     pub body: Box<Ast>,
 }
 
+/// A function the host provides, like `extern "env" log(x: I32): Unit`.
+#[derive(Debug, Clone)]
+pub struct ExternDef {
+    /// The module the host provides it in, "env" if none is given.
+    pub module: String,
+    pub name: String,
+    pub params: Vec<Param>,
+    pub return_type: TypeDef,
+}
+
 #[derive(Debug, Clone)]
 pub struct Param {
     pub name: String,
@@ -85,10 +99,12 @@ fn parse_value(lex: &mut Lexer) -> Ast {
         }
         Token::I32(value) => Ast::I32(value),
         Token::F64(value) => Ast::F64(value),
+        Token::Str(value) => Ast::Str(unescape(value)),
         Token::Ident("true") => Ast::Bool(true),
         Token::Ident("false") => Ast::Bool(false),
         Token::Ident("while") => Ast::While(Box::new(parse_expr(lex)), Box::new(parse_expr(lex))),
         Token::Ident("return") => Ast::Return(Box::new(parse_expr(lex))),
+        Token::Ident("extern") => parse_extern(lex).unwrap_or(Ast::Error),
         Token::Ident("let") => {
             let name = if let Token::Ident(name) = lex.next() {
                 name.to_string()
@@ -137,7 +153,9 @@ fn parse_value(lex: &mut Lexer) -> Ast {
                 }
 
                 // parse return type
-                let return_type = parse_type(lex);
+                let Some(return_type) = parse_type(lex) else {
+                    return Ast::Error;
+                };
 
                 // parse body
                 let body = Box::new(parse_expr(lex));
@@ -155,13 +173,20 @@ fn parse_value(lex: &mut Lexer) -> Ast {
         Token::Open('{') => {
             let mut statements = vec![];
             while !check(lex, Token::Close('}')) {
-                statements.push(parse_expr(lex));
+                if lex.is_done() {
+                    return Ast::Error;
+                }
+                let line = lex.line();
+                statements.push(Ast::Line(line, Box::new(parse_expr(lex))));
             }
             Ast::Block(statements)
         }
         Token::Open('[') => {
             let mut values = vec![];
             while !check(lex, Token::Close(']')) {
+                if lex.is_done() {
+                    return Ast::Error;
+                }
                 values.push(parse_expr(lex));
                 check(lex, Token::Comma);
             }
@@ -171,6 +196,68 @@ fn parse_value(lex: &mut Lexer) -> Ast {
     }
 }
 
+fn parse_extern(lex: &mut Lexer) -> Option<Ast> {
+    let save = lex.save();
+    let module = match lex.next() {
+        Token::Str(module) => module.to_string(),
+        _ => {
+            lex.load(save);
+            "env".to_string()
+        }
+    };
+
+    let Token::Ident(name) = lex.next() else {
+        return None;
+    };
+    let name = name.to_string();
+
+    if lex.next() != Token::Open('(') {
+        return None;
+    }
+
+    let mut params = vec![];
+    if !check(lex, Token::Close(')')) {
+        loop {
+            params.push(parse_param(lex)?);
+
+            if lex.next() == Token::Close(')') {
+                break;
+            }
+        }
+    }
+
+    if lex.next() != Token::Colon {
+        return None;
+    }
+
+    return Some(Ast::Extern(ExternDef {
+        module,
+        name,
+        params,
+        return_type: parse_type(lex)?,
+    }));
+}
+
+/// Replace the escape sequences in a string with what they stand for.
+fn unescape(value: &str) -> String {
+    let mut string = String::new();
+    let mut chars = value.chars();
+    while let Some(chr) = chars.next() {
+        if chr != '\\' {
+            string.push(chr);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => string.push('\n'),
+            Some('t') => string.push('\t'),
+            Some(chr) => string.push(chr),
+            None => {}
+        }
+    }
+    return string;
+}
+
 fn parse_func_call(lex: &mut Lexer) -> Ast {
     let value = parse_value(lex);
 
@@ -181,6 +268,9 @@ fn parse_func_call(lex: &mut Lexer) -> Ast {
             params.push(parse_expr(lex));
 
             while lex.next() != Token::Close(')') {
+                if lex.is_done() {
+                    return Ast::Error;
+                }
                 params.push(parse_expr(lex));
             }
         }
@@ -244,12 +334,14 @@ fn parse_expr(lex: &mut Lexer) -> Ast {
     return parse_cmp(lex);
 }
 
-fn parse_type(lex: &mut Lexer) -> TypeDef {
+fn parse_type(lex: &mut Lexer) -> Option<TypeDef> {
     match lex.next() {
-        Token::Ident("I32") => TypeDef::I32,
-        Token::Ident("F64") => TypeDef::F64,
-        Token::Ident("Bool") => TypeDef::Bool,
-        _ => unimplemented!(),
+        Token::Ident("I32") => Some(TypeDef::I32),
+        Token::Ident("F64") => Some(TypeDef::F64),
+        Token::Ident("Bool") => Some(TypeDef::Bool),
+        Token::Ident("Unit") => Some(TypeDef::Unit),
+        Token::Ident("Str") => Some(TypeDef::Str),
+        _ => None,
     }
 }
 
@@ -268,7 +360,7 @@ fn parse_param(lex: &mut Lexer) -> Option<Param> {
 
     return Some(Param {
         name,
-        param_type: parse_type(lex),
+        param_type: parse_type(lex)?,
     });
 }
 
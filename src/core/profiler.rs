@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+use crate::core::*;
+use crate::utils::*;
+
+/// What ran in a block of a function.
+#[derive(Debug, Clone, Default)]
+pub struct BlockProfile {
+    pub insts: u64,
+    /// How many times the block was started.
+    pub runs: u64,
+    pub time: Duration,
+    /// How many times the block was jumped back to, if it's the header of a loop.
+    pub iterations: Option<u64>,
+}
+
+/// What ran in a function, not counting the functions it called.
+#[derive(Debug, Clone)]
+pub struct FuncProfile {
+    pub name: String,
+    pub insts: u64,
+    pub calls: u64,
+    pub time: Duration,
+    pub blocks: Vec<BlockProfile>,
+    /// The block that starts at each inst.
+    starts: Vec<Option<Block>>,
+    /// The blocks in each loop, by their header.
+    loops: Vec<(Block, Vec<Block>)>,
+}
+
+impl FuncProfile {
+    fn new(func: &Func) -> FuncProfile {
+        let mut starts = vec![None; func.ir.insts.len()];
+        for (block, start) in func.ir.blocks.iter().enumerate() {
+            if let Some(start) = starts.get_mut(*start) {
+                *start = Some(block);
+            }
+        }
+
+        let loops = get_loops(func);
+        let mut blocks = vec![BlockProfile::default(); func.ir.blocks.len()];
+        for (header, _) in &loops {
+            blocks[*header].iterations = Some(0);
+        }
+
+        return FuncProfile {
+            name: func.name.clone(),
+            insts: 0,
+            calls: 0,
+            time: Duration::ZERO,
+            blocks,
+            starts,
+            loops,
+        };
+    }
+}
+
+/// A function that is running, and the block it is in.
+struct Frame {
+    func: usize,
+    block: Block,
+}
+
+/// Counts the instructions, calls and time of every function and block that
+/// runs, and the instructions run with each stack of calls.
+#[derive(Default)]
+pub struct Profiler {
+    pub funcs: Vec<FuncProfile>,
+    /// The index in <funcs> of each function, by name.
+    ids: HashMap<String, usize>,
+    frames: Vec<Frame>,
+    /// How many instructions ran with each stack of functions, the first function first.
+    stacks: HashMap<Vec<usize>, u64>,
+    /// The instructions that ran since the stack last changed.
+    stack_insts: u64,
+    last: Option<Instant>,
+}
+
+impl Profiler {
+    fn func_id(&mut self, func: &Func) -> usize {
+        if let Some(id) = self.ids.get(&func.name) {
+            return *id;
+        }
+        self.funcs.push(FuncProfile::new(func));
+        self.ids.insert(func.name.clone(), self.funcs.len() - 1);
+        return self.funcs.len() - 1;
+    }
+
+    /// Count the instructions that ran with the current stack before it changes.
+    fn flush_stack(&mut self) {
+        if self.stack_insts > 0 {
+            let stack = self.frames.iter().map(|frame| frame.func).collect();
+            *self.stacks.entry(stack).or_default() += self.stack_insts;
+            self.stack_insts = 0;
+        }
+    }
+
+    /// Write the functions and their blocks, the ones that ran the most
+    /// instructions first. Loops are marked with how many times they went around.
+    pub fn report(&self, out: &mut impl Write) -> std::io::Result<()> {
+        let total = self.funcs.iter().map(|func| func.insts).sum::<u64>().max(1);
+        let percent = |insts: u64| insts as f64 * 100.0 / total as f64;
+
+        let mut funcs: Vec<&FuncProfile> = self.funcs.iter().collect();
+        funcs.sort_by_key(|func| std::cmp::Reverse(func.insts));
+
+        writeln!(
+            out,
+            "{:>10} {:>6} {:>8} {:>12}  function",
+            "insts", "%", "calls", "time"
+        )?;
+        for func in funcs {
+            writeln!(
+                out,
+                "{:>10} {:>5.1}% {:>8} {:>12?}  {}",
+                func.insts,
+                percent(func.insts),
+                func.calls,
+                func.time,
+                func.name
+            )?;
+
+            let mut blocks: Vec<(Block, &BlockProfile)> = func
+                .blocks
+                .iter()
+                .enumerate()
+                .filter(|(_, block)| block.runs > 0)
+                .collect();
+            blocks.sort_by_key(|(_, block)| std::cmp::Reverse(block.insts));
+
+            for (block, profile) in blocks {
+                write!(
+                    out,
+                    "{:>10} {:>5.1}% {:>8} {:>12?}    '{block}",
+                    profile.insts,
+                    percent(profile.insts),
+                    profile.runs,
+                    profile.time
+                )?;
+                match profile.iterations {
+                    Some(iterations) => writeln!(out, " loop, {iterations} iterations")?,
+                    None => writeln!(out)?,
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    /// Write how many instructions ran with each stack of calls, in the
+    /// collapsed format flamegraph tools read, like `main;fib;fib 120`.
+    pub fn write_stacks(&self, out: &mut impl Write) -> std::io::Result<()> {
+        // the instructions since the stack last changed haven't been counted yet
+        let mut counts = self.stacks.clone();
+        if self.stack_insts > 0 {
+            let stack = self.frames.iter().map(|frame| frame.func).collect();
+            *counts.entry(stack).or_default() += self.stack_insts;
+        }
+
+        let mut stacks: Vec<(String, u64)> = counts
+            .iter()
+            .map(|(stack, insts)| {
+                let names: Vec<&str> = stack
+                    .iter()
+                    .map(|func| self.funcs[*func].name.as_str())
+                    .collect();
+                (names.join(";"), *insts)
+            })
+            .collect();
+        stacks.sort();
+
+        for (stack, insts) in stacks {
+            writeln!(out, "{stack} {insts}")?;
+        }
+        return Ok(());
+    }
+}
+
+impl Debugger for Profiler {
+    fn step(&mut self, paused: &Paused) -> Result<(), Trap> {
+        // the time since the last step is how long its inst took
+        let now = Instant::now();
+        if let (Some(last), Some(frame)) = (self.last, self.frames.last()) {
+            let func = &mut self.funcs[frame.func];
+            func.time += now - last;
+            func.blocks[frame.block].time += now - last;
+        }
+
+        let depth = paused.depth();
+        let mut is_call = false;
+        if self.frames.len() != depth + 1 || paused.step == 0 {
+            self.flush_stack();
+
+            // a tail call reuses the frame of the function it came from
+            if paused.step == 0 {
+                self.frames.truncate(depth);
+            } else {
+                self.frames.truncate(depth + 1);
+            }
+
+            if self.frames.len() == depth {
+                let func = self.func_id(paused.func);
+                self.funcs[func].calls += 1;
+                self.frames.push(Frame { func, block: 0 });
+                is_call = true;
+            }
+        }
+
+        let frame = self.frames.last_mut().unwrap();
+        let func = &mut self.funcs[frame.func];
+        if let Some(Some(block)) = func.starts.get(paused.step) {
+            // jumping to a loop header from inside the loop goes around again
+            let is_back_edge = func
+                .loops
+                .iter()
+                .any(|(header, body)| header == block && body.contains(&frame.block));
+            if is_back_edge && !is_call {
+                *func.blocks[*block].iterations.as_mut().unwrap() += 1;
+            }
+
+            frame.block = *block;
+            func.blocks[*block].runs += 1;
+        }
+
+        func.insts += 1;
+        func.blocks[frame.block].insts += 1;
+        self.stack_insts += 1;
+
+        // don't count the time spent profiling
+        self.last = Some(Instant::now());
+        return Ok(());
+    }
+}
@@ -1,134 +1,239 @@
-use std::collections::HashMap;
+use std::io::{BufRead, Write};
 
 use crate::core::*;
-use crate::utils::*;
 
-struct Regs<'a> {
-    mem: Mem,
-    current_reg: usize,
-    vars: HashMap<usize, usize>,
-    func: &'a Func,
+/// The function an expression typed in to the repl runs in, a name no function
+/// in the source can have.
+const EVAL_NAME: &str = "<repl>";
+
+/// Runs atlas code a piece at a time. Functions and externs stay defined for
+/// everything after them, and defining one again replaces it.
+pub struct Repl {
+    defs: Vec<Ast>,
+    /// Gets a module ready to run, like registering its host functions.
+    setup: Box<dyn Fn(&mut Module)>,
 }
 
-impl<'a> Regs<'a> {
-    fn new(func: &Func) -> Regs {
-        return Regs {
-            mem: Mem::default(),
-            current_reg: 0,
-            vars: HashMap::new(),
-            func,
+impl Repl {
+    pub fn new(setup: impl Fn(&mut Module) + 'static) -> Self {
+        return Repl {
+            defs: vec![],
+            setup: Box::new(setup),
         };
     }
 
-    fn assign(&mut self, var: &usize, val: &Value) {
-        if let Some(reg) = self.vars.get(var) {
-            self.mem.set(*reg, val.get_bytes());
-        } else {
-            self.vars.insert(*var, self.current_reg);
-            self.mem.set(self.current_reg, val.get_bytes());
-            self.current_reg += val.get_size();
-        }
-    }
+    /// Read code from <input> until it runs out, running it whenever its braces
+    /// are balanced.
+    pub fn run(&mut self, mut input: impl BufRead, out: &mut impl Write) -> std::io::Result<()> {
+        let mut src = String::new();
+        loop {
+            write!(out, "{}", if src.is_empty() { ">> " } else { ".. " })?;
+            out.flush()?;
+
+            let mut line = String::new();
+            if input.read_line(&mut line)? == 0 {
+                writeln!(out)?;
+                return Ok(());
+            }
+            src.push_str(&line);
+            if is_open(&src) {
+                continue;
+            }
 
-    fn get(&self, var: &usize) -> Value {
-        return self
-            .mem
-            .get(*self.vars.get(var).unwrap(), self.func.get_var_type(*var));
+            let src = std::mem::take(&mut src);
+            if let ":q" | ":quit" = src.trim() {
+                return Ok(());
+            }
+            self.eval(&src, out)?;
+        }
     }
-}
 
-pub fn exec_ir(func: &Func, funcs: &Vec<Func>, mem: &mut Mem, args: Vec<Value>) -> Value {
-    let mut step = 0;
-    let mut regs = Regs::new(func);
+    /// Run a piece of code, or one of the commands:
+    ///
+    /// - `:ir <name>` writes the ir of a function
+    /// - `:wat <name>` writes the wat of a function
+    /// - `:type <expr>` writes the type of an expression without running it
+    /// - `:load <file>` runs the code in a file
+    pub fn eval(&mut self, src: &str, out: &mut impl Write) -> std::io::Result<()> {
+        let src = src.trim();
+        let (command, arg) = match src.split_once(char::is_whitespace) {
+            Some((command, arg)) => (command, arg.trim()),
+            None => (src, ""),
+        };
 
-    for (i, arg) in args.into_iter().enumerate() {
-        regs.assign(&i, &arg);
+        match command {
+            "" => {}
+            ":ir" => match self.compile(None) {
+                Ok(module) => match module.get(arg) {
+                    Some(func) => func.log(out)?,
+                    None => writeln!(out, "ERR unknown function {arg}")?,
+                },
+                Err(msg) => writeln!(out, "ERR {msg}")?,
+            },
+            ":wat" => {
+                let wat = self
+                    .compile(None)
+                    .map(|module| String::from_utf8_lossy(&module.to_wat()).to_string());
+                match wat.map(|wat| get_wat_func(&wat, arg)) {
+                    Ok(Some(func)) => writeln!(out, "{func}")?,
+                    Ok(None) => writeln!(out, "ERR unknown function {arg}")?,
+                    Err(msg) => writeln!(out, "ERR {msg}")?,
+                }
+            }
+            ":type" => match try_parse(arg).and_then(|asts| asts.into_iter().next()) {
+                Some(expr) if is_expr(&expr) => match self.compile(Some(expr)) {
+                    Ok(module) => {
+                        let ir = &module.get(EVAL_NAME).unwrap().ir;
+                        match ir.insts.iter().find(|inst| matches!(inst, Inst::Return(_))) {
+                            Some(Inst::Return(var)) => writeln!(out, "{:?}", ir.var_type[*var])?,
+                            _ => writeln!(out, "ERR {arg} has no value")?,
+                        }
+                    }
+                    Err(msg) => writeln!(out, "ERR {msg}")?,
+                },
+                _ => writeln!(out, "ERR {arg} isn't an expression")?,
+            },
+            ":load" => match std::fs::read_to_string(arg) {
+                Ok(src) => self.eval_code(&src, out)?,
+                Err(err) => writeln!(out, "ERR can't read {arg}, {err}")?,
+            },
+            _ if command.starts_with(':') => writeln!(out, "ERR unknown command {command}")?,
+            _ => self.eval_code(src, out)?,
+        }
+        return Ok(());
     }
 
-    loop {
-        step += 1;
-        match &func.ir.insts[step - 1] {
-            Inst::Op(var, op, a, b) => {
-                let a = regs.get(a);
-                let b = regs.get(b);
+    /// Define the functions and externs in <src>, and run its expressions.
+    fn eval_code(&mut self, src: &str, out: &mut impl Write) -> std::io::Result<()> {
+        let Some(asts) = try_parse(src) else {
+            writeln!(out, "ERR can't parse {}", src.trim())?;
+            return Ok(());
+        };
 
-                regs.assign(var, &do_op(op, a, b));
+        for ast in asts {
+            if is_expr(&ast) {
+                let module = match self.compile(Some(ast)) {
+                    Ok(module) => module,
+                    Err(msg) => {
+                        writeln!(out, "ERR {msg}")?;
+                        continue;
+                    }
+                };
+                match module.exec(EVAL_NAME, vec![]) {
+                    Ok(Value::Unit) => {}
+                    Ok(value) => writeln!(out, "{value}")?,
+                    Err(err) => writeln!(out, "ERR {err}")?,
+                }
+                continue;
             }
-            Inst::UOp(var, op, a) => {
-                let a = regs.get(a);
 
-                regs.assign(var, &do_uop(op, a));
-            }
-            Inst::Const(var, val) => {
-                regs.assign(var, val);
+            // replace the old definition, unless the new one doesn't compile
+            let old = self.defs.clone();
+            match self
+                .defs
+                .iter()
+                .position(|def| def_name(def) == def_name(&ast))
+            {
+                Some(i) => self.defs[i] = ast,
+                None => self.defs.push(ast),
             }
-            Inst::Call(var, func_id_reg, param_regs) => {
-                let args = param_regs.iter().map(|var| regs.get(var)).collect();
-                regs.assign(var, &exec_ir(&funcs[*func_id_reg], funcs, mem, args));
+            if let Err(msg) = self.compile(None) {
+                writeln!(out, "ERR {msg}")?;
+                self.defs = old;
             }
-            Inst::JumpTo(block, args) => {
-                step = func.ir.blocks[*block];
+        }
+        return Ok(());
+    }
 
-                let (first_param, num_params) = func.ir.block_params[*block];
+    /// Make a module out of the definitions so far, with <expr> in a function to run it.
+    fn compile(&self, expr: Option<Ast>) -> Result<Module<'static>, String> {
+        let mut defs = self.defs.clone();
+        if let Some(expr) = expr {
+            defs.push(Ast::FuncDef(FuncDef {
+                name: EVAL_NAME.to_string(),
+                params: vec![],
+                return_type: TypeDef::Unit,
+                body: Box::new(Ast::Return(Box::new(expr))),
+            }));
+        }
 
-                for i in 0..num_params {
-                    regs.assign(&(first_param + i), &regs.get(&args[i]));
-                }
-            }
-            Inst::Branch(cond, (a, b)) => {
-                if regs.get(cond).as_bool() {
-                    step = func.ir.blocks[*a];
-                } else {
-                    step = func.ir.blocks[*b];
-                }
-            }
-            Inst::Return(var) => {
-                return regs.get(var);
-            }
+        let mut module = Module::from_defs(&defs);
+        if let Some(err) = module.errors.first() {
+            return Err(err.clone());
+        }
+
+        // the expression's function returns its type, so wasm gets its value too
+        if let Some(func) = module
+            .funcs_mut()
+            .iter_mut()
+            .find(|func| func.name == EVAL_NAME)
+        {
+            let returned = func.ir.insts.iter().find_map(|inst| match inst {
+                Inst::Return(var) => Some(func.ir.var_type[*var]),
+                _ => None,
+            });
+            func.return_type = returned.unwrap_or(TypeDef::Unit);
         }
+        (self.setup)(&mut module);
+        return Ok(module);
     }
 }
 
-fn do_op(op: &Op, a: Value, b: Value) -> Value {
-    match (op, a.get_type(), b.get_type()) {
-        (Op::Eq, TypeDef::Bool, TypeDef::Bool) => Value::bool(a.as_bool() == b.as_bool()),
-        (Op::Ne, TypeDef::Bool, TypeDef::Bool) => Value::bool(a.as_bool() != b.as_bool()),
-
-        (Op::Add, TypeDef::I32, TypeDef::I32) => Value::i32(a.as_i32() + b.as_i32()),
-        (Op::Sub, TypeDef::I32, TypeDef::I32) => Value::i32(a.as_i32() - b.as_i32()),
-        (Op::Mul, TypeDef::I32, TypeDef::I32) => Value::i32(a.as_i32() * b.as_i32()),
-        (Op::Div, TypeDef::I32, TypeDef::I32) => Value::i32(a.as_i32() / b.as_i32()),
-
-        (Op::Eq, TypeDef::I32, TypeDef::I32) => Value::bool(a.as_i32() == b.as_i32()),
-        (Op::Ne, TypeDef::I32, TypeDef::I32) => Value::bool(a.as_i32() != b.as_i32()),
-        (Op::Le, TypeDef::I32, TypeDef::I32) => Value::bool(a.as_i32() <= b.as_i32()),
-        (Op::Lt, TypeDef::I32, TypeDef::I32) => Value::bool(a.as_i32() < b.as_i32()),
-        (Op::Ge, TypeDef::I32, TypeDef::I32) => Value::bool(a.as_i32() >= b.as_i32()),
-        (Op::Gt, TypeDef::I32, TypeDef::I32) => Value::bool(a.as_i32() > b.as_i32()),
-
-        (Op::Add, TypeDef::F64, TypeDef::F64) => Value::f64(a.as_f64() + b.as_f64()),
-        (Op::Sub, TypeDef::F64, TypeDef::F64) => Value::f64(a.as_f64() - b.as_f64()),
-        (Op::Mul, TypeDef::F64, TypeDef::F64) => Value::f64(a.as_f64() * b.as_f64()),
-        (Op::Div, TypeDef::F64, TypeDef::F64) => Value::f64(a.as_f64() / b.as_f64()),
-
-        (Op::Eq, TypeDef::F64, TypeDef::F64) => Value::bool(a.as_f64() == b.as_f64()),
-        (Op::Ne, TypeDef::F64, TypeDef::F64) => Value::bool(a.as_f64() != b.as_f64()),
-        (Op::Le, TypeDef::F64, TypeDef::F64) => Value::bool(a.as_f64() <= b.as_f64()),
-        (Op::Lt, TypeDef::F64, TypeDef::F64) => Value::bool(a.as_f64() < b.as_f64()),
-        (Op::Ge, TypeDef::F64, TypeDef::F64) => Value::bool(a.as_f64() >= b.as_f64()),
-        (Op::Gt, TypeDef::F64, TypeDef::F64) => Value::bool(a.as_f64() > b.as_f64()),
-
-        _ => unimplemented!(),
+/// Parse <src>, if it doesn't have any mistakes.
+fn try_parse(src: &str) -> Option<Vec<Ast>> {
+    let asts = parse(src);
+    if asts.iter().any(|ast| matches!(ast, Ast::Error)) {
+        return None;
     }
+    return Some(asts);
 }
 
-fn do_uop(op: &UOp, a: Value) -> Value {
-    match (op, a.get_type()) {
-        (UOp::Neg, TypeDef::I32) => Value::i32(-a.as_i32()),
-        (UOp::Neg, TypeDef::F64) => Value::f64(-a.as_f64()),
+fn is_expr(ast: &Ast) -> bool {
+    return !matches!(ast, Ast::FuncDef(_) | Ast::Extern(_));
+}
 
-        (UOp::Not, TypeDef::Bool) => Value::bool(!a.as_bool()),
+fn def_name(ast: &Ast) -> Option<&str> {
+    return match ast {
+        Ast::FuncDef(func_def) => Some(&func_def.name),
+        Ast::Extern(extern_def) => Some(&extern_def.name),
+        _ => None,
+    };
+}
 
-        _ => unimplemented!(),
+/// Check if <src> has a `{` that isn't closed yet, so it needs more lines.
+fn is_open(src: &str) -> bool {
+    let mut depth = 0;
+    let mut chars = src.chars();
+    while let Some(chr) = chars.next() {
+        match chr {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            // braces in strings and comments don't count
+            '"' => {
+                while let Some(chr) = chars.next() {
+                    match chr {
+                        '\\' => _ = chars.next(),
+                        '"' => break,
+                        _ => {}
+                    }
+                }
+            }
+            '/' if chars.clone().next() == Some('/') => {
+                while chars.next().is_some_and(|chr| chr != '\n') {}
+            }
+            _ => {}
+        }
     }
+    return depth > 0;
+}
+
+/// Get the function <name> out of the wat of a module.
+fn get_wat_func(wat: &str, name: &str) -> Option<String> {
+    let start = format!("(func ${name}");
+    let lines: Vec<&str> = wat.lines().collect();
+    let first = lines
+        .iter()
+        .position(|line| *line == start || line.starts_with(&format!("{start} ")))?;
+    let len = lines[first..].iter().position(|line| *line == ")")?;
+    return Some(lines[first..=first + len].join("\n"));
 }
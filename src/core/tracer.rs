@@ -0,0 +1,149 @@
+use std::io::Write;
+
+use crate::core::*;
+
+/// Writes every instruction the interpreter runs to <out>, with the values it
+/// reads and writes, in a format that stays the same between runs so traces can
+/// be diffed.
+///
+/// Every call starts with the function and its args, and the functions it
+/// calls are indented under it. Blocks start with their label, and jumps show
+/// the values passed to the params of the block.
+pub struct Tracer<W: Write> {
+    out: W,
+    funcs: Vec<String>,
+    externs: Vec<String>,
+    /// The last inst, if it writes a var that only has its value once it has
+    /// run, and its step.
+    pending: Option<(Var, String, usize)>,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(module: &Module, out: W) -> Self {
+        return Tracer {
+            out,
+            funcs: module
+                .funcs()
+                .iter()
+                .map(|func| func.name.clone())
+                .collect(),
+            externs: module
+                .externs
+                .iter()
+                .map(|extern_def| extern_def.name.clone())
+                .collect(),
+            pending: None,
+        };
+    }
+
+    fn trace(&mut self, paused: &Paused) -> std::io::Result<()> {
+        let func = paused.func;
+        let indent = "    ".repeat(paused.depth());
+        let value = |var: Var| format!("{:?}", paused.vars[var]);
+        let values = |vars: &[Var]| {
+            vars.iter()
+                .map(|var| value(*var))
+                .collect::<Vec<String>>()
+                .join(" ")
+        };
+        let names = |vars: &[Var]| {
+            vars.iter()
+                .map(|var| format!("v{var}"))
+                .collect::<Vec<String>>()
+                .join(", ")
+        };
+
+        // the last inst didn't change frames, so its var is in this one
+        if let Some((var, line, _)) = self.pending.take() {
+            writeln!(self.out, "{line} -> {}", value(var))?;
+        }
+
+        // a call, or a tail call that reused the frame
+        if paused.step == 0 {
+            let args = paused.vars[..func.num_params]
+                .iter()
+                .map(|arg| format!("{arg:?}"))
+                .collect::<Vec<String>>();
+            writeln!(self.out, "{indent}{}({}):", func.name, args.join(", "))?;
+        }
+
+        if let Some(block) = func
+            .ir
+            .blocks
+            .iter()
+            .rposition(|start| *start == paused.step)
+        {
+            writeln!(self.out, "{indent}  '{block}:")?;
+        }
+
+        let Some(inst) = func.ir.insts.get(paused.step) else {
+            return Ok(());
+        };
+        let indent = format!("{indent}    ");
+        match inst {
+            Inst::Op(var, op, a, b) => {
+                let line = format!(
+                    "{indent}v{var} = ({op:?} v{a} v{b}) ; {} {}",
+                    value(*a),
+                    value(*b)
+                );
+                self.pending = Some((*var, line, paused.step));
+            }
+            Inst::UOp(var, op, a) => {
+                let line = format!("{indent}v{var} = ({op:?} v{a}) ; {}", value(*a));
+                self.pending = Some((*var, line, paused.step));
+            }
+            Inst::Const(var, val) => writeln!(self.out, "{indent}v{var} = {val:?}")?,
+            Inst::Call(var, callee, args) => writeln!(
+                self.out,
+                "{indent}v{var} = {}({}) ; {}",
+                self.funcs[*callee],
+                names(args),
+                values(args)
+            )?,
+            Inst::CallExtern(var, extern_id, args) => {
+                let line = format!(
+                    "{indent}v{var} = extern {}({}) ; {}",
+                    self.externs[*extern_id],
+                    names(args),
+                    values(args)
+                );
+                self.pending = Some((*var, line, paused.step));
+            }
+            Inst::JumpTo(block, args) => {
+                let (first_param, _) = func.ir.block_params[*block];
+                let params = args
+                    .iter()
+                    .enumerate()
+                    .map(|(i, arg)| format!("v{} = {}", first_param + i, value(*arg)))
+                    .collect::<Vec<String>>();
+                writeln!(self.out, "{indent}jump '{block}({})", params.join(", "))?;
+            }
+            Inst::Branch(cond, (a, b)) => writeln!(
+                self.out,
+                "{indent}if v{cond} then '{a} else '{b} ; {}",
+                value(*cond)
+            )?,
+            Inst::Return(var) => writeln!(self.out, "{indent}return v{var} ; {}", value(*var))?,
+        }
+        return Ok(());
+    }
+}
+
+impl<W: Write> Debugger for Tracer<W> {
+    fn step(&mut self, paused: &Paused) -> Result<(), Trap> {
+        // if the trace can't be written there's no point in running
+        return self.trace(paused).map_err(|_| Trap::Interrupted);
+    }
+
+    fn trapped(&mut self, paused: &Paused, _trap: &Trap) {
+        // the last inst never got its value if it's the one that trapped
+        if let Some((var, line, step)) = self.pending.take() {
+            let _ = if step == paused.step {
+                writeln!(self.out, "{line} -> trap")
+            } else {
+                writeln!(self.out, "{line} -> {:?}", paused.vars[var])
+            };
+        }
+    }
+}
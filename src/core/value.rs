@@ -1,13 +1,17 @@
-use std::{fmt::Display, mem::size_of};
-
-use crate::utils::Mem;
-
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+use std::{
+    fmt::Display,
+    hash::{Hash, Hasher},
+    mem::size_of,
+    rc::Rc,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum TypeDef {
     Unit,
     Bool,
     I32,
     F64,
+    Str,
 }
 
 impl TypeDef {
@@ -17,80 +21,112 @@ impl TypeDef {
             TypeDef::Bool => size_of::<bool>(),
             TypeDef::I32 => size_of::<i32>(),
             TypeDef::F64 => size_of::<f64>(),
+            // strings are as long as they are
+            TypeDef::Str => 0,
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Value {
-    def: TypeDef,
-    mem: Mem,
+/// A value atlas code works with, strings are shared so copying them is cheap.
+#[derive(Debug, Clone)]
+pub enum Value {
+    Unit,
+    Bool(bool),
+    I32(i32),
+    F64(f64),
+    Str(Rc<str>),
 }
 
-impl Value {
-    pub fn new(def: TypeDef, mem: Mem) -> Value {
-        return Value { def, mem };
+// floats are the same if their bits are, so consts can be keys
+impl PartialEq for Value {
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (Value::Unit, Value::Unit) => true,
+            (Value::Bool(a), Value::Bool(b)) => a == b,
+            (Value::I32(a), Value::I32(b)) => a == b,
+            (Value::F64(a), Value::F64(b)) => a.to_bits() == b.to_bits(),
+            (Value::Str(a), Value::Str(b)) => a == b,
+            _ => false,
+        }
     }
+}
 
-    pub fn get_size(&self) -> usize {
-        return self.get_type().size();
-    }
+impl Eq for Value {}
 
-    pub fn get_type(&self) -> TypeDef {
-        return self.def;
+impl Hash for Value {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        std::mem::discriminant(self).hash(state);
+        match self {
+            Value::Unit => {}
+            Value::Bool(value) => value.hash(state),
+            Value::I32(value) => value.hash(state),
+            Value::F64(value) => value.to_bits().hash(state),
+            Value::Str(value) => value.hash(state),
+        }
     }
+}
 
-    pub fn get_bytes(&self) -> &Mem {
-        return &self.mem;
+impl Value {
+    pub fn get_type(&self) -> TypeDef {
+        return match self {
+            Value::Unit => TypeDef::Unit,
+            Value::Bool(_) => TypeDef::Bool,
+            Value::I32(_) => TypeDef::I32,
+            Value::F64(_) => TypeDef::F64,
+            Value::Str(_) => TypeDef::Str,
+        };
     }
 }
 
 impl Value {
     pub fn as_i32(&self) -> i32 {
-        if self.def != TypeDef::I32 {
+        let Value::I32(value) = self else {
             panic!("No an int!");
-        }
-
-        i32::from_be_bytes(self.mem.get_slice(0))
+        };
+        return *value;
     }
 
     pub fn as_f64(&self) -> f64 {
-        if self.def != TypeDef::F64 {
+        let Value::F64(value) = self else {
             panic!("No a float!");
-        }
+        };
+        return *value;
+    }
 
-        f64::from_be_bytes(self.mem.get_slice(0))
+    pub fn as_str(&self) -> &str {
+        let Value::Str(value) = self else {
+            panic!("No a string!");
+        };
+        return value;
     }
 
     pub fn as_bool(&self) -> bool {
-        if self.def != TypeDef::Bool {
+        let Value::Bool(value) = self else {
             panic!("No a bool!");
-        }
-
-        return self.mem.bytes[0] == 1;
+        };
+        return *value;
     }
 }
 
 impl Value {
+    pub fn unit() -> Value {
+        return Value::Unit;
+    }
+
     pub fn i32(value: i32) -> Value {
-        Value {
-            def: TypeDef::I32,
-            mem: Mem::new(value.to_be_bytes().to_vec()),
-        }
+        return Value::I32(value);
     }
 
     pub fn f64(value: f64) -> Value {
-        Value {
-            def: TypeDef::F64,
-            mem: Mem::new(value.to_be_bytes().to_vec()),
-        }
+        return Value::F64(value);
+    }
+
+    pub fn str(value: &str) -> Value {
+        return Value::Str(value.into());
     }
 
     pub fn bool(value: bool) -> Value {
-        Value {
-            def: TypeDef::Bool,
-            mem: Mem::new(if value { vec![1] } else { vec![0] }),
-        }
+        return Value::Bool(value);
     }
 }
 
@@ -100,7 +136,66 @@ impl Display for TypeDef {
             TypeDef::I32 => write!(f, "I32"),
             TypeDef::F64 => write!(f, "F64"),
             TypeDef::Bool => write!(f, "Bool"),
-            _ => unimplemented!(),
+            TypeDef::Unit => write!(f, "Unit"),
+            TypeDef::Str => write!(f, "Str"),
+        }
+    }
+}
+
+/// Values are written the way atlas code would see them, like `42` or `true`.
+impl Display for Value {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Value::Unit => write!(f, "()"),
+            Value::Bool(value) => write!(f, "{value}"),
+            Value::I32(value) => write!(f, "{value}"),
+            Value::F64(value) => write!(f, "{value:?}"),
+            Value::Str(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+impl Value {
+    /// Read a value of type <typ> from <src>, the way `Display` writes it.
+    pub fn parse(typ: TypeDef, src: &str) -> Result<Value, String> {
+        let value = match typ {
+            TypeDef::Unit if src == "()" => Some(Value::Unit),
+            TypeDef::Unit => None,
+            TypeDef::Bool => src.parse().ok().map(Value::Bool),
+            TypeDef::I32 => src.parse().ok().map(Value::I32),
+            TypeDef::F64 => src.parse().ok().map(Value::F64),
+            TypeDef::Str => Some(Value::str(src)),
+        };
+        return value.ok_or_else(|| format!("{src:?} isn't a valid {typ}"));
+    }
+
+    /// Write the value as json, unit and floats that json can't have are `null`.
+    pub fn to_json(&self) -> String {
+        return match self {
+            Value::Unit => "null".to_string(),
+            Value::Bool(value) => value.to_string(),
+            Value::I32(value) => value.to_string(),
+            Value::F64(value) if value.is_finite() => format!("{value:?}"),
+            Value::F64(_) => "null".to_string(),
+            Value::Str(value) => json_string(value),
+        };
+    }
+}
+
+/// Quote <value> as a json string.
+pub fn json_string(value: &str) -> String {
+    let mut json = String::from("\"");
+    for chr in value.chars() {
+        match chr {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            chr if (chr as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", chr as u32)),
+            chr => json.push(chr),
         }
     }
+    json.push('"');
+    return json;
 }
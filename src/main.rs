@@ -1,4 +1,5 @@
 mod core;
+mod passes;
 mod server;
 mod targets;
 mod utils;
@@ -8,62 +9,470 @@ use crate::core::*;
 use std::io::Write;
 
 fn main() -> std::io::Result<()> {
-    let args: Vec<String> = std::env::args().collect();
+    let mut args: Vec<String> = std::env::args().collect();
 
-    match &args.iter().map(|s| s.as_str()).collect::<Vec<&str>>()[1..] {
+    // `--target <name>` is the same as `--target=<name>`, and so are `--engine` and `--entry`
+    while let Some(i) = args
+        .iter()
+        .position(|arg| arg == "--target" || arg == "--engine" || arg == "--entry")
+    {
+        let name = if i + 1 < args.len() {
+            args.remove(i + 1)
+        } else {
+            String::new()
+        };
+        args[i] = format!("{}={name}", args[i]);
+    }
+
+    // split the flags from the rest of the arguments, negative numbers are arguments
+    let (flags, args): (Vec<&str>, Vec<&str>) = args[1..]
+        .iter()
+        .map(|s| s.as_str())
+        .partition(|arg| arg.starts_with('-') && arg.parse::<f64>().is_err());
+
+    let (options, wasm_options, run_options) = parse_options(&flags);
+
+    match &args[..] {
         ["server"] => server::start(),
-        ["run", name] => {
+        ["run", name, args @ ..] => {
+            let Some(mut module) = load(name, &options)? else {
+                return Ok(());
+            };
+            register_printers(&mut module);
+            module.limits = run_options.limits;
+            module.engine = run_options.engine;
+
+            let json = run_options.json;
+            let entry = run_options.entry.as_deref().unwrap_or("main");
+            let args = match entry_args(&module, entry, args) {
+                Ok(args) => args,
+                Err(msg) => {
+                    print_error(&msg, &[], json);
+                    return Ok(());
+                }
+            };
+
+            // tracing and profiling need the ir engine to look at every instruction
+            if run_options.trace && run_options.profile {
+                println!("ERR --trace and --profile can't be used together");
+                return Ok(());
+            }
+            if run_options.profile && module.engine != interpreter::Engine::Ir {
+                println!("ERR --profile only works with --engine ir");
+                return Ok(());
+            }
+            if run_options.trace && module.engine != interpreter::Engine::Ir {
+                println!("ERR --trace only works with --engine ir");
+                return Ok(());
+            }
+            if run_options.trace {
+                let mut tracer = tracer::Tracer::new(&module, std::io::stdout());
+                print_result(module.debug(entry, args, &mut tracer), json);
+                return Ok(());
+            }
+            if run_options.compare {
+                // compare wasm with the engine that was picked, if it's an interpreter
+                if module.engine == interpreter::Engine::Wasm {
+                    module.engine = interpreter::Engine::Ir;
+                }
+                let interpreted = module.exec(entry, args.clone());
+                module.engine = interpreter::Engine::Wasm;
+                print_compared(interpreted, module.exec(entry, args), json);
+                return Ok(());
+            }
+            if !run_options.profile {
+                print_result(module.exec(entry, args), json);
+                return Ok(());
+            }
+
+            let mut profiler = profiler::Profiler::default();
+            print_result(module.debug(entry, args, &mut profiler), json);
+            profiler.report(&mut std::io::stdout())?;
+            if let Some(path) = &run_options.profile_stacks {
+                profiler.write_stacks(&mut std::fs::File::create(path)?)?;
+            }
+        }
+        ["repl"] => {
+            let mut repl = repl::Repl::new(move |module| {
+                let _ = module.run_passes(&options, &mut std::io::stdout());
+                register_printers(module);
+                module.limits = run_options.limits.clone();
+                module.engine = run_options.engine;
+            });
+            repl.run(std::io::stdin().lock(), &mut std::io::stdout())?;
+        }
+        ["debug", name, args @ ..] => {
+            // the passes move insts around and lose the lines they came from
+            if !options.passes.is_empty() {
+                println!("ERR optimized code can't be debugged");
+                return Ok(());
+            }
             let src = std::fs::read_to_string(name)?;
-            let module = module::Module::from_src(&src);
-            println!("{:?}", module.exec("main", vec![]));
+            let Some(mut module) = load(name, &options)? else {
+                return Ok(());
+            };
+            register_printers(&mut module);
+            module.limits = run_options.limits;
+
+            let json = run_options.json;
+            let entry = run_options.entry.as_deref().unwrap_or("main");
+            let args = match entry_args(&module, entry, args) {
+                Ok(args) => args,
+                Err(msg) => {
+                    print_error(&msg, &[], json);
+                    return Ok(());
+                }
+            };
+            let mut session =
+                debugger::Session::new(&src, std::io::stdin().lock(), std::io::stdout());
+            print_result(module.debug(entry, args, &mut session), json);
         }
         ["to-wasm", name, out] => {
-            let src = std::fs::read_to_string(name)?;
-            let module = module::Module::from_src(&src);
+            let Some(module) = load(name, &options)? else {
+                return Ok(());
+            };
+            let mut file = std::fs::File::create(out)?;
+            file.write(&module.to_wasm_with(&wasm_options))?;
+        }
+        ["to-wat", name, out] => {
+            let Some(module) = load(name, &options)? else {
+                return Ok(());
+            };
             let mut file = std::fs::File::create(out)?;
-            file.write(&module.to_wasm())?;
+            file.write_all(&module.to_wat_with(&wasm_options))?;
         }
         ["to-ir", name, out] => {
-            let src = std::fs::read_to_string(name)?;
-            let module = module::Module::from_src(&src);
+            let Some(module) = load(name, &options)? else {
+                return Ok(());
+            };
             let mut file = std::fs::File::create(out)?;
             module.log(&mut file)?;
         }
+        ["wasm-dump", name] => {
+            let wasm = std::fs::read(name)?;
+            let mut out = std::io::stdout();
+            if let Err(err) = targets::wasm_dump::dump_wasm(&wasm, &mut out) {
+                println!("ERR {err}");
+            }
+        }
         _ => println!("ERR unknown command"),
     };
 
     return Ok(());
 }
 
+/// How the `run` command runs a module.
+#[derive(Default)]
+struct RunOptions {
+    limits: interpreter::Limits,
+    engine: interpreter::Engine,
+    profile: bool,
+    trace: bool,
+    /// The function to run instead of `main`.
+    entry: Option<String>,
+    /// Write the result as json.
+    json: bool,
+    /// Run with an interpreter and with wasm, and say if the results differ.
+    compare: bool,
+    /// Where to write the collapsed stacks of the profile.
+    profile_stacks: Option<String>,
+}
+
+fn parse_options(flags: &[&str]) -> (passes::Options, targets::wasm::WasmOptions, RunOptions) {
+    let mut options = passes::Options::level(0);
+    let mut wasm_options = targets::wasm::WasmOptions::default();
+    let mut run_options = RunOptions::default();
+    let limits = &mut run_options.limits;
+
+    for flag in flags {
+        match flag.split_once('=') {
+            Some(("--inline-threshold", value)) => match value.parse() {
+                Ok(threshold) => options.inline_threshold = threshold,
+                Err(_) => println!("ERR invalid inline threshold {value}"),
+            },
+            Some(("--passes", names)) => {
+                options.passes = names.split(',').map(|s| s.to_string()).collect()
+            }
+            Some(("--print-after", names)) => {
+                options.print_after = names.split(',').map(|s| s.to_string()).collect()
+            }
+            Some(("--target", name)) => match name {
+                "wasm" => wasm_options.target = targets::wasm::Target::Wasm,
+                "wasi" => wasm_options.target = targets::wasm::Target::Wasi,
+                _ => println!("ERR unknown target {name}"),
+            },
+            Some(("--engine", name)) => match name {
+                "ir" => run_options.engine = interpreter::Engine::Ir,
+                "bytecode" => run_options.engine = interpreter::Engine::Bytecode,
+                "wasm" => run_options.engine = interpreter::Engine::Wasm,
+                _ => println!("ERR unknown engine {name}"),
+            },
+            Some(("--entry", name)) => run_options.entry = Some(name.to_string()),
+            Some(("--profile-stacks", path)) => {
+                run_options.profile = true;
+                run_options.profile_stacks = Some(path.to_string());
+            }
+            Some(("--fuel", value)) => match value.parse() {
+                Ok(fuel) => limits.fuel = Some(fuel),
+                Err(_) => println!("ERR invalid fuel {value}"),
+            },
+            Some(("--max-depth", value)) => match value.parse() {
+                Ok(depth) => limits.max_call_depth = depth,
+                Err(_) => println!("ERR invalid max depth {value}"),
+            },
+            Some(("--max-memory", value)) => match value.parse() {
+                Ok(bytes) => limits.max_memory = bytes,
+                Err(_) => println!("ERR invalid max memory {value}"),
+            },
+            _ => match *flag {
+                "-O" | "-O2" => options.passes = passes::Options::level(2).passes,
+                "-O1" => options.passes = passes::Options::level(1).passes,
+                "-O0" => options.passes = vec![],
+                "--verify" => options.verify = true,
+                "--profile" => run_options.profile = true,
+                "--trace" => run_options.trace = true,
+                "--json" => run_options.json = true,
+                "--compare" => run_options.compare = true,
+                "--tail-calls" => wasm_options.tail_calls = true,
+                "--stackify" => wasm_options.stackify = true,
+                "--folded" => wasm_options.folded = true,
+                _ => println!("ERR unknown flag {flag}"),
+            },
+        }
+    }
+
+    return (options, wasm_options, run_options);
+}
+
+/// Compile the file <name>, writing the mistakes in it if it doesn't compile.
+fn load(name: &str, options: &passes::Options) -> std::io::Result<Option<module::Module<'static>>> {
+    let src = std::fs::read_to_string(name)?;
+    let mut module = module::Module::from_src(&src);
+    if !module.errors.is_empty() {
+        for err in &module.errors {
+            println!("ERR {err}");
+        }
+        return Ok(None);
+    }
+    if let Some(stem) = std::path::Path::new(name).file_stem() {
+        module.name = stem.to_string_lossy().to_string();
+    }
+    if let Err(err) = module.run_passes(options, &mut std::io::stdout()) {
+        println!("ERR {err}");
+    }
+    return Ok(Some(module));
+}
+
+/// Read <args> as the params of the function <entry>.
+fn entry_args(
+    module: &module::Module,
+    entry: &str,
+    args: &[&str],
+) -> Result<Vec<value::Value>, String> {
+    let Some(func) = module.get(entry) else {
+        return Err(format!("unknown function {entry}"));
+    };
+    if args.len() != func.num_params {
+        return Err(format!(
+            "{entry} takes {} arguments but got {}",
+            func.num_params,
+            args.len()
+        ));
+    }
+
+    return args
+        .iter()
+        .enumerate()
+        .map(|(i, arg)| {
+            let name = func.ir.var_name[i].as_deref().unwrap_or("?");
+            value::Value::parse(func.ir.var_type[i], arg).map_err(|err| format!("{err} for {name}"))
+        })
+        .collect();
+}
+
+/// Print what running a function returned, as json if <json> is set.
+fn print_result(result: Result<value::Value, interpreter::RuntimeError>, json: bool) {
+    match result {
+        Ok(value) if json => println!("{{\"value\": {}}}", value.to_json()),
+        Ok(value) => println!("{value}"),
+        Err(err) => print_error(&err.trap.to_string(), &err.stack, json),
+    }
+}
+
+/// Print the result of the interpreter if wasm got the same one, or both of
+/// them if it didn't. Traps are the same if they are the same kind of trap,
+/// wherever they happened.
+fn print_compared(
+    interpreted: Result<value::Value, interpreter::RuntimeError>,
+    wasm: Result<value::Value, interpreter::RuntimeError>,
+    json: bool,
+) {
+    let is_same = match (&interpreted, &wasm) {
+        (Ok(a), Ok(b)) => a == b,
+        (Err(a), Err(b)) => a.trap == b.trap,
+        _ => false,
+    };
+    if is_same {
+        print_result(interpreted, json);
+        return;
+    }
+
+    let describe = |result: &Result<value::Value, interpreter::RuntimeError>| match result {
+        Ok(value) if json => format!("{{\"value\": {}}}", value.to_json()),
+        Ok(value) => value.to_string(),
+        Err(err) if json => format!(
+            "{{\"error\": {}}}",
+            value::json_string(&err.trap.to_string())
+        ),
+        Err(err) => format!("ERR {}", err.trap),
+    };
+    if json {
+        println!(
+            "{{\"error\": \"results differ\", \"interpreter\": {}, \"wasm\": {}}}",
+            describe(&interpreted),
+            describe(&wasm)
+        );
+    } else {
+        println!("ERR results differ");
+        println!("  interpreter: {}", describe(&interpreted));
+        println!("  wasm: {}", describe(&wasm));
+    }
+}
+
+/// Print an error, and the functions that were running when it happened.
+fn print_error(msg: &str, stack: &[String], json: bool) {
+    if json {
+        let stack: Vec<String> = stack.iter().map(|name| value::json_string(name)).collect();
+        println!(
+            "{{\"error\": {}, \"stack\": [{}]}}",
+            value::json_string(msg),
+            stack.join(", ")
+        );
+        return;
+    }
+
+    print!("ERR {msg}");
+    for name in stack {
+        print!("\n  at {name}");
+    }
+    println!();
+}
+
+/// Print the arguments of every call to an extern that returns nothing, so
+/// atlas code can log things when it is run by the interpreter. The print
+/// functions of the wasi target only print their argument, like they do there.
+fn register_printers(module: &mut module::Module) {
+    for extern_def in module.externs.clone() {
+        if extern_def.return_type != value::TypeDef::Unit {
+            continue;
+        }
+
+        let name = extern_def.name.clone();
+        let is_print = targets::wasi::PRINTS.contains(&name.as_str());
+        module.register(&extern_def.module, &extern_def.name, move |args| {
+            let args = args
+                .iter()
+                .map(|arg| match arg {
+                    value::Value::F64(value) if is_print => format_f64(*value),
+                    arg => arg.to_string(),
+                })
+                .collect::<Vec<String>>();
+            if is_print {
+                println!("{}", args.join(", "));
+            } else {
+                println!("{name}({})", args.join(", "));
+            }
+            return value::Value::unit();
+        });
+    }
+}
+
+/// Write <value> with up to 6 decimal places, like `print_f64` does in wasi.
+fn format_f64(value: f64) -> String {
+    // the digits of big numbers aren't exact, so wasi writes zeros instead
+    if value.is_finite() && value.abs() >= targets::wasi::MAX_EXACT_F64 {
+        let mut whole = value.abs();
+        let mut zeros = 0;
+        while whole >= targets::wasi::MAX_EXACT_F64 {
+            whole = (whole / 10.0).floor();
+            zeros += 1;
+        }
+        let sign = if value < 0.0 { "-" } else { "" };
+        return format!("{sign}{}{}.0", whole as u64, "0".repeat(zeros));
+    }
+
+    let value = format!("{value:.6}");
+    let value = value.trim_end_matches('0');
+    if value.ends_with('.') {
+        return format!("{value}0");
+    }
+    return value.to_string();
+}
+
 #[cfg(test)]
 #[rustfmt::skip]
 mod tests_ir {
+    use crate::bytecode::*;
+    use crate::debugger::*;
+    use crate::interpreter::*;
+    use crate::ir::*;
     use crate::module::Module;
+    use crate::passes::*;
+    use crate::profiler::*;
+    use crate::repl::*;
+    use crate::tracer::*;
+    use crate::targets::wasm::*;
+    use crate::targets::wasm_dump::*;
+    use crate::format_f64;
+    use crate::utils::*;
     use crate::value::*;
 
-    fn test_interpreter(module: &Module, value: Value) {
-        assert_eq!(module.exec("main", vec![]), value);
+    fn test_interpreter(module: &mut Module, value: Value) {
+        // every engine gets the same result
+        for engine in [Engine::Ir, Engine::Bytecode, Engine::Wasm] {
+            module.engine = engine;
+            assert_eq!(module.exec("main", vec![]), Ok(value.clone()));
+        }
     }
 
     fn test_wasm(module: &Module, value: Value) {
-        match value.get_type() {
-            TypeDef::F64 => assert_eq!(exec_wasm::<f64>(module), value.as_f64()),
-            TypeDef::I32 => assert_eq!(exec_wasm::<i32>(module), value.as_i32()),
-            TypeDef::Bool => assert_eq!(exec_wasm::<i32>(module), if value.as_bool() { 1 } else {0}),
-            _ => {}
+        // the result is the same whether values are kept on the stack or not
+        for stackify in [false, true] {
+            let options = &WasmOptions { stackify, ..Default::default() };
+            match value.get_type() {
+                TypeDef::F64 => assert_eq!(exec_wasm::<f64>(module, options), value.as_f64()),
+                TypeDef::I32 => assert_eq!(exec_wasm::<i32>(module, options), value.as_i32()),
+                TypeDef::Bool => assert_eq!(exec_wasm::<i32>(module, options), if value.as_bool() { 1 } else {0}),
+                _ => {}
+            }
         }
 
-        match value.get_type() {
-            TypeDef::F64 => assert_eq!(exec_wat::<f64>(module), value.as_f64()),
-            TypeDef::I32 => assert_eq!(exec_wat::<i32>(module), value.as_i32()),
-            TypeDef::Bool => assert_eq!(exec_wat::<i32>(module), if value.as_bool() { 1 } else {0}),
-            _ => {}
+        // and so does the wat, however it's written
+        for (folded, stackify) in [(false, false), (true, true)] {
+            let options = &WasmOptions { folded, stackify, ..Default::default() };
+            match value.get_type() {
+                TypeDef::F64 => assert_eq!(exec_wat::<f64>(module, options), value.as_f64()),
+                TypeDef::I32 => assert_eq!(exec_wat::<i32>(module, options), value.as_i32()),
+                TypeDef::Bool => assert_eq!(exec_wat::<i32>(module, options), if value.as_bool() { 1 } else {0}),
+                _ => {}
+            }
         }
     }
 
-    fn exec_wasm<T: wasmtime::WasmResults>(module: &Module) -> T {
-        let wasm = module.to_wasm();
+    fn exec_wasm<T: wasmtime::WasmResults>(module: &Module, options: &WasmOptions) -> T {
+        let wasm = module.to_wasm_with(options);
+        if let Err(err) = dump_wasm(&wasm, &mut std::io::sink()) {
+            panic!("invalid wasm: {err}");
+        }
+        return exec(wasm);
+    }
+
+    fn exec_wat<T: wasmtime::WasmResults>(module: &Module, options: &WasmOptions) -> T {
+        return exec(module.to_wat_with(options));
+    }
 
+    /// Run the main function of a wasm module, in binary or text form.
+    fn exec<T: wasmtime::WasmResults>(wasm: Vec<u8>) -> T {
         let engine = wasmtime::Engine::default();
         let module = wasmtime::Module::new(&engine, wasm).unwrap();
 
@@ -77,26 +486,38 @@ mod tests_ir {
         return main.call(&mut store, ()).unwrap();
     }
 
-    fn exec_wat<T: wasmtime::WasmResults>(module: &Module) -> T {
-        let wat = module.to_wasm();
+    fn test(src: &str, value: Value) {
+        let module = &mut Module::from_src(src);
+        test_interpreter(module, value.clone());
+        test_wasm(module, value.clone());
 
-        let engine = wasmtime::Engine::default();
-        let module = wasmtime::Module::new(&engine, wat).unwrap();
+        // optimizing should never change the result
+        optimize(module, Options { verify: true, ..Options::default() });
+        test_interpreter(module, value.clone());
+        test_wasm(module, value.clone());
+    }
 
-        let mut store = wasmtime::Store::new(&engine, 4);
-        let instance = wasmtime::Instance::new(&mut store, &module, &[]).unwrap();
-        let main = instance
-            .get_typed_func::<(), T, _>(&mut store, "main")
-            .unwrap();
+    fn optimize(module: &mut Module, options: Options) {
+        module.run_passes(&options, &mut std::io::sink()).unwrap();
+    }
 
-        // And finally we can call the wasm!
-        return main.call(&mut store, ()).unwrap();
+    fn log_len(module: &Module, name: &str) -> usize {
+        let mut log = vec![];
+        module.get(name).unwrap().log(&mut log).unwrap();
+        String::from_utf8(log).unwrap().lines().count()
     }
 
-    fn test(src: &str, value: Value) {
-        let module = &Module::from_src(src);
-        test_interpreter(module, value.clone());
-        test_wasm(module, value.clone());
+    fn count_loop_insts(module: &Module, name: &str) -> usize {
+        let func = module.get(name).unwrap();
+        get_loops(func)
+            .iter()
+            .flat_map(|(_, body)| body)
+            .map(|block| func.ir.block_insts(*block).len())
+            .sum()
+    }
+
+    fn count_insts(module: &Module, name: &str, pred: fn(&Inst) -> bool) -> usize {
+        module.get(name).unwrap().ir.insts.iter().filter(|inst| pred(inst)).count()
     }
 
     fn test_eval(src: &str, value: Value) {
@@ -348,4 +769,1606 @@ mod tests_ir {
         test_eval("40 + (2)", Value::i32(42));
         test_eval("(((40)) + (2))", Value::i32(42));
     }
+
+    #[test]
+    fn test_const_fold() {
+        let module = &mut Module::from_src("main(): I32 { return 20 * 2 + 2 }");
+        optimize(module, Options::default());
+        assert_eq!(count_insts(module, "main", |inst| matches!(inst, Inst::Op(..))), 0);
+        test_interpreter(module, Value::i32(42));
+        test_wasm(module, Value::i32(42));
+
+        // constants are propagated through block params
+        let module = &mut Module::from_src("
+            main(): I32 {
+                let x = 1
+                if (x == 1) {
+                    x = 2
+                } else {
+                    x = 2
+                }
+                return x * 21
+            }
+        ");
+        optimize(module, Options::default());
+        assert_eq!(count_insts(module, "main", |inst| matches!(inst, Inst::Op(..))), 0);
+        assert_eq!(count_insts(module, "main", |inst| matches!(inst, Inst::Branch(..))), 0);
+        test_interpreter(module, Value::i32(42));
+        test_wasm(module, Value::i32(42));
+
+        // things that would trap at runtime are left alone
+        let module = &mut Module::from_src("main(): I32 { return 1 / 0 }");
+        optimize(module, Options::default());
+        assert_eq!(count_insts(module, "main", |inst| matches!(inst, Inst::Op(..))), 1);
+
+        // params are not constants
+        test("
+            double(x: I32): I32 {
+                return x * 2
+            }
+
+            main(): I32 {
+                return double(21)
+            }
+        ", Value::i32(42));
+    }
+
+    #[test]
+    fn test_dead_code() {
+        let src = "
+            main(): I32 {
+                let x = 4
+                1 + 1
+                if x == 4 {
+                    return x
+                }
+                return 0
+            }
+        ";
+
+        let module = &mut Module::from_src(src);
+        let wasm_len = module.to_wasm().len();
+        optimize(module, Options::default());
+        assert_eq!(module.get("main").unwrap().ir.num_vars, 1);
+        assert_eq!(module.get("main").unwrap().ir.blocks.len(), 2);
+        assert!(module.to_wasm().len() < wasm_len);
+        test_interpreter(module, Value::i32(4));
+        test_wasm(module, Value::i32(4));
+
+        // block params that are only passed back into themselves are removed
+        let module = &mut Module::from_src("
+            count(n: I32): I32 {
+                let i = 0
+                let unused = 0
+                while i < n {
+                    unused = unused + 1
+                    i = i + 1
+                }
+                return i
+            }
+
+            main(): I32 {
+                return count(10)
+            }
+        ");
+        optimize(module, Options::default());
+        assert_eq!(module.get("count").unwrap().ir.block_params[1].1, 1);
+        test_interpreter(module, Value::i32(10));
+        test_wasm(module, Value::i32(10));
+
+        // calls might have side effects so they stay
+        let module = &mut Module::from_src("
+            one(): I32 {
+                return 1
+            }
+
+            main(): I32 {
+                one()
+                return 2
+            }
+        ");
+        optimize(module, Options { inline_threshold: 0, ..Options::default() });
+        assert_eq!(count_insts(module, "main", |inst| matches!(inst, Inst::Call(..))), 1);
+    }
+
+    #[test]
+    fn test_inline() {
+        let src = "
+            add(a: I32, b: I32): I32 {
+                return a + b
+            }
+
+            fib(num: I32): I32 {
+                return
+                    if (num == 1) 1
+                    else if (num == 0) 0
+                    else add(fib(num - 1), fib(num - 2))
+            }
+
+            main(): I32 {
+                return add(fib(7), 29)
+            }
+        ";
+
+        let module = &mut Module::from_src(src);
+        optimize(module, Options::default());
+
+        // fib calls itself so it can't be inlined, but add can
+        assert_eq!(count_insts(module, "main", |inst| matches!(inst, Inst::Call(..))), 1);
+        assert_eq!(count_insts(module, "fib", |inst| matches!(inst, Inst::Call(..))), 2);
+        test_interpreter(module, Value::i32(42));
+        test_wasm(module, Value::i32(42));
+
+        // nothing is bigger than the threshold
+        let module = &mut Module::from_src(src);
+        optimize(module, Options { inline_threshold: 0, ..Options::default() });
+        assert_eq!(count_insts(module, "main", |inst| matches!(inst, Inst::Call(..))), 2);
+
+        // inlined functions can contain control flow
+        test("
+            abs(x: I32): I32 {
+                if x < 0 {
+                    return -x
+                }
+                return x
+            }
+
+            main(): I32 {
+                return abs(-40) + abs(2)
+            }
+        ", Value::i32(42));
+    }
+
+    #[test]
+    fn test_gvn() {
+        let src = "
+            f(a: I32, b: I32): I32 {
+                return a * b + b * a
+            }
+
+            main(): I32 {
+                return f(3, 7)
+            }
+        ";
+
+        let module = &mut Module::from_src(src);
+        optimize(module, Options { passes: vec!["gvn".to_string()], ..Options::default() });
+        assert_eq!(count_insts(module, "f", |inst| matches!(inst, Inst::Op(_, Op::Mul, ..))), 1);
+
+        // and the add uses the one that's left for both sides
+        let insts = &module.get("f").unwrap().ir.insts;
+        let Some(Inst::Op(mul, ..)) = insts.iter().find(|inst| matches!(inst, Inst::Op(_, Op::Mul, ..))) else { panic!() };
+        let adds: Vec<&Inst> = insts.iter().filter(|inst| matches!(inst, Inst::Op(_, Op::Add, ..))).collect();
+        assert!(matches!(adds[..], [Inst::Op(_, Op::Add, a, b)] if a == mul && b == mul));
+
+        optimize(module, Options { inline_threshold: 0, ..Options::default() });
+        test_interpreter(module, Value::i32(42));
+        test_wasm(module, Value::i32(42));
+
+        // repeated comparisons in else if chains are only done once
+        let src = "
+            sign(x: I32): I32 {
+                return
+                    if (x < 0) 1
+                    else if (x == 0) 2
+                    else if (x < 0) 3
+                    else 4
+            }
+
+            main(): I32 {
+                return sign(-5) + sign(0) * 10 + sign(5) * 100
+            }
+        ";
+
+        let module = &mut Module::from_src(src);
+        optimize(module, Options { inline_threshold: 0, ..Options::default() });
+        assert_eq!(count_insts(module, "sign", |inst| matches!(inst, Inst::Op(_, Op::Lt, ..))), 1);
+        test_interpreter(module, Value::i32(421));
+        test_wasm(module, Value::i32(421));
+
+        // values from sibling branches can't be reused
+        test("
+            pick(c: Bool, x: I32): I32 {
+                let y = 0
+                if c {
+                    y = x * 2
+                } else {
+                    y = x * 3
+                }
+                return y + x * 2
+            }
+
+            main(): I32 {
+                return pick(true, 10) + pick(false, 1)
+            }
+        ", Value::i32(45));
+    }
+
+    #[test]
+    fn test_licm() {
+        let src = "
+            sum(n: I32, k: I32): I32 {
+                let i = 0
+                let total = 0
+                while i < n {
+                    total = total + k * 3
+                    i = i + 1
+                }
+                return total
+            }
+
+            main(): I32 {
+                return sum(7, 2)
+            }
+        ";
+
+        let module = &mut Module::from_src(src);
+        let before = count_loop_insts(module, "sum");
+        optimize(module, Options { inline_threshold: 0, ..Options::default() });
+
+        // 3, k * 3 and 1 are moved out of the loop
+        assert_eq!(count_loop_insts(module, "sum"), before - 3);
+        test_interpreter(module, Value::i32(42));
+        test_wasm(module, Value::i32(42));
+
+        // loops that never run still work
+        test("
+            main(): I32 {
+                let x = 42
+                let y = 0
+                while y > 0 {
+                    x = y / 0
+                }
+                return x
+            }
+        ", Value::i32(42));
+    }
+
+    #[test]
+    fn test_pass_manager() {
+        let src = "main(): I32 { return 20 * 2 + 2 }";
+
+        // passes run by name and the ir is logged after the ones asked for
+        let module = &mut Module::from_src(src);
+        let options = Options {
+            passes: vec!["constfold".to_string(), "dce".to_string()],
+            print_after: vec!["dce".to_string()],
+            verify: true,
+            ..Options::default()
+        };
+        let mut log = vec![];
+        module.run_passes(&options, &mut log).unwrap();
+        let log = String::from_utf8(log).unwrap();
+        assert!(log.starts_with("; after dce\nfunction main"));
+        assert!(!log.contains("; after constfold"));
+        assert_eq!(log_len(module, "main"), 4);
+
+        // -O0 does nothing
+        let module = &mut Module::from_src(src);
+        let before = log_len(module, "main");
+        optimize(module, Options::level(0));
+        assert_eq!(log_len(module, "main"), before);
+
+        let module = &mut Module::from_src(src);
+        let options = Options { passes: vec!["nope".to_string()], ..Options::default() };
+        assert_eq!(module.run_passes(&options, &mut vec![]), Err("unknown pass nope".to_string()));
+    }
+
+    #[test]
+    fn test_verify() {
+        let module = &mut Module::from_src("
+            main(): I32 {
+                let x = 1
+                if x == 1 {
+                    x = 2
+                }
+                return x
+            }
+        ");
+        assert_eq!(verify(&module.funcs()[0]), Ok(()));
+
+        // use a var from a block that doesn't dominate the use
+        let func = &mut module.funcs_mut()[0];
+        let last = func.ir.insts.len() - 1;
+        func.ir.insts[last] = Inst::Return(func.ir.num_vars - 2);
+        assert!(verify(func).unwrap_err().contains("might not be assigned"));
+    }
+
+    #[test]
+    fn test_tail_call() {
+        let src = "
+            count(n: I32, total: I32): I32 {
+                if n == 0 {
+                    return total
+                }
+                return count(n - 1, total + 1)
+            }
+
+            main(): I32 {
+                return count(100000, 0)
+            }
+        ";
+
+        // the call becomes a jump, so this doesn't run out of stack
+        let module = &mut Module::from_src(src);
+        optimize(module, Options { verify: true, ..Options::default() });
+        assert_eq!(count_insts(module, "count", |inst| matches!(inst, Inst::Call(..))), 0);
+        test_interpreter(module, Value::i32(100000));
+        test_wasm(module, Value::i32(100000));
+
+        // params can be passed to each other
+        test("
+            swap(n: I32, a: I32, b: I32): I32 {
+                if n == 0 {
+                    return a * 10 + b
+                }
+                return swap(n - 1, b, a)
+            }
+
+            main(): I32 {
+                return swap(3, 1, 2)
+            }
+        ", Value::i32(21));
+
+        // tail calls to other functions use return_call
+        let module = &mut Module::from_src("
+            even(n: I32): Bool {
+                if n == 0 {
+                    return true
+                }
+                return odd(n - 1)
+            }
+
+            odd(n: I32): Bool {
+                if n == 0 {
+                    return false
+                }
+                return even(n - 1)
+            }
+
+            main(): Bool {
+                return even(10)
+            }
+        ");
+        test_interpreter(module, Value::bool(true));
+
+        let wasm = module.to_wasm_with(&WasmOptions { tail_calls: true, ..Default::default() });
+        let features = wasmparser::WasmFeatures { tail_call: true, ..Default::default() };
+        assert!(wasmparser::Validator::new_with_features(features).validate_all(&wasm).is_ok());
+        assert!(wasmparser::Validator::new().validate_all(&wasm).is_err());
+        assert!(wasmparser::Validator::new().validate_all(&module.to_wasm()).is_ok());
+    }
+
+    /// Get the groups of locals each function declares, as (count, type).
+    fn get_locals(wasm: &[u8]) -> Vec<Vec<(u32, wasmparser::ValType)>> {
+        let mut funcs = vec![];
+        for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+            if let wasmparser::Payload::CodeSectionEntry(body) = payload.unwrap() {
+                let locals = body.get_locals_reader().unwrap();
+                funcs.push(locals.into_iter().map(|local| local.unwrap()).collect());
+            }
+        }
+        funcs
+    }
+
+    #[test]
+    fn test_locals() {
+        let src = "
+            main(): I32 {
+                let a = 1
+                let b = a + 2
+                let c = b * 3
+                let d = c - 4
+                let e = d + 5
+                return e
+            }
+        ";
+
+        // each var is dead once the next one is computed, so they can share
+        let module = &mut Module::from_src(src);
+        let locals = get_locals(&module.to_wasm());
+        assert_eq!(locals[0].len(), 1);
+        assert!(locals[0][0].0 < 5);
+        test_interpreter(module, Value::i32(10));
+        test_wasm(module, Value::i32(10));
+
+        // the locals are grouped by type, and the params are reused
+        let module = &mut Module::from_src("
+            mix(n: I32, x: F64): I32 {
+                let a = n + 1
+                let b = x * 2.0
+                let c = a * 2
+                let d = b + 1.0
+                if d > 3.0 {
+                    return c
+                }
+                return n
+            }
+
+            main(): I32 {
+                return mix(3, 1.5)
+            }
+        ");
+        let locals = get_locals(&module.to_wasm());
+        assert!(locals[0].iter().all(|(count, _)| *count > 0));
+        let types = locals[0].iter().map(|(_, typ)| *typ).collect::<Vec<_>>();
+        let mut deduped = types.clone();
+        deduped.dedup();
+        assert_eq!(types, deduped);
+        test_interpreter(module, Value::i32(8));
+        test_wasm(module, Value::i32(8));
+
+        // vars passed around a loop keep their own locals
+        test("
+            main(): I32 {
+                let a = 0
+                let b = 1
+                let i = 0
+                while i < 10 {
+                    let t = a + b
+                    a = b
+                    b = t
+                    i = i + 1
+                }
+                return a
+            }
+        ", Value::i32(55));
+    }
+
+    #[test]
+    fn test_stackify() {
+        let src = "
+            square(x: I32): I32 {
+                return x * x
+            }
+
+            main(): I32 {
+                let a = 3
+                let b = 4
+                let c = square(a) + square(b)
+                if c > 20 {
+                    return c - 1
+                }
+                return c + 1
+            }
+        ";
+
+        // values used once don't go through locals
+        let module = &mut Module::from_src(src);
+        let options = WasmOptions { stackify: true, ..Default::default() };
+        let stackified = module.to_wasm_with(&options);
+        assert!(stackified.len() < module.to_wasm().len());
+        assert!(wasmparser::Validator::new().validate_all(&stackified).is_ok());
+        assert_eq!(get_locals(&stackified)[0].len(), 0);
+        test_interpreter(module, Value::i32(24));
+        test_wasm(module, Value::i32(24));
+
+        // the order of calls doesn't change
+        test("
+            main(): I32 {
+                return sub(one(), two())
+            }
+
+            sub(a: I32, b: I32): I32 {
+                return a - b
+            }
+
+            one(): I32 {
+                return 1
+            }
+
+            two(): I32 {
+                return 2
+            }
+        ", Value::i32(-1));
+    }
+
+    #[test]
+    fn test_wat() {
+        let src = "
+            half(x: I32): I32 {
+                return x / 2
+            }
+
+            main(): F64 {
+                let a = 7.0
+                let b = a / 2.0
+                if b >= 3.5 {
+                    if b != 4.0 {
+                        return -b
+                    }
+                }
+                return b
+            }
+        ";
+
+        // the wat parses, and does the same thing as the binary
+        let module = &Module::from_src(src);
+        for (folded, stackify) in [(false, false), (false, true), (true, false), (true, true)] {
+            let options = &WasmOptions { folded, stackify, ..Default::default() };
+            let wat = String::from_utf8(module.to_wat_with(options)).unwrap();
+            assert!(wat.contains("(func $half (export \"half\")"));
+            assert!(wat.contains("local.get $l0"));
+            assert!(!wat.contains("get_local"));
+            assert_eq!(exec_wat::<f64>(module, options), exec_wasm::<f64>(module, options));
+        }
+        test_wasm(module, Value::f64(-3.5));
+
+        // instructions are nested in the ones using their results
+        let wat = String::from_utf8(module.to_wat_with(&WasmOptions { folded: true, ..Default::default() })).unwrap();
+        assert!(wat.contains("(local.set $l0 (i32.div_s (local.get $l0) (local.get $l1)))"));
+    }
+
+    /// Get the module name, function names and local names in the name section.
+    fn get_names(wasm: &[u8]) -> (String, Vec<String>, Vec<(u32, u32, String)>) {
+        let (mut module, mut funcs, mut locals) = (String::new(), vec![], vec![]);
+        for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+            let wasmparser::Payload::CustomSection(section) = payload.unwrap() else {
+                continue;
+            };
+            assert_eq!(section.name(), "name");
+
+            let mut reader = wasmparser::NameSectionReader::new(section.data(), section.data_offset()).unwrap();
+            while !reader.eof() {
+                match reader.read().unwrap() {
+                    wasmparser::Name::Module(name) => module = name.get_name().unwrap().to_string(),
+                    wasmparser::Name::Function(map) => {
+                        let mut map = map.get_map().unwrap();
+                        for _ in 0..map.get_count() {
+                            funcs.push(map.read().unwrap().name.to_string());
+                        }
+                    }
+                    wasmparser::Name::Local(map) => {
+                        let mut map = map.get_indirect_map().unwrap();
+                        for _ in 0..map.get_indirect_count() {
+                            let func = map.read().unwrap();
+                            let mut names = func.get_map().unwrap();
+                            for _ in 0..names.get_count() {
+                                let naming = names.read().unwrap();
+                                locals.push((func.indirect_index, naming.index, naming.name.to_string()));
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        (module, funcs, locals)
+    }
+
+    #[test]
+    fn test_name_section() {
+        let src = "
+            divide(a: I32, b: I32): I32 {
+                let quotient = a / b
+                return quotient
+            }
+
+            main(): I32 {
+                let zero = 0
+                return divide(1, zero)
+            }
+        ";
+
+        let module = &mut Module::from_src(src);
+        module.name = String::from("numbers");
+        let wasm = module.to_wasm();
+        assert!(wasmparser::Validator::new().validate_all(&wasm).is_ok());
+
+        let (name, funcs, locals) = get_names(&wasm);
+        assert_eq!(name, "numbers");
+        assert_eq!(funcs, vec!["divide", "main"]);
+
+        // a local that holds more than one var gets all their names
+        let has_name = |locals: &[(u32, u32, String)], func: u32, local: Option<u32>, name: &str| {
+            locals.iter().any(|(f, l, names)| {
+                *f == func && !matches!(local, Some(local) if local != *l) && names.split('/').any(|n| n == name)
+            })
+        };
+        assert!(has_name(&locals, 0, Some(0), "a"));
+        assert!(has_name(&locals, 0, Some(1), "b"));
+        assert!(has_name(&locals, 0, None, "quotient"));
+        assert!(has_name(&locals, 1, None, "zero"));
+
+        // stack traces use the names
+        let engine = wasmtime::Engine::default();
+        let wasm_module = wasmtime::Module::new(&engine, &wasm).unwrap();
+        let mut store = wasmtime::Store::new(&engine, ());
+        let instance = wasmtime::Instance::new(&mut store, &wasm_module, &[]).unwrap();
+        let main = instance.get_typed_func::<(), i32, _>(&mut store, "main").unwrap();
+        let trace = format!("{:?}", main.call(&mut store, ()).unwrap_err());
+        assert!(trace.contains("numbers!divide"), "{trace}");
+        assert!(trace.contains("numbers!main"), "{trace}");
+
+        // names survive optimizing
+        optimize(module, Options { passes: vec![String::from("dce")], verify: true, ..Options::default() });
+        let (_, _, locals) = get_names(&module.to_wasm());
+        assert!(has_name(&locals, 0, None, "quotient"));
+    }
+
+    /// Run the `_start` function of a wasi module, and get its exit code and what it printed.
+    fn exec_wasi(wasm: Vec<u8>) -> (i32, String) {
+        let engine = wasmtime::Engine::default();
+        let module = wasmtime::Module::new(&engine, wasm).unwrap();
+
+        let stdout = wasi_common::pipe::WritePipe::new_in_memory();
+        let wasi = wasmtime_wasi::WasiCtxBuilder::new().stdout(Box::new(stdout.clone())).build();
+        let mut store = wasmtime::Store::new(&engine, wasi);
+        let mut linker = wasmtime::Linker::new(&engine);
+        wasmtime_wasi::add_to_linker(&mut linker, |wasi| wasi).unwrap();
+
+        let instance = linker.instantiate(&mut store, &module).unwrap();
+        let start = instance.get_typed_func::<(), (), _>(&mut store, "_start").unwrap();
+        let exit = start.call(&mut store, ()).unwrap_err();
+        let code = exit.downcast_ref::<wasmtime_wasi::I32Exit>().unwrap().0;
+
+        drop(store);
+        let out = stdout.try_into_inner().unwrap().into_inner();
+        return (code, String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn test_wasi() {
+        let src = "
+            extern print(s: Str): Unit
+            extern print_i32(x: I32): Unit
+            extern print_f64(x: F64): Unit
+
+            main(): I32 {
+                print(\"hello \\\"world\\\"\")
+                let i = -2
+                while i < 2 {
+                    print_i32(i * 1000)
+                    i = i + 1
+                }
+                print_i32(2147483647)
+                print_f64(2.5)
+                print_f64(-0.125)
+                print_f64(3.0)
+                print_f64(1.9999999)
+                print_f64(10000000000.0)
+                print_f64(4294967296.75)
+                print_f64(100000000000000000000.0)
+                print_f64(-1152921504606846976.0)
+                print_f64(999999999.9999999)
+                print_f64(-0.0)
+                print_f64(0.0 / 0.0)
+                print_f64(1.0 / 0.0)
+                print_f64(-1.0 / 0.0)
+                return 3
+            }
+        ";
+        let floats = [2.5, -0.125, 3.0, 1.9999999, 1e10, 4294967296.75, 1e20, -1152921504606846976.0, 999999999.9999999, -0.0, f64::NAN, f64::INFINITY, -f64::INFINITY];
+        let floats: String = floats.iter().map(|x| format_f64(*x) + "\n").collect();
+        let expected = "hello \"world\"\n-2000\n-1000\n0\n1000\n2147483647\n".to_string() + &floats;
+
+        let module = &mut Module::from_src(src);
+        for options in [
+            WasmOptions { target: Target::Wasi, ..WasmOptions::default() },
+            WasmOptions { target: Target::Wasi, stackify: true, ..WasmOptions::default() },
+        ] {
+            let wasm = module.to_wasm_with(&options);
+            assert!(wasmparser::Validator::new().validate_all(&wasm).is_ok());
+            assert!(dump_wasm(&wasm, &mut std::io::sink()).is_ok());
+            assert_eq!(exec_wasi(wasm), (3, expected.to_string()));
+
+            assert_eq!(exec_wasi(module.to_wat_with(&options)), (3, expected.to_string()));
+            let folded = WasmOptions { folded: true, ..options };
+            assert_eq!(exec_wasi(module.to_wat_with(&folded)), (3, expected.to_string()));
+        }
+
+        // the print functions print the same thing in the interpreter
+        assert_eq!(format_f64(1.9999999), "2.0");
+        assert_eq!(format_f64(-0.125), "-0.125");
+        assert_eq!(format_f64(1e10), "10000000000.0");
+        assert_eq!(format_f64(1e20), "100000000000000000000.0");
+        assert_eq!(format_f64(f64::NAN), "NaN");
+        assert_eq!(format_f64(-f64::INFINITY), "-inf");
+    }
+
+    #[test]
+    fn test_extern() {
+        let src = "
+            extern \"env\" log(x: I32): Unit
+            extern twice(x: I32): I32
+
+            count(n: I32): I32 {
+                let i = 0
+                while i < n {
+                    log(i)
+                    i = i + 1
+                }
+                return i
+            }
+
+            main(): I32 {
+                return twice(count(3))
+            }
+        ";
+
+        let module = &mut Module::from_src(src);
+
+        // the interpreter calls the closures registered for the externs
+        let logged = std::rc::Rc::new(std::cell::RefCell::new(vec![]));
+        let log = logged.clone();
+        module.register("env", "log", move |args| {
+            log.borrow_mut().push(args[0].as_i32());
+            return Value::unit();
+        });
+        module.register("env", "twice", |args| Value::i32(args[0].as_i32() * 2));
+        for engine in [Engine::Ir, Engine::Bytecode, Engine::Wasm] {
+            module.engine = engine;
+            logged.borrow_mut().clear();
+            assert_eq!(module.exec("main", vec![]), Ok(Value::i32(6)));
+            assert_eq!(*logged.borrow(), vec![0, 1, 2]);
+        }
+
+        // in wasm they are imports, so the module's own functions come after them
+        for wasm in [
+            module.to_wasm(),
+            module.to_wasm_with(&WasmOptions { stackify: true, ..WasmOptions::default() }),
+            module.to_wat_with(&WasmOptions { stackify: true, folded: true, ..WasmOptions::default() }),
+        ] {
+            let engine = wasmtime::Engine::default();
+            let wasm_module = wasmtime::Module::new(&engine, &wasm).unwrap();
+            let mut store = wasmtime::Store::new(&engine, vec![]);
+            let mut linker = wasmtime::Linker::new(&engine);
+            linker.func_wrap("env", "log", |mut caller: wasmtime::Caller<'_, Vec<i32>>, x: i32| caller.data_mut().push(x)).unwrap();
+            linker.func_wrap("env", "twice", |x: i32| x * 2).unwrap();
+            let instance = linker.instantiate(&mut store, &wasm_module).unwrap();
+            let main = instance.get_typed_func::<(), i32, _>(&mut store, "main").unwrap();
+            assert_eq!(main.call(&mut store, ()).unwrap(), 6);
+            assert_eq!(*store.data(), vec![0, 1, 2]);
+        }
+
+        let (_, funcs, _) = get_names(&module.to_wasm());
+        assert_eq!(funcs, vec!["log", "twice", "count", "main"]);
+
+        // optimizing never removes or reorders calls to the host
+        optimize(module, Options { verify: true, ..Options::default() });
+        logged.borrow_mut().clear();
+        assert_eq!(module.exec("main", vec![]), Ok(Value::i32(6)));
+        assert_eq!(*logged.borrow(), vec![0, 1, 2]);
+
+        // functions that return unit return nothing in wasm
+        let module = &mut Module::from_src("
+            extern log(x: I32): Unit
+
+            show(x: I32): Unit {
+                return log(x)
+            }
+
+            main(): I32 {
+                show(7)
+                return 1
+            }
+        ");
+        module.register("env", "log", |_| Value::unit());
+        assert_eq!(module.exec("main", vec![]), Ok(Value::i32(1)));
+        for wasm in [module.to_wasm(), module.to_wat(), module.to_wasm_with(&WasmOptions { stackify: true, ..WasmOptions::default() })] {
+            let engine = wasmtime::Engine::default();
+            let wasm_module = wasmtime::Module::new(&engine, &wasm).unwrap();
+            let mut store = wasmtime::Store::new(&engine, vec![]);
+            let mut linker = wasmtime::Linker::new(&engine);
+            linker.func_wrap("env", "log", |mut caller: wasmtime::Caller<'_, Vec<i32>>, x: i32| caller.data_mut().push(x)).unwrap();
+            let instance = linker.instantiate(&mut store, &wasm_module).unwrap();
+            let main = instance.get_typed_func::<(), i32, _>(&mut store, "main").unwrap();
+            assert_eq!(main.call(&mut store, ()).unwrap(), 1);
+            assert_eq!(*store.data(), vec![7]);
+        }
+
+        // calling something that isn't declared is a mistake in the source, not a crash
+        let module = Module::from_src("main(): I32 { return missing(1) }");
+        assert_eq!(module.errors, vec!["unknown function missing in main"]);
+
+        // and so is a param that can't hold anything
+        let module = Module::from_src("f(x: Unit): I32 { return 1 }");
+        assert_eq!(module.errors, vec!["the param x can't be Unit in f"]);
+    }
+
+    /// A tiny random number generator, so the fuzz tests are repeatable.
+    struct Rng(u64);
+
+    impl Rng {
+        fn below(&mut self, n: u64) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0 % n
+        }
+    }
+
+    /// Make random statements with ifs, loops and early returns, that always finish.
+    fn gen_stmts(rng: &mut Rng, depth: usize, loops: &mut usize) -> String {
+        let vars = ["a", "b", "c"];
+        let mut src = String::new();
+
+        for _ in 0..rng.below(3) + 1 {
+            let var = vars[rng.below(3) as usize];
+            let other = vars[rng.below(3) as usize];
+            let k = rng.below(5) + 1;
+
+            match if depth == 0 { 0 } else { rng.below(4) } {
+                0 => src += &format!("{var} = {other} + {k}\n"),
+                1 => {
+                    let a = gen_stmts(rng, depth - 1, loops);
+                    let b = gen_stmts(rng, depth - 1, loops);
+                    src += &format!("if {var} < {} {{\n{a}}} else {{\n{b}}}\n", rng.below(20));
+                }
+                2 => {
+                    let a = gen_stmts(rng, depth - 1, loops);
+                    src += &format!("if {var} > {} {{\n{a}}}\n", rng.below(20));
+                }
+                _ => {
+                    *loops += 1;
+                    let i = format!("i{loops}");
+                    let body = gen_stmts(rng, depth - 1, loops);
+                    src += &format!("let {i} = 0\nwhile {i} < {k} {{\n{i} = {i} + 1\n{body}}}\n");
+                }
+            }
+        }
+
+        // sometimes leave early
+        if rng.below(4) == 0 {
+            src += &format!("return {}\n", vars[rng.below(3) as usize]);
+        }
+
+        src
+    }
+
+    #[test]
+    fn test_fuzz_control_flow() {
+        for seed in 1..100u64 {
+            let rng = &mut Rng(seed.wrapping_mul(0x9E3779B97F4A7C15));
+            let stmts = gen_stmts(rng, 3, &mut 0);
+            let src = format!("main(): I32 {{\nlet a = 1\nlet b = 2\nlet c = 3\n{stmts}return a + b + c\n}}");
+
+            // the wasm has to do the same thing as the interpreter
+            let value = Module::from_src(&src).exec("main", vec![]).expect(&src);
+            let result = std::panic::catch_unwind(|| test(&src, value));
+            assert!(result.is_ok(), "{src}");
+        }
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let wat = |src: &str| String::from_utf8(Module::from_src(src).to_wat()).unwrap();
+
+        // wasm can see nothing runs after a return
+        assert!(!wat("main(): I32 { return 1 }").contains("unreachable"));
+
+        // but not that a loop never ends without returning
+        let src = "main(): I32 {\nlet a = 0\nwhile a < 3 { a = a + 1 }\nreturn a\n}";
+        assert!(wat(src).contains("unreachable"));
+        test(src, Value::i32(3));
+    }
+
+    #[test]
+    fn test_leb128() {
+        for value in [0, 1, 63, 64, 127, 128, 624485, i32::MAX, -1, -64, -65, -123456, i32::MIN] {
+            let mut bytes = vec![];
+            value.write_leb128(&mut bytes);
+            let mut r = Reader::new(&bytes);
+            assert_eq!(r.read_i32(), Ok(value));
+            assert!(r.is_done());
+        }
+
+        for value in [0usize, 1, 127, 128, 16384, u32::MAX as usize] {
+            let mut bytes = vec![];
+            value.write_leb128(&mut bytes);
+            let mut r = Reader::new(&bytes);
+            assert_eq!(r.read_usize(), Ok(value));
+            assert!(r.is_done());
+        }
+
+        // running out of bytes is an error, not a panic
+        assert!(Reader::new(&[0x80, 0x80]).read_usize().is_err());
+    }
+
+    #[test]
+    fn test_wasm_dump() {
+        let src = "
+            extern print_i32(x: I32): Unit
+
+            main(): I32 {
+                let a = 1
+                print_i32(a)
+                return a + 2
+            }
+        ";
+        let module = &Module::from_src(src);
+        let wasm = module.to_wasm();
+
+        let mut out = vec![];
+        assert_eq!(dump_wasm(&wasm, &mut out), Ok(()));
+        let out = String::from_utf8(out).unwrap();
+        assert!(out.contains("section 1 type"));
+        assert!(out.contains("env.print_i32"));
+        assert!(out.contains("i32.add"));
+
+        // the wrong type of add
+        let add = wasm.iter().rposition(|byte| *byte == 0x6A).unwrap();
+        let mut bad = wasm.clone();
+        bad[add] = 0xA0;
+        assert!(dump_wasm(&bad, &mut std::io::sink()).unwrap_err().contains("expected f64"));
+
+        // a section that says it's bigger than it is
+        let mut bad = wasm.clone();
+        bad[9] += 1;
+        assert!(dump_wasm(&bad, &mut std::io::sink()).is_err());
+
+        // the sections in the wrong order
+        let mut bad = wasm.clone();
+        bad.extend_from_slice(&wasm[8..10 + wasm[9] as usize]);
+        assert!(dump_wasm(&bad, &mut std::io::sink()).unwrap_err().contains("out of order"));
+
+        // a module with one function returning an i32, with the code <body>
+        let module = |body: &[u8]| {
+            let mut wasm = vec![0x00, 0x61, 0x73, 0x6D, 0x01, 0x00, 0x00, 0x00];
+            wasm.extend_from_slice(&[0x01, 0x05, 0x01, 0x60, 0x00, 0x01, 0x7F]);
+            wasm.extend_from_slice(&[0x03, 0x02, 0x01, 0x00]);
+            wasm.extend_from_slice(&[0x0A, body.len() as u8 + 2, 0x01, body.len() as u8]);
+            wasm.extend_from_slice(body);
+            return wasm;
+        };
+        let dump = |body: &[u8]| dump_wasm(&module(body), &mut std::io::sink());
+
+        // if with an else
+        assert_eq!(dump(&[0x00, 0x41, 0x01, 0x04, 0x7F, 0x41, 0x02, 0x05, 0x41, 0x03, 0x0B, 0x0B]), Ok(()));
+
+        // else in a block
+        assert!(dump(&[0x00, 0x02, 0x40, 0x05, 0x0B, 0x41, 0x01, 0x0B]).unwrap_err().contains("else without an if"));
+
+        // else twice
+        assert!(dump(&[0x00, 0x41, 0x01, 0x04, 0x7F, 0x41, 0x02, 0x05, 0x41, 0x03, 0x05, 0x41, 0x04, 0x0B, 0x0B]).unwrap_err().contains("else without an if"));
+
+        // if with a result but nothing when the condition is false
+        assert!(dump(&[0x00, 0x41, 0x01, 0x04, 0x7F, 0x41, 0x02, 0x0B, 0x0B]).unwrap_err().contains("needs an else"));
+
+        // more locals than a function can have
+        assert!(dump(&[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0x0F, 0x7F, 0x41, 0x00, 0x0B]).unwrap_err().contains("locals"));
+    }
+
+    #[test]
+    fn test_runtime_errors() {
+        let src = "
+            divide(a: I32, b: I32): I32 {
+                return a / b
+            }
+
+            count(n: I32): I32 {
+                return 1 + count(n + 1)
+            }
+
+            wrap(): I32 {
+                return 2147483647 + 1
+            }
+
+            zero(): I32 {
+                return divide(1, 0) + 1
+            }
+
+            overflow(): I32 {
+                return divide(-2147483647 - 1, -1) + 1
+            }
+
+            deep(): I32 {
+                return count(0) - 1
+            }
+
+            tail(): I32 {
+                return divide(1, 0)
+            }
+        ";
+        let module = &mut Module::from_src(src);
+
+        // every engine traps the same way
+        for engine in [Engine::Ir, Engine::Bytecode] {
+            module.engine = engine;
+
+            // i32s wrap around like they do in wasm
+            assert_eq!(module.exec("wrap", vec![]), Ok(Value::i32(i32::MIN)));
+
+            let err = module.exec("zero", vec![]).unwrap_err();
+            assert_eq!(err.trap, Trap::DivideByZero);
+            assert_eq!(err.stack, vec!["divide", "zero"]);
+            assert_eq!(err.to_string(), "integer divide by zero\n  at divide\n  at zero");
+
+            // a caller that returns what the call returns is still on the stack
+            let err = module.exec("tail", vec![]).unwrap_err();
+            assert_eq!(err.stack, vec!["divide", "tail"]);
+
+            let err = module.exec("overflow", vec![]).unwrap_err();
+            assert_eq!(err.trap, Trap::IntegerOverflow);
+
+            let err = module.exec("deep", vec![]).unwrap_err();
+            assert_eq!(err.trap, Trap::StackOverflow);
+            assert_eq!(err.stack.len(), 10_000);
+            assert_eq!(err.stack.last().unwrap(), "deep");
+        }
+
+        // wasm traps the same way
+        for name in ["zero", "overflow", "deep"] {
+            let trap = module.exec_wasm(name, vec![]).unwrap_err().trap;
+            assert_eq!(trap, module.exec(name, vec![]).unwrap_err().trap);
+        }
+
+        // mistakes only the interpreter can make
+        let err = module.exec("nope", vec![]).unwrap_err();
+        assert_eq!(err.trap, Trap::UnknownFunction("nope".to_string()));
+        let err = module.exec("divide", vec![Value::f64(1.0), Value::i32(1)]).unwrap_err();
+        assert!(matches!(err.trap, Trap::TypeMismatch(_)));
+
+        // a call can pass a value the ops of the callee weren't compiled for
+        let module = &mut Module::from_src("f(x: I32): I32 { return x + 1 }  main(): I32 { return f(1.5) }");
+        for engine in [Engine::Ir, Engine::Bytecode] {
+            module.engine = engine;
+            let err = module.exec("main", vec![]).unwrap_err();
+            assert_eq!(err.trap, Trap::TypeMismatch("can't Add F64 and I32".to_string()));
+            assert_eq!(err.stack, vec!["f", "main"]);
+        }
+
+        let module = &mut Module::from_src("extern log(x: I32): Unit  main(): I32 { log(1) return 0 }");
+        for engine in [Engine::Ir, Engine::Bytecode, Engine::Wasm] {
+            module.engine = engine;
+            let err = module.exec("main", vec![]).unwrap_err();
+            assert_eq!(err.trap, Trap::MissingHost("env.log".to_string()));
+            assert_eq!(err.stack, vec!["main"]);
+        }
+    }
+
+    #[test]
+    fn test_limits() {
+        let src = "
+            forever(): I32 {
+                let i = 0
+                while true {
+                    i = i + 1
+                }
+                return i
+            }
+
+            sum(): I32 {
+                let total = 0
+                let i = 0
+                while i < 100 {
+                    total = total + i
+                    i = i + 1
+                }
+                return total
+            }
+
+            count(n: I32): I32 {
+                if n == 0 {
+                    return 0
+                }
+                return 1 + count(n - 1)
+            }
+
+            deep(): I32 {
+                return count(50)
+            }
+        ";
+        let module = &mut Module::from_src(src);
+        for engine in [Engine::Ir, Engine::Bytecode] {
+            module.engine = engine;
+
+            module.limits = Limits { fuel: Some(10_000), ..Limits::default() };
+
+            // both backends stop a loop that never ends
+            let err = module.exec("forever", vec![]).unwrap_err();
+            assert_eq!(err.trap, Trap::OutOfFuel);
+            assert_eq!(err.stack, vec!["forever"]);
+            assert_eq!(module.exec_wasm("forever", vec![]).unwrap_err().trap, Trap::OutOfFuel);
+
+            // and both finish a loop with enough fuel for it
+            assert_eq!(module.exec("sum", vec![]), Ok(Value::i32(4950)));
+            assert_eq!(module.exec_wasm("sum", vec![]), Ok(Value::i32(4950)));
+
+            // and both run out before the loop is done with too little
+            module.limits = Limits { fuel: Some(100), ..Limits::default() };
+            assert_eq!(module.exec("sum", vec![]).unwrap_err().trap, Trap::OutOfFuel);
+            assert_eq!(module.exec_wasm("sum", vec![]).unwrap_err().trap, Trap::OutOfFuel);
+
+            module.limits = Limits { max_call_depth: 10, ..Limits::default() };
+            let err = module.exec("deep", vec![]).unwrap_err();
+            assert_eq!(err.trap, Trap::StackOverflow);
+            assert_eq!(err.stack.len(), 10);
+
+            module.limits = Limits { max_memory: 100, ..Limits::default() };
+            assert_eq!(module.exec("deep", vec![]).unwrap_err().trap, Trap::OutOfMemory);
+            module.limits = Limits::default();
+            assert_eq!(module.exec("deep", vec![]), Ok(Value::i32(50)));
+        }
+    }
+
+    #[test]
+    fn test_big_frame() {
+        // more vars than fit in 1024 bytes
+        let lets: String = (0..200).map(|i| format!("let x{i} = {i}.0\n")).collect();
+        let sum: Vec<String> = (0..200).map(|i| format!("x{i}")).collect();
+        let src = format!("main(): F64 {{\n{lets}return {}\n}}", sum.join(" + "));
+        test_interpreter(&mut Module::from_src(&src), Value::f64(19900.0));
+    }
+
+    /// Time the interpreter, with `cargo test --release bench_fib -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_fib() {
+        let module = &mut Module::from_src("
+            fib(n: I32): I32 {
+                if n < 2 {
+                    return n
+                }
+                return fib(n - 1) + fib(n - 2)
+            }
+
+            main(): I32 {
+                return fib(25)
+            }
+        ");
+
+        for engine in [Engine::Ir, Engine::Bytecode, Engine::Wasm] {
+            module.engine = engine;
+            let start = std::time::Instant::now();
+            assert_eq!(module.exec("main", vec![]), Ok(Value::i32(75025)));
+            println!("fib(25) took {:?} with {engine:?}", start.elapsed());
+        }
+    }
+
+    #[test]
+    fn test_bytecode() {
+        // every instruction is the same size, and small
+        assert!(std::mem::size_of::<Code>() <= 16);
+
+        let module = &Module::from_src("
+            main(): I32 {
+                let a = 1
+                let b = 2.0
+                while a < 10 {
+                    a = a + 1
+                    b = b * 2.0
+                }
+                return a
+            }
+        ");
+        let chunk = Chunk::new(0, &module.funcs()[0]);
+
+        // the ops are picked by type, and jumps go straight to an instruction
+        assert!(chunk.code.iter().any(|code| matches!(code, Code::AddI32(..))));
+        assert!(chunk.code.iter().any(|code| matches!(code, Code::MulF64(..))));
+        assert!(!chunk.code.iter().any(|code| matches!(code, Code::Op(..))));
+        for code in &chunk.code {
+            if let Code::Jump(target) | Code::Branch(_, target, _) = code {
+                assert!((*target as usize) < chunk.code.len());
+            }
+        }
+
+        // a call has to pass as many args as the callee takes
+        let module = Module::from_src("f(a: I32, b: I32): I32 { return a + b }  main(): I32 { return f(1) }");
+        assert_eq!(module.errors, vec!["f takes 2 args but got 1 in main"]);
+    }
+
+    #[test]
+    fn test_debugger() {
+        let src = "
+            // counts down
+            count(n: I32): I32 {
+                let total = 0
+                while n > 0 {
+                    total = total + n
+                    n = n - 1
+                }
+                return total
+            }
+
+            main(): I32 {
+                let a = 3
+                let b = count(a)
+                return a + b
+            }
+        ";
+        let module = &Module::from_src(src);
+
+        // the statements know their lines
+        let count = module.get("count").unwrap();
+        assert_eq!(count.ir.line(0), Some(4));
+        assert!(count.ir.lines.iter().any(|(_, line)| *line == 7));
+
+        // the hook sees every inst, in every function
+        struct Lines(Vec<(String, usize)>);
+        impl Debugger for Lines {
+            fn step(&mut self, paused: &Paused) -> Result<(), Trap> {
+                if paused.func.ir.is_line_start(paused.step) {
+                    self.0.push((paused.func.name.clone(), paused.line().unwrap()));
+                }
+                return Ok(());
+            }
+        }
+        let lines = &mut Lines(vec![]);
+        assert_eq!(module.debug("main", vec![], lines), Ok(Value::i32(9)));
+        assert_eq!(lines.0.first(), Some(&("main".to_string(), 13)));
+        assert_eq!(lines.0.iter().filter(|(_, line)| *line == 7).count(), 3);
+
+        // script the debugger
+        let debug = |commands: &str| {
+            let mut out = vec![];
+            let session = &mut Session::new(src, commands.as_bytes(), &mut out);
+            let result = module.debug("main", vec![], session);
+            return (result, String::from_utf8(out).unwrap());
+        };
+
+        let (result, out) = debug("b count\nc\nbt\nn\nn\np\nc\n");
+        assert_eq!(result, Ok(Value::i32(9)));
+        assert!(out.contains("count:4  let total = 0"));
+        assert!(out.contains("  at count:4\n  at main:14\n"));
+        assert!(out.contains("count:6  total = total + n"));
+        assert!(out.contains("n = 3\ntotal = 0\n"));
+
+        // lines can be stopped at every time they run
+        let (_, out) = debug("b 7\nc\nc\np n\nc\nc\n");
+        assert_eq!(out.matches("count:7  n = n - 1").count(), 3);
+        assert!(out.contains("n = 2\n"));
+
+        // step goes in to calls, next goes over them, and finish goes back out
+        let (_, out) = debug("n\ns\np\nf\n");
+        assert!(out.contains("main:14  let b = count(a)\n(debug) count:4"));
+        assert!(out.contains("n = 3\n(debug) main:15"));
+        let (_, out) = debug("n\nn\np\n");
+        assert!(out.contains("main:15  return a + b"));
+        assert!(out.contains("a = 3\nb = 6\n"));
+
+        let (result, out) = debug("p x\nfoo\nq\n");
+        assert_eq!(result.map_err(|err| err.to_string()), Err("interrupted\n  at main".to_string()));
+        assert!(out.contains("ERR no var x"));
+        assert!(out.contains("ERR unknown command foo"));
+
+        // breakpoints only go on lines a statement starts on
+        let (_, out) = debug("b 2\nb 99\nb 7\nq\n");
+        assert!(out.contains("ERR no statement starts on line 2\n"));
+        assert!(out.contains("ERR no statement starts on line 99\n"));
+        assert!(out.contains("breakpoint at 7\n"));
+    }
+
+    #[test]
+    fn test_profiler() {
+        let module = &mut Module::from_src("
+            fib(n: I32): I32 {
+                if n < 2 {
+                    return n
+                }
+                return fib(n - 1) + fib(n - 2)
+            }
+
+            count(n: I32): I32 {
+                let total = 0
+                while n > 0 {
+                    total = total + n
+                    n = n - 1
+                }
+                return total
+            }
+
+            main(): I32 {
+                return fib(10) + count(100)
+            }
+        ");
+        let profiler = &mut Profiler::default();
+        assert_eq!(module.debug("main", vec![], profiler), Ok(Value::i32(5105)));
+
+        let get = |name: &str| profiler.funcs.iter().find(|func| func.name == name).unwrap();
+        assert_eq!(get("main").calls, 1);
+        assert_eq!(get("fib").calls, 177);
+        assert_eq!(get("count").calls, 1);
+
+        // the loop header runs once more than it goes around
+        let header = get("count").blocks.iter().find(|block| block.iterations.is_some()).unwrap();
+        assert_eq!(header.iterations, Some(100));
+        assert_eq!(header.runs, 101);
+        assert!(get("fib").blocks.iter().all(|block| block.iterations.is_none()));
+
+        // every instruction is counted once, like fuel
+        let insts: u64 = profiler.funcs.iter().map(|func| func.insts).sum();
+        module.limits.fuel = Some(insts);
+        assert!(module.exec("main", vec![]).is_ok());
+        module.limits.fuel = Some(insts - 1);
+        assert_eq!(module.exec("main", vec![]).unwrap_err().trap, Trap::OutOfFuel);
+
+        let report = &mut vec![];
+        profiler.report(report).unwrap();
+        let report = String::from_utf8(report.clone()).unwrap();
+        assert!(report.contains("loop, 100 iterations"));
+        assert!(report.find("fib").unwrap() < report.find("count").unwrap());
+
+        let stacks = &mut vec![];
+        profiler.write_stacks(stacks).unwrap();
+        let stacks = String::from_utf8(stacks.clone()).unwrap();
+        assert!(stacks.contains(&format!("main;count {}\n", get("count").insts)));
+        assert!(stacks.contains("main;fib;fib;fib;fib;fib;fib;fib;fib;fib;fib 8\n"));
+        let total: u64 = stacks.lines().map(|line| line.rsplit_once(' ').unwrap().1.parse::<u64>().unwrap()).sum();
+        assert_eq!(total, insts);
+    }
+
+    #[test]
+    fn test_tracer() {
+        let module = &Module::from_src("
+            add(a: I32, b: I32): I32 {
+                return a + b
+            }
+
+            main(): I32 {
+                let x = 0
+                if add(1, 2) > 2 {
+                    x = 5
+                }
+                return -x
+            }
+        ");
+        let trace = || {
+            let mut out = vec![];
+            let tracer = &mut Tracer::new(module, &mut out);
+            assert_eq!(module.debug("main", vec![], tracer), Ok(Value::i32(-5)));
+            return String::from_utf8(out).unwrap();
+        };
+        let out = trace();
+        assert_eq!(out, "\
+main():
+  '0:
+    v0 = I32(0)
+    v1 = I32(1)
+    v2 = I32(2)
+    v3 = add(v1, v2) ; I32(1) I32(2)
+    add(I32(1), I32(2)):
+      '0:
+        v2 = (Add v0 v1) ; I32(1) I32(2) -> I32(3)
+        return v2 ; I32(3)
+    v4 = I32(2)
+    v5 = (Gt v3 v4) ; I32(3) I32(2) -> Bool(true)
+    if v5 then '1 else '2 ; Bool(true)
+  '1:
+    v6 = I32(5)
+    jump '3(v7 = I32(5))
+  '3:
+    v8 = (Neg v7) ; I32(5) -> I32(-5)
+    return v8 ; I32(-5)
+");
+
+        // the same run gives the same trace
+        assert_eq!(trace(), out);
+
+        // an inst that traps is traced too
+        let module = &mut Module::from_src("
+            main(): I32 {
+                let a = 0
+                return 1 / a
+            }
+        ");
+        let trace = |module: &Module| {
+            let mut out = vec![];
+            let tracer = &mut Tracer::new(module, &mut out);
+            assert!(module.debug("main", vec![], tracer).is_err());
+            return String::from_utf8(out).unwrap();
+        };
+        assert_eq!(trace(module), "\
+main():
+  '0:
+    v0 = I32(0)
+    v1 = I32(1)
+    v2 = (Div v1 v0) ; I32(1) I32(0) -> trap
+");
+
+        // and so is the value of the last one that ran before the fuel ran out
+        let module = &mut Module::from_src("main(): I32 { let a = 2  return a * a }");
+        module.limits.fuel = Some(2);
+        assert!(trace(module).ends_with("v1 = (Mul v0 v0) ; I32(2) I32(2) -> I32(4)\n"));
+    }
+
+    #[test]
+    fn test_repl() {
+        let repl = |input: &str| {
+            let mut repl = Repl::new(|module| module.register("env", "twice", |args| Value::i32(args[0].as_i32() * 2)));
+            let mut out = vec![];
+            repl.run(input.as_bytes(), &mut out).unwrap();
+            return String::from_utf8(out).unwrap();
+        };
+
+        // definitions stay around, and lines are read until the braces are balanced
+        let out = repl("
+            fib(n: I32): I32 {
+                if n < 2 {
+                    // not done yet }
+                    return n
+                }
+                return fib(n - 1) + fib(n - 2)
+            }
+            fib(10)
+            fib(5) + 1.0
+        ");
+        assert!(out.starts_with(">> >> .. .. .. .. .. .. >> 55\n"));
+        assert!(out.contains("ERR can't Add I32 and F64 in <repl>\n"));
+
+        // redefining a function replaces it, for the functions that call it too
+        let out = repl("
+            double(x: I32): I32 { return x * 2 }
+            quad(x: I32): I32 { return double(double(x)) }
+            double(x: I32): I32 { return x + x + 1 }
+            quad(1)
+            extern twice(x: I32): I32
+            twice(quad(1))
+        ");
+        assert!(out.contains(">> 7\n") && !out.contains(">> 4\n"));
+        assert!(out.contains(">> 14\n"));
+
+        // a definition that doesn't compile leaves the old one
+        let out = repl("
+            f(): I32 { return 1 }
+            f(): I32 { return nope() }
+            f()
+        ");
+        assert!(out.contains("ERR unknown function nope in f\n"));
+        assert!(out.contains(">> 1\n"));
+
+        let out = repl("
+            f(x: F64): Bool { return x > 1.0 }
+            :type f(2.0)
+            :type 1 + 2
+            :ir f
+            :wat f
+            :wat g
+            :nope
+            ) (
+        ");
+        assert!(out.contains("Bool\n"));
+        assert!(out.contains("I32\n"));
+        assert!(out.contains("function f ():"));
+        assert!(out.contains("(func $f (export \"f\")\n\t(param $l0 f64)\n\t(result i32)"));
+        assert!(out.contains("ERR unknown function g"));
+        assert!(out.contains("ERR unknown command :nope"));
+        assert!(out.contains("ERR can't parse ) ("));
+
+        // mistakes in the code are reported instead of crashing the repl
+        let out = repl("
+            x + 1
+            99999999999
+            g(a: Nope): I32 { return 1 }
+            []
+            h(x: I32): I32 { if x > 1 { return 1 } }
+            1
+        ");
+        assert!(out.contains("ERR unknown variable x in <repl>\n"));
+        assert!(out.contains("ERR can't parse 99999999999\n"));
+        assert!(out.contains("ERR arrays can't be empty in <repl>\n"));
+        assert!(out.contains("ERR it can end without returning in h\n"));
+
+        let path = std::env::temp_dir().join("atlas_test_repl.atlas");
+        std::fs::write(&path, "three(): I32 { return 3 }").unwrap();
+        let out = repl(&format!(":load {}\nthree()\n:quit\nthree()\n", path.display()));
+        assert_eq!(out, ">> >> 3\n>> ");
+    }
+
+    #[test]
+    fn test_entry() {
+        // values are written like atlas code would see them
+        assert_eq!(Value::i32(-3).to_string(), "-3");
+        assert_eq!(Value::f64(2.0).to_string(), "2.0");
+        assert_eq!(Value::bool(true).to_string(), "true");
+        assert_eq!(Value::str("hi").to_string(), "hi");
+        assert_eq!(Value::unit().to_string(), "()");
+
+        assert_eq!(Value::i32(-3).to_json(), "-3");
+        assert_eq!(Value::f64(0.5).to_json(), "0.5");
+        assert_eq!(Value::f64(f64::NAN).to_json(), "null");
+        assert_eq!(Value::str("a \"b\"\n").to_json(), "\"a \\\"b\\\"\\n\"");
+
+        for value in [Value::i32(-3), Value::f64(2.5), Value::bool(false), Value::str("hi"), Value::unit()] {
+            assert_eq!(Value::parse(value.get_type(), &value.to_string()), Ok(value));
+        }
+        assert!(Value::parse(TypeDef::I32, "2.5").is_err());
+
+        // the args are read as the types of the params
+        let module = &Module::from_src("
+            scale(x: F64, n: I32, up: Bool): F64 {
+                if up {
+                    return x * 2.0
+                }
+                return x
+            }
+        ");
+        let args = crate::entry_args(module, "scale", &["1.5", "-2", "true"]).unwrap();
+        assert_eq!(args, vec![Value::f64(1.5), Value::i32(-2), Value::bool(true)]);
+        assert_eq!(module.exec("scale", args), Ok(Value::f64(3.0)));
+
+        assert_eq!(crate::entry_args(module, "scale", &["1.5"]), Err("scale takes 3 arguments but got 1".to_string()));
+        assert_eq!(crate::entry_args(module, "scale", &["1.5", "x", "true"]), Err("\"x\" isn't a valid I32 for n".to_string()));
+        assert_eq!(crate::entry_args(module, "main", &[]), Err("unknown function main".to_string()));
+    }
+
+    #[test]
+    fn test_wasm_engine() {
+        let module = &mut Module::from_src("
+            extern twice(x: I32): I32
+
+            greet(): Str {
+                return \"hi there\"
+            }
+
+            scale(x: F64, n: I32, up: Bool): F64 {
+                if up {
+                    return x * 2.0
+                }
+                return x
+            }
+
+            divide(a: I32, b: I32): I32 {
+                return a / b
+            }
+
+            quad(x: I32): I32 {
+                return twice(twice(x))
+            }
+
+            forever(): I32 {
+                while true {}
+                return 0
+            }
+
+            main(): I32 {
+                return divide(twice(5), 0) + 1
+            }
+        ");
+        module.register("env", "twice", |args| Value::i32(args[0].as_i32() * 2));
+        module.engine = Engine::Wasm;
+
+        // the args and results have the types of the function
+        assert_eq!(module.exec("greet", vec![]), Ok(Value::str("hi there")));
+        let args = crate::entry_args(module, "scale", &["1.5", "-2", "true"]).unwrap();
+        assert_eq!(module.exec("scale", args), Ok(Value::f64(3.0)));
+        assert_eq!(module.exec("quad", vec![Value::i32(3)]), Ok(Value::i32(12)));
+
+        // traps are the ones the interpreter raises, with the same stack
+        let err = module.exec("main", vec![]).unwrap_err();
+        assert_eq!(err.trap, Trap::DivideByZero);
+        assert_eq!(err.stack, vec!["divide", "main"]);
+        module.engine = Engine::Ir;
+        assert_eq!(module.exec("main", vec![]).unwrap_err(), err);
+        module.engine = Engine::Wasm;
+
+        module.limits = Limits { fuel: Some(10_000), ..Limits::default() };
+        let err = module.exec("forever", vec![]).unwrap_err();
+        assert_eq!(err.trap, Trap::OutOfFuel);
+        assert_eq!(err.stack, vec!["forever"]);
+        module.limits = Limits::default();
+
+        // it's checked the same way before it runs
+        let err = module.exec("nope", vec![]).unwrap_err();
+        assert_eq!(err.trap, Trap::UnknownFunction("nope".to_string()));
+        let err = module.exec("quad", vec![Value::f64(1.0)]).unwrap_err();
+        assert!(matches!(err.trap, Trap::TypeMismatch(_)));
+
+        // hosts can't hand wasm a string, there's nowhere to put it
+        let module = &mut Module::from_src("extern name(): Str  main(): I32 { name() return 0 }");
+        module.register("env", "name", |_| Value::str("atlas"));
+        module.engine = Engine::Wasm;
+        let err = module.exec("main", vec![]).unwrap_err();
+        assert!(matches!(err.trap, Trap::TypeMismatch(_)), "{err}");
+        assert_eq!(err.stack, vec!["main"]);
+
+        // a call can pass a value the callee wasn't compiled for, wasmtime rejects it
+        let module = &mut Module::from_src("f(x: I32): I32 { return x + 1 }  main(): I32 { return f(1.5) }");
+        module.engine = Engine::Wasm;
+        assert!(matches!(module.exec("main", vec![]).unwrap_err().trap, Trap::Wasm(_)));
+
+        // ops with the wrong types are errors, but still compile to wasm that traps
+        let module = Module::from_src("main(): Bool { return true + false }  neg(): Bool { return -true }");
+        assert_eq!(module.errors.len(), 2);
+        for name in ["main", "neg"] {
+            assert_eq!(module.exec_wasm(name, vec![]).unwrap_err().trap, Trap::Unreachable);
+        }
+
+        // the repl gets the values of expressions back from wasm too
+        let mut repl = Repl::new(|module| module.engine = Engine::Wasm);
+        let mut out = vec![];
+        repl.run("add(a: I32, b: I32): I32 { return a + b }\nadd(2, 3)\n\"hi\"\n".as_bytes(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), ">> >> 5\n>> hi\n>> \n");
+    }
 }
@@ -0,0 +1,172 @@
+use crate::core::*;
+
+/// What we know about the value of a var.
+#[derive(Debug, Clone, PartialEq)]
+enum Lattice {
+    /// Nothing assigns to the var yet.
+    Unknown,
+    /// The var always holds this value.
+    Const(Value),
+    /// The var can hold more than one value.
+    Varying,
+}
+
+impl Lattice {
+    /// Merge in another possible value, returns true if anything changed.
+    fn meet(&mut self, other: &Lattice) -> bool {
+        let new = match (&*self, other) {
+            (Lattice::Varying, _) | (_, Lattice::Unknown) => return false,
+            (Lattice::Unknown, _) => other.clone(),
+            (Lattice::Const(a), Lattice::Const(b)) if a == b => return false,
+            _ => Lattice::Varying,
+        };
+
+        *self = new;
+        return true;
+    }
+}
+
+/// Fold operators on constants, propagate constants through block params and
+/// turn branches on constant conditions into jumps.
+pub fn const_fold(func: &mut Func) {
+    let (values, reachable) = solve(func);
+
+    let mut insts = vec![];
+    let mut replace = (0..func.ir.num_vars).collect::<Vec<Var>>();
+
+    for (block, is_reachable) in reachable.into_iter().enumerate() {
+        let mut block_insts = vec![];
+
+        if !is_reachable {
+            insts.push(func.ir.block_insts(block).to_vec());
+            continue;
+        }
+
+        // block params that are always the same get materialized at the start of the block
+        let (first_param, num_params) = func.ir.block_params[block];
+        for param in first_param..first_param + num_params {
+            if let Lattice::Const(value) = &values[param] {
+                let var = func.ir.new_var(value.get_type());
+                func.ir.var_name[var] = func.ir.var_name[param].clone();
+                block_insts.push(Inst::Const(var, value.clone()));
+                replace[param] = var;
+            }
+        }
+
+        for inst in func.ir.block_insts(block) {
+            block_insts.push(match inst {
+                Inst::Op(var, ..) | Inst::UOp(var, ..) => match &values[*var] {
+                    Lattice::Const(value) => Inst::Const(*var, value.clone()),
+                    _ => inst.clone(),
+                },
+                Inst::Branch(cond, (a, b)) => match get(&values, *cond) {
+                    Lattice::Const(value) => {
+                        Inst::JumpTo(if value.as_bool() { *a } else { *b }, vec![])
+                    }
+                    _ => inst.clone(),
+                },
+                _ => inst.clone(),
+            });
+        }
+
+        insts.push(block_insts);
+    }
+
+    for inst in insts.iter_mut().flatten() {
+        inst.map_uses(|var| *replace.get(var).unwrap_or(&var));
+    }
+
+    func.ir.set_block_insts(insts);
+}
+
+/// Find the value of every var and which blocks can be reached.
+fn solve(func: &Func) -> (Vec<Lattice>, Vec<bool>) {
+    let mut values = vec![Lattice::Unknown; func.ir.num_vars];
+    let mut reachable = vec![false; func.ir.blocks.len()];
+
+    values[..func.num_params].fill(Lattice::Varying);
+    reachable[0] = true;
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        let todo = (0..func.ir.blocks.len())
+            .filter(|block| reachable[*block])
+            .collect::<Vec<Block>>();
+
+        for block in todo {
+            for inst in func.ir.block_insts(block) {
+                match inst {
+                    Inst::Op(var, op, a, b) => {
+                        let value = match (get(&values, *a), get(&values, *b)) {
+                            (Lattice::Const(a), Lattice::Const(b)) => fold_op(op, a, b),
+                            (Lattice::Unknown, _) | (_, Lattice::Unknown) => Lattice::Unknown,
+                            _ => Lattice::Varying,
+                        };
+                        changed |= values[*var].meet(&value);
+                    }
+                    Inst::UOp(var, op, a) => {
+                        let value = match get(&values, *a) {
+                            Lattice::Const(a) => fold_uop(op, a),
+                            other => other,
+                        };
+                        changed |= values[*var].meet(&value);
+                    }
+                    Inst::Const(var, value) => {
+                        changed |= values[*var].meet(&Lattice::Const(value.clone()));
+                    }
+                    Inst::Call(var, ..) | Inst::CallExtern(var, ..) => {
+                        changed |= values[*var].meet(&Lattice::Varying);
+                    }
+                    Inst::Branch(cond, (a, b)) => {
+                        let targets = match get(&values, *cond) {
+                            Lattice::Unknown => vec![],
+                            Lattice::Const(value) if value.as_bool() => vec![*a],
+                            Lattice::Const(_) => vec![*b],
+                            Lattice::Varying => vec![*a, *b],
+                        };
+
+                        for target in targets {
+                            changed |= !reachable[target];
+                            reachable[target] = true;
+                        }
+                    }
+                    Inst::JumpTo(target, args) => {
+                        changed |= !reachable[*target];
+                        reachable[*target] = true;
+
+                        let first_param = func.ir.block_params[*target].0;
+                        for (i, arg) in args.iter().enumerate() {
+                            let value = get(&values, *arg);
+                            changed |= values[first_param + i].meet(&value);
+                        }
+                    }
+                    Inst::Return(..) => {}
+                }
+            }
+        }
+    }
+
+    return (values, reachable);
+}
+
+fn get(values: &[Lattice], var: Var) -> Lattice {
+    return values.get(var).cloned().unwrap_or(Lattice::Varying);
+}
+
+/// Fold a binary operator, leaving anything that would trap at runtime alone.
+fn fold_op(op: &Op, a: Value, b: Value) -> Lattice {
+    return match do_op(op, a, b) {
+        Ok(value) => Lattice::Const(value),
+        Err(_) => Lattice::Varying,
+    };
+}
+
+/// Fold a unary operator, leaving anything that would trap at runtime alone.
+fn fold_uop(op: &UOp, a: Value) -> Lattice {
+    return match do_uop(op, a) {
+        Ok(value) => Lattice::Const(value),
+        Err(_) => Lattice::Varying,
+    };
+}
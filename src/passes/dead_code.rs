@@ -0,0 +1,162 @@
+use crate::core::*;
+use crate::utils::*;
+
+/// Remove unreachable blocks, instructions and block params whose values are
+/// never used, then renumber the vars that are left so they are compact.
+pub fn dead_code(func: &mut Func) {
+    let reachable = find_reachable(func);
+    let live = find_live(func, &reachable);
+
+    // give the blocks that are left new ids, keeping the entry block first
+    let mut block_ids = vec![None; func.ir.blocks.len()];
+    let mut num_blocks = 0;
+    for (block, is_reachable) in reachable.iter().enumerate() {
+        if *is_reachable {
+            block_ids[block] = Some(num_blocks);
+            num_blocks += 1;
+        }
+    }
+
+    // give the vars that are left new ids, the params of each block have to stay together
+    let mut var_ids = vec![None; func.ir.num_vars];
+    let mut var_type = vec![];
+    let mut var_decl = vec![];
+    let mut var_name = vec![];
+    let mut block_params = vec![(0, 0); num_blocks];
+
+    for (var, id) in var_ids.iter_mut().enumerate().take(func.num_params) {
+        *id = Some(var);
+    }
+    var_type.extend_from_slice(&func.ir.var_type[..func.num_params]);
+    var_decl.resize(func.num_params, 0);
+    var_name.extend_from_slice(&func.ir.var_name[..func.num_params]);
+
+    let mut insts = vec![vec![]; num_blocks];
+    let mut offsets = vec![0; num_blocks];
+
+    for block in func.ir.layout() {
+        let Some(new_block) = block_ids[block] else {
+            continue;
+        };
+
+        let (first_param, num_params) = func.ir.block_params[block];
+        block_params[new_block].0 = var_type.len();
+        for param in first_param..first_param + num_params {
+            if live[param] {
+                var_ids[param] = Some(var_type.len());
+                var_type.push(func.ir.var_type[param]);
+                var_decl.push(func.ir.blocks[block]);
+                var_name.push(func.ir.var_name[param].clone());
+                block_params[new_block].1 += 1;
+            }
+        }
+
+        for inst in func.ir.block_insts(block) {
+            if let Some(var) = inst.def() {
+                if !live[var] && is_pure(func, inst) {
+                    continue;
+                }
+
+                var_ids[var] = Some(var_type.len());
+                var_type.push(func.ir.var_type[var]);
+                var_decl.push(func.ir.blocks[block] + insts[new_block].len() + 1);
+                var_name.push(func.ir.var_name[var].clone());
+            }
+
+            let mut inst = inst.clone();
+
+            // drop the arguments to params that were removed
+            if let Inst::JumpTo(target, args) = &mut inst {
+                let first_param = func.ir.block_params[*target].0;
+                let mut i = 0;
+                args.retain(|_| {
+                    i += 1;
+                    live[first_param + i - 1]
+                });
+            }
+
+            insts[new_block].push(inst);
+        }
+
+        offsets[new_block] = func.ir.blocks[block];
+    }
+
+    for inst in insts.iter_mut().flatten() {
+        inst.map_def(|var| var_ids[var].unwrap());
+        inst.map_uses(|var| var_ids[var].expect("a live inst uses a var that was removed"));
+        inst.map_targets(|block| block_ids[block].unwrap());
+    }
+
+    func.ir.num_vars = var_type.len();
+    func.ir.var_type = var_type;
+    func.ir.var_decl = var_decl;
+    func.ir.var_name = var_name;
+    func.ir.block_params = block_params;
+    func.ir.blocks = offsets;
+    func.ir.set_block_insts(insts);
+}
+
+/// Find every block that can be reached from the entry block.
+fn find_reachable(func: &Func) -> Vec<bool> {
+    let mut reachable = vec![false; func.ir.blocks.len()];
+    let mut todo = vec![0];
+
+    while let Some(block) = todo.pop() {
+        if !reachable[block] {
+            reachable[block] = true;
+            todo.append(&mut get_children(func, block));
+        }
+    }
+
+    return reachable;
+}
+
+/// Find every var whose value is needed to run the function.
+fn find_live(func: &Func, reachable: &[bool]) -> Vec<bool> {
+    let mut live = vec![false; func.ir.num_vars];
+
+    let mut defs = vec![None; func.ir.num_vars];
+    let mut param_of = vec![None; func.ir.num_vars];
+    let mut jumps = vec![vec![]; func.ir.blocks.len()];
+    let mut todo = vec![];
+
+    for block in (0..func.ir.blocks.len()).filter(|block| reachable[*block]) {
+        let (first_param, num_params) = func.ir.block_params[block];
+        let params = &mut param_of[first_param..first_param + num_params];
+        for (i, param) in params.iter_mut().enumerate() {
+            *param = Some((block, i));
+        }
+
+        for inst in func.ir.block_insts(block) {
+            match inst {
+                Inst::JumpTo(target, args) => jumps[*target].push(args),
+                _ if inst.def().is_some() && is_pure(func, inst) => {
+                    defs[inst.def().unwrap()] = Some(inst)
+                }
+                _ => todo.append(&mut inst.uses()),
+            }
+        }
+    }
+
+    while let Some(var) = todo.pop() {
+        if var >= func.ir.num_vars || live[var] {
+            continue;
+        }
+
+        live[var] = true;
+
+        // the operands of a live instruction are live
+        if let Some(inst) = defs[var] {
+            todo.append(&mut inst.uses());
+        }
+
+        // the arguments passed to a live block param are live
+        if let Some((block, i)) = param_of[var] {
+            for args in &jumps[block] {
+                todo.push(args[i]);
+            }
+        }
+    }
+
+    return live;
+}
@@ -0,0 +1,89 @@
+use crate::core::*;
+use crate::utils::*;
+
+use std::collections::HashMap;
+
+/// What an instruction computes, two instructions with the same key always
+/// give the same result.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Key {
+    Op(Op, Var, Var),
+    UOp(UOp, Var),
+    Const(Value),
+}
+
+impl Key {
+    fn new(inst: &Inst) -> Option<Key> {
+        match inst {
+            // the order doesn't matter for these, so always put the smaller var first
+            Inst::Op(_, op @ (Op::Add | Op::Mul | Op::Eq | Op::Ne), a, b) => {
+                Some(Key::Op(*op, *a.min(b), *a.max(b)))
+            }
+            Inst::Op(_, op, a, b) => Some(Key::Op(*op, *a, *b)),
+            Inst::UOp(_, op, a) => Some(Key::UOp(*op, *a)),
+            Inst::Const(_, value) => Some(Key::Const(value.clone())),
+            _ => None,
+        }
+    }
+}
+
+/// Remove instructions that compute something that was already computed in a
+/// block that dominates them, and use the earlier var instead.
+pub fn gvn(func: &mut Func) {
+    let tree = get_dom_tree(func);
+
+    let mut insts = (0..func.ir.blocks.len())
+        .map(|block| func.ir.block_insts(block).to_vec())
+        .collect::<Vec<Vec<Inst>>>();
+    let mut replace = (0..func.ir.num_vars).collect::<Vec<Var>>();
+    let mut known = HashMap::new();
+
+    visit(0, &tree, &mut insts, &mut replace, &mut known);
+
+    // uses in blocks that weren't visited still need to be updated
+    for inst in insts.iter_mut().flatten() {
+        inst.map_uses(|var| *replace.get(var).unwrap_or(&var));
+    }
+
+    func.ir.set_block_insts(insts);
+}
+
+/// Number the values in <block> then in the blocks it dominates.
+fn visit(
+    block: Block,
+    tree: &[Vec<Block>],
+    insts: &mut [Vec<Inst>],
+    replace: &mut [Var],
+    known: &mut HashMap<Key, Var>,
+) {
+    let mut added = vec![];
+
+    insts[block] = std::mem::take(&mut insts[block])
+        .into_iter()
+        .filter_map(|mut inst| {
+            inst.map_uses(|var| *replace.get(var).unwrap_or(&var));
+
+            let (Some(key), Some(var)) = (Key::new(&inst), inst.def()) else {
+                return Some(inst);
+            };
+
+            if let Some(existing) = known.get(&key) {
+                replace[var] = *existing;
+                return None;
+            }
+
+            known.insert(key.clone(), var);
+            added.push(key);
+            return Some(inst);
+        })
+        .collect();
+
+    for child in &tree[block] {
+        visit(*child, tree, insts, replace, known);
+    }
+
+    // what was computed here isn't available outside of the blocks it dominates
+    for key in added {
+        known.remove(&key);
+    }
+}
@@ -0,0 +1,148 @@
+use crate::core::*;
+
+/// Inline calls to functions that have at most <threshold> instructions.
+///
+/// Functions that can end up calling themselves are never inlined, so
+/// inlining always stops.
+pub fn inline(module: &mut Module, threshold: usize) {
+    let funcs = module.funcs_mut();
+    let recursive = (0..funcs.len())
+        .map(|func_id| is_recursive(funcs, func_id))
+        .collect::<Vec<bool>>();
+
+    for caller in 0..funcs.len() {
+        while let Some((block, index, callee)) =
+            find_call_site(&funcs[caller], funcs, &recursive, threshold)
+        {
+            let callee = funcs[callee].clone();
+            splice(&mut funcs[caller], block, index, &callee);
+        }
+    }
+}
+
+/// Can <func_id> end up calling itself?
+fn is_recursive(funcs: &[Func], func_id: FuncId) -> bool {
+    let mut todo = get_callees(&funcs[func_id]);
+    let mut seen = vec![false; funcs.len()];
+
+    while let Some(callee) = todo.pop() {
+        if callee == func_id {
+            return true;
+        } else if callee < funcs.len() && !seen[callee] {
+            seen[callee] = true;
+            todo.append(&mut get_callees(&funcs[callee]));
+        }
+    }
+
+    return false;
+}
+
+fn get_callees(func: &Func) -> Vec<FuncId> {
+    return func
+        .ir
+        .insts
+        .iter()
+        .filter_map(|inst| match inst {
+            Inst::Call(_, func_id, _) => Some(*func_id),
+            _ => None,
+        })
+        .collect();
+}
+
+/// Find the first call in <func> that is worth inlining.
+fn find_call_site(
+    func: &Func,
+    funcs: &[Func],
+    recursive: &[bool],
+    threshold: usize,
+) -> Option<(Block, usize, FuncId)> {
+    for block in func.ir.layout() {
+        for (index, inst) in func.ir.block_insts(block).iter().enumerate() {
+            if let Inst::Call(_, callee, args) = inst {
+                if *callee < funcs.len()
+                    && !recursive[*callee]
+                    && funcs[*callee].num_params == args.len()
+                    && funcs[*callee].ir.insts.len() <= threshold
+                {
+                    return Some((block, index, *callee));
+                }
+            }
+        }
+    }
+
+    return None;
+}
+
+/// Replace the call at <index> in <block> with the body of <callee>.
+///
+/// The block is split in two around the call, the first half jumps into a copy
+/// of the callee, and the callee returns by jumping to the second half.
+fn splice(func: &mut Func, block: Block, index: usize, callee: &Func) {
+    let ir = &mut func.ir;
+
+    let old_layout = ir.layout();
+    let insts = ir.block_insts(block).to_vec();
+    let Inst::Call(result, _, args) = &insts[index] else {
+        unreachable!()
+    };
+
+    // the params of the callee become the arguments, everything else gets a new var
+    let mut vars = args.clone();
+    for var in callee.num_params..callee.ir.num_vars {
+        let new = ir.new_var(callee.ir.var_type[var]);
+        ir.var_name[new] = callee.ir.var_name[var].clone();
+        vars.push(new);
+    }
+
+    let blocks = (0..callee.ir.blocks.len())
+        .map(|_| ir.new_block())
+        .collect::<Vec<Block>>();
+
+    for (i, (first_param, num_params)) in callee.ir.block_params.iter().enumerate() {
+        if *num_params > 0 {
+            ir.block_params[blocks[i]] = (vars[*first_param], *num_params);
+        }
+    }
+
+    // the block after the call gets the return value as a param
+    let after = ir.new_block();
+    let ret = ir.new_var(callee.return_type);
+    ir.block_params[after] = (ret, 1);
+
+    let mut before = insts[..index].to_vec();
+    before.push(Inst::JumpTo(blocks[0], vec![]));
+
+    let mut layout = vec![];
+    for old in old_layout {
+        if old != block {
+            layout.push((old, ir.block_insts(old).to_vec()));
+            continue;
+        }
+
+        layout.push((block, before.clone()));
+
+        for callee_block in callee.ir.layout() {
+            let mut insts = callee.ir.block_insts(callee_block).to_vec();
+            for inst in &mut insts {
+                inst.map_def(|var| vars[var]);
+                inst.map_uses(|var| *vars.get(var).unwrap_or(&var));
+                inst.map_targets(|block| blocks[block]);
+
+                if let Inst::Return(var) = inst {
+                    *inst = Inst::JumpTo(after, vec![*var]);
+                }
+            }
+            layout.push((blocks[callee_block], insts));
+        }
+
+        layout.push((after, insts[index + 1..].to_vec()));
+    }
+
+    for (_, insts) in &mut layout {
+        for inst in insts {
+            inst.map_uses(|var| if var == *result { ret } else { var });
+        }
+    }
+
+    ir.set_layout(layout);
+}
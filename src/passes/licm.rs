@@ -0,0 +1,103 @@
+use crate::core::*;
+use crate::utils::*;
+
+/// Move instructions that compute the same value on every iteration of a loop
+/// into a new block that runs once before the loop starts.
+pub fn licm(func: &mut Func) {
+    let mut done = vec![];
+
+    // inner loops go first, so what they hoist can be hoisted again by the outer loop
+    while let Some((header, body)) = get_loops(func)
+        .into_iter()
+        .filter(|(header, _)| !done.contains(header))
+        .min_by_key(|(_, body)| body.len())
+    {
+        done.push(header);
+        hoist(func, header, &body);
+    }
+}
+
+fn hoist(func: &mut Func, header: Block, body: &[Block]) {
+    // we need a single jump from outside of the loop to put the preheader in
+    let entries = get_parents(func)[header]
+        .iter()
+        .filter(|parent| !body.contains(parent))
+        .copied()
+        .collect::<Vec<Block>>();
+
+    let [entry] = entries[..] else {
+        return;
+    };
+
+    let Some(Inst::JumpTo(_, args)) = func.ir.block_insts(entry).last().cloned() else {
+        return;
+    };
+
+    let mut insts = (0..func.ir.blocks.len())
+        .map(|block| func.ir.block_insts(block).to_vec())
+        .collect::<Vec<Vec<Inst>>>();
+
+    // everything assigned inside of the loop
+    let mut in_loop = vec![false; func.ir.num_vars];
+    for block in body {
+        let (first_param, num_params) = func.ir.block_params[*block];
+        in_loop[first_param..first_param + num_params].fill(true);
+        for inst in &insts[*block] {
+            if let Some(var) = inst.def() {
+                in_loop[var] = true;
+            }
+        }
+    }
+
+    let is_invariant = |in_loop: &[bool], inst: &Inst| {
+        is_pure(func, inst)
+            && inst
+                .uses()
+                .iter()
+                .all(|var| !in_loop.get(*var).unwrap_or(&false))
+    };
+
+    let mut hoisted = vec![];
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for block in get_reverse_postorder(func) {
+            if !body.contains(&block) {
+                continue;
+            }
+
+            for inst in std::mem::take(&mut insts[block]) {
+                if is_invariant(&in_loop, &inst) {
+                    in_loop[inst.def().unwrap()] = false;
+                    hoisted.push(inst);
+                    changed = true;
+                } else {
+                    insts[block].push(inst);
+                }
+            }
+        }
+    }
+
+    if hoisted.is_empty() {
+        return;
+    }
+
+    // jump to the preheader instead of straight into the loop
+    let preheader = func.ir.new_block();
+    *insts[entry].last_mut().unwrap() = Inst::JumpTo(preheader, vec![]);
+    hoisted.push(Inst::JumpTo(header, args));
+    insts.push(hoisted);
+
+    let mut layout = vec![];
+    for block in func.ir.layout() {
+        if block == header {
+            layout.push((preheader, std::mem::take(&mut insts[preheader])));
+        }
+        if block != preheader {
+            layout.push((block, std::mem::take(&mut insts[block])));
+        }
+    }
+
+    func.ir.set_layout(layout);
+}
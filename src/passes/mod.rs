@@ -0,0 +1,113 @@
+pub mod const_fold;
+pub mod dead_code;
+pub mod gvn;
+pub mod inline;
+pub mod licm;
+pub mod tail_call;
+pub mod verify;
+
+use crate::core::*;
+
+pub use const_fold::*;
+pub use dead_code::*;
+pub use gvn::*;
+pub use inline::*;
+pub use licm::*;
+pub use tail_call::*;
+pub use verify::*;
+
+/// The default for the most instructions a function can have and still be inlined.
+pub const INLINE_THRESHOLD: usize = 20;
+
+/// A transformation of the ir.
+#[derive(Clone, Copy)]
+pub enum Pass {
+    Module(fn(&mut Module, &Options)),
+    Func(fn(&mut Func)),
+}
+
+/// Every pass by name.
+pub const PASSES: &[(&str, Pass)] = &[
+    ("tailcall", Pass::Module(|m, _| tail_call(m))),
+    ("inline", Pass::Module(|m, o| inline(m, o.inline_threshold))),
+    ("constfold", Pass::Func(const_fold)),
+    ("gvn", Pass::Func(gvn)),
+    ("licm", Pass::Func(licm)),
+    ("dce", Pass::Func(dead_code)),
+];
+
+pub fn get_pass(name: &str) -> Option<Pass> {
+    return PASSES
+        .iter()
+        .find(|(pass_name, _)| *pass_name == name)
+        .map(|(_, pass)| *pass);
+}
+
+/// Get the passes to run for an optimization level.
+pub fn get_level(level: usize) -> Vec<&'static str> {
+    match level {
+        0 => vec![],
+        1 => vec!["constfold", "dce"],
+        _ => vec!["tailcall", "inline", "constfold", "gvn", "licm", "dce"],
+    }
+}
+
+pub struct Options {
+    /// The passes to run, in order.
+    pub passes: Vec<String>,
+    pub inline_threshold: usize,
+    /// Log the ir after each of these passes runs, "all" logs after every pass.
+    pub print_after: Vec<String>,
+    /// Check the ir is well formed after every pass.
+    pub verify: bool,
+}
+
+impl Options {
+    pub fn level(level: usize) -> Self {
+        return Options {
+            passes: get_level(level).iter().map(|s| s.to_string()).collect(),
+            inline_threshold: INLINE_THRESHOLD,
+            print_after: vec![],
+            verify: false,
+        };
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        return Options::level(2);
+    }
+}
+
+impl<'a> Module<'a> {
+    /// Run the passes named in <options>, logging the ir to <log> after the
+    /// ones named in <options.print_after>.
+    pub fn run_passes(
+        &mut self,
+        options: &Options,
+        log: &mut impl std::io::Write,
+    ) -> Result<(), String> {
+        for name in &options.passes {
+            match get_pass(name) {
+                Some(Pass::Module(pass)) => pass(self, options),
+                Some(Pass::Func(pass)) => self.funcs_mut().iter_mut().for_each(pass),
+                None => return Err(format!("unknown pass {name}")),
+            }
+
+            if options.verify {
+                for func in self.funcs() {
+                    verify(func).map_err(|err| {
+                        format!("invalid ir in {} after {name}: {err}", func.name)
+                    })?;
+                }
+            }
+
+            if options.print_after.iter().any(|n| n == name || n == "all") {
+                let _ = writeln!(log, "; after {name}");
+                let _ = self.log(log);
+            }
+        }
+
+        return Ok(());
+    }
+}
@@ -0,0 +1,85 @@
+use crate::core::*;
+use crate::utils::*;
+
+/// Turn calls a function makes to itself right before returning into jumps
+/// back to the start of the function, so they don't use any stack.
+pub fn tail_call(module: &mut Module) {
+    for (func_id, func) in module.funcs_mut().iter_mut().enumerate() {
+        let sites = func
+            .ir
+            .layout()
+            .into_iter()
+            .flat_map(|block| {
+                let func = &*func;
+                func.ir
+                    .block_insts(block)
+                    .iter()
+                    .enumerate()
+                    .filter(move |(index, inst)| match inst {
+                        Inst::Call(_, callee, args) => {
+                            *callee == func_id
+                                && args.len() == func.num_params
+                                && is_tail_call(func, block, *index)
+                        }
+                        _ => false,
+                    })
+                    .map(move |(index, _)| (block, index))
+            })
+            .collect::<Vec<(Block, usize)>>();
+
+        if !sites.is_empty() {
+            add_loop(func, &sites);
+        }
+    }
+}
+
+/// Move the body of the entry block into a new block that takes the function
+/// params as block params, then jump to it from the entry block and from every
+/// call site.
+fn add_loop(func: &mut Func, sites: &[(Block, usize)]) {
+    let ir = &mut func.ir;
+
+    let mut insts = (0..ir.blocks.len())
+        .map(|block| ir.block_insts(block).to_vec())
+        .collect::<Vec<Vec<Inst>>>();
+    let layout = ir.layout();
+
+    let start = ir.new_block();
+    ir.block_params[start] = (ir.num_vars, func.num_params);
+    for var in 0..func.num_params {
+        let param = ir.new_var(ir.var_type[var]);
+        ir.var_name[param] = ir.var_name[var].clone();
+    }
+
+    // everything reads the block params instead of the function params now
+    let first_param = ir.block_params[start].0;
+    for inst in insts.iter_mut().flatten() {
+        inst.map_uses(|var| {
+            if var < func.num_params {
+                first_param + var
+            } else {
+                var
+            }
+        });
+    }
+
+    for (block, index) in sites {
+        let Inst::Call(_, _, args) = insts[*block][*index].clone() else {
+            unreachable!()
+        };
+        insts[*block].truncate(*index);
+        insts[*block].push(Inst::JumpTo(start, args));
+    }
+
+    let body = std::mem::take(&mut insts[0]);
+    insts.push(body);
+    insts[0] = vec![Inst::JumpTo(start, (0..func.num_params).collect())];
+
+    let mut new_layout = vec![(0, std::mem::take(&mut insts[0]))];
+    new_layout.push((start, std::mem::take(&mut insts[start])));
+    for block in layout.into_iter().filter(|block| *block != 0) {
+        new_layout.push((block, std::mem::take(&mut insts[block])));
+    }
+
+    ir.set_layout(new_layout);
+}
@@ -0,0 +1,136 @@
+use crate::core::*;
+use crate::utils::*;
+
+/// Check that the ir of <func> is well formed: every reachable block ends with
+/// a jump or return, jumps pass the right arguments, every var is assigned
+/// once and is assigned before it is used.
+pub fn verify(func: &Func) -> Result<(), String> {
+    let ir = &func.ir;
+
+    if ir.var_type.len() != ir.num_vars {
+        return Err(format!(
+            "{} vars but {} var types",
+            ir.num_vars,
+            ir.var_type.len()
+        ));
+    }
+
+    if ir.var_name.len() != ir.num_vars {
+        return Err(format!(
+            "{} vars but {} var names",
+            ir.num_vars,
+            ir.var_name.len()
+        ));
+    }
+
+    // where each var is assigned, as (block, index in block)
+    let mut defs = vec![None; ir.num_vars];
+    let order = get_reverse_postorder(func);
+
+    let mut define = |var: Var, place: (Block, usize)| {
+        match defs.get(var) {
+            None => return Err(format!("v{var} is out of range")),
+            Some(Some(_)) => return Err(format!("v{var} is assigned more than once")),
+            Some(None) => defs[var] = Some(place),
+        }
+        return Ok(());
+    };
+
+    for var in 0..func.num_params {
+        define(var, (0, 0))?;
+    }
+
+    for block in &order {
+        let (first_param, num_params) = ir.block_params[*block];
+        for param in first_param..first_param + num_params {
+            define(param, (*block, 0))?;
+        }
+
+        let insts = ir.block_insts(*block);
+        match insts.last() {
+            Some(inst) if inst.is_exit() => {}
+            _ => return Err(format!("'{block} doesn't end with a jump or return")),
+        }
+
+        for (i, inst) in insts.iter().enumerate() {
+            if let Some(var) = inst.def() {
+                // + 1 so block params come before everything in the block
+                define(var, (*block, i + 1))?;
+            }
+        }
+    }
+
+    let idoms = get_idoms(func);
+
+    for block in &order {
+        for (i, inst) in ir.block_insts(*block).iter().enumerate() {
+            for var in inst.uses() {
+                let Some((def_block, def_index)) = defs.get(var).copied().flatten() else {
+                    return Err(format!("'{block} uses v{var} which is never assigned"));
+                };
+
+                if def_block == *block && def_index > i {
+                    return Err(format!("'{block} uses v{var} before it is assigned"));
+                }
+
+                if !is_dominated_by(&idoms, *block, def_block) {
+                    return Err(format!("'{block} uses v{var} which might not be assigned"));
+                }
+            }
+
+            verify_inst(func, *block, inst)?;
+        }
+    }
+
+    return Ok(());
+}
+
+fn verify_inst(func: &Func, block: Block, inst: &Inst) -> Result<(), String> {
+    let ir = &func.ir;
+
+    match inst {
+        Inst::Op(_, _, a, b) if ir.var_type[*a] != ir.var_type[*b] => Err(format!(
+            "'{block} applies an operator to v{a} ({}) and v{b} ({})",
+            ir.var_type[*a], ir.var_type[*b]
+        )),
+        Inst::Branch(cond, _) if ir.var_type[*cond] != TypeDef::Bool => {
+            Err(format!("'{block} branches on v{cond} which isn't a Bool"))
+        }
+        Inst::Branch(_, (a, b)) => {
+            for target in [a, b] {
+                if *target >= ir.blocks.len() {
+                    return Err(format!(
+                        "'{block} branches to '{target} which doesn't exist"
+                    ));
+                } else if ir.block_params[*target].1 != 0 {
+                    return Err(format!("'{block} branches to '{target} which has params"));
+                }
+            }
+            Ok(())
+        }
+        Inst::JumpTo(target, _) if *target >= ir.blocks.len() => {
+            Err(format!("'{block} jumps to '{target} which doesn't exist"))
+        }
+        Inst::JumpTo(target, args) => {
+            let (first_param, num_params) = ir.block_params[*target];
+            if args.len() != num_params {
+                return Err(format!(
+                    "'{block} passes {} args to '{target} which has {num_params} params",
+                    args.len()
+                ));
+            }
+
+            for (i, arg) in args.iter().enumerate() {
+                let param = first_param + i;
+                if ir.var_type[*arg] != ir.var_type[param] {
+                    return Err(format!(
+                        "'{block} passes v{arg} ({}) to v{param} ({})",
+                        ir.var_type[*arg], ir.var_type[param]
+                    ));
+                }
+            }
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
@@ -1 +1,4 @@
+pub mod wasi;
 pub mod wasm;
+pub mod wasm_dump;
+pub mod wasm_exec;
@@ -0,0 +1,369 @@
+use crate::core::*;
+
+use super::wasm::{Code, WasmFunc, WasmInst, WasmOrWatBuilder};
+
+/// The externs a wasi module gets from the runtime instead of importing them.
+pub const PRINTS: [&str; 3] = ["print", "print_i32", "print_f64"];
+
+// where the runtime keeps things in memory
+const IOVEC: i32 = 0;
+const NWRITTEN: i32 = 16;
+const NEWLINE: i32 = 20;
+const MINUS: i32 = 21;
+const DOT: i32 = 22;
+const ZERO: i32 = 23;
+const NAN: i32 = 24;
+const INF: i32 = 27;
+/// Numbers are written backwards into the bytes before this.
+const NUMBER_END: i32 = 64;
+/// Where f64s stop being able to hold every whole number, the digits of
+/// bigger ones aren't printed exactly.
+pub const MAX_EXACT_F64: f64 = 9007199254740992.0;
+
+/// Where the strings of the module go.
+pub(super) const DATA_START: usize = 64;
+
+const STDOUT: i32 = 1;
+
+/// The functions a wasi module imports.
+pub(super) fn imports() -> Vec<WasmFunc> {
+    let import = |name: &str, params: Vec<TypeDef>, result| WasmFunc {
+        name: name.to_string(),
+        params,
+        result,
+        code: Code::Import("wasi_snapshot_preview1".to_string()),
+    };
+
+    return vec![
+        import("fd_write", vec![TypeDef::I32; 4], TypeDef::I32),
+        import("proc_exit", vec![TypeDef::I32], TypeDef::Unit),
+    ];
+}
+
+/// The functions the runtime adds to a wasi module.
+pub(super) fn runtime() -> Vec<WasmFunc> {
+    let func = |name: &str, params: Vec<TypeDef>| WasmFunc {
+        name: name.to_string(),
+        params,
+        result: TypeDef::Unit,
+        code: Code::Runtime,
+    };
+
+    return vec![
+        func("atlas_write", vec![TypeDef::I32, TypeDef::I32]),
+        func("atlas_write_u32", vec![TypeDef::I32, TypeDef::I32]),
+        func("print_i32", vec![TypeDef::I32]),
+        func("print_f64", vec![TypeDef::F64]),
+        func("print", vec![TypeDef::Str]),
+    ];
+}
+
+/// The characters the runtime prints on their own.
+pub(super) fn data() -> (usize, Vec<u8>) {
+    return (NEWLINE as usize, b"\n-.0NaNinf".to_vec());
+}
+
+/// Get the locals a runtime function has after its params.
+pub(super) fn locals(name: &str) -> Vec<TypeDef> {
+    match name {
+        "atlas_write_u32" => vec![TypeDef::I32],
+        "print_f64" => vec![
+            TypeDef::I32,
+            TypeDef::I32,
+            TypeDef::I32,
+            TypeDef::F64,
+            TypeDef::I32,
+        ],
+        _ => vec![],
+    }
+}
+
+/// Add the code of the runtime function <name>.
+pub(super) fn build_runtime(f: &mut impl WasmOrWatBuilder, name: &str) {
+    match name {
+        // (ptr, len), write the bytes from ptr to ptr + len to stdout
+        "atlas_write" => {
+            f.add_const_i32(IOVEC);
+            f.get_local(0);
+            f.add_inst(WasmInst::I32Store);
+            f.add_const_i32(IOVEC + 4);
+            f.get_local(1);
+            f.add_inst(WasmInst::I32Store);
+
+            f.add_const_i32(STDOUT);
+            f.add_const_i32(IOVEC);
+            f.add_const_i32(1);
+            f.add_const_i32(NWRITTEN);
+            f.add_call(f.layout().index_of("fd_write"));
+            f.add_inst(WasmInst::Drop);
+        }
+        // (x, width), write x with at least width digits, padded with zeros
+        "atlas_write_u32" => {
+            f.add_const_i32(NUMBER_END);
+            f.set_local(2);
+
+            f.start_loop();
+            add_add_i32(f, 2, -1);
+
+            // the last digit goes before the ones already written
+            f.get_local(2);
+            f.add_const_i32(b'0' as i32);
+            f.get_local(0);
+            f.add_const_i32(10);
+            f.add_inst(WasmInst::I32RemU);
+            f.add_inst(WasmInst::I32Add);
+            f.add_inst(WasmInst::I32Store8);
+
+            f.get_local(0);
+            f.add_const_i32(10);
+            f.add_inst(WasmInst::I32DivU);
+            f.set_local(0);
+            add_add_i32(f, 1, -1);
+
+            // keep going while there are digits left, or it isn't wide enough
+            f.get_local(0);
+            f.add_const_i32(0);
+            f.add_inst(WasmInst::I32Ne);
+            f.get_local(1);
+            f.add_const_i32(0);
+            f.add_inst(WasmInst::I32GtS);
+            f.add_inst(WasmInst::I32Or);
+            f.if_block();
+            f.add_break(1);
+            f.end_block();
+            f.close_loop();
+
+            f.get_local(2);
+            f.add_const_i32(NUMBER_END);
+            f.get_local(2);
+            f.add_inst(WasmInst::I32Sub);
+            f.add_call(f.layout().index_of("atlas_write"));
+        }
+        // (x), write x and a newline
+        "print_i32" => {
+            f.get_local(0);
+            f.add_const_i32(0);
+            f.add_inst(WasmInst::I32LtS);
+            f.if_block();
+            add_write(f, MINUS, 1);
+            f.add_const_i32(0);
+            f.get_local(0);
+            f.add_inst(WasmInst::I32Sub);
+            f.set_local(0);
+            f.end_block();
+
+            // the most negative number is still right when read as unsigned
+            f.get_local(0);
+            f.add_const_i32(1);
+            f.add_call(f.layout().index_of("atlas_write_u32"));
+            add_write(f, NEWLINE, 1);
+        }
+        // (x), write x with up to 6 decimal places and a newline
+        "print_f64" => {
+            // nan isn't equal to anything, even itself
+            f.get_local(0);
+            f.get_local(0);
+            f.add_inst(WasmInst::F64Ne);
+            f.if_block();
+            add_write(f, NAN, 3);
+            add_write(f, NEWLINE, 1);
+            f.add_return();
+            f.end_block();
+
+            // dividing by it gives the sign of -0.0 too
+            f.get_local(0);
+            f.add_const_f64(0.0);
+            f.add_inst(WasmInst::F64Lt);
+            f.add_const_f64(1.0);
+            f.get_local(0);
+            f.add_inst(WasmInst::F64Div);
+            f.add_const_f64(0.0);
+            f.add_inst(WasmInst::F64Lt);
+            f.add_inst(WasmInst::I32Or);
+            f.if_block();
+            add_write(f, MINUS, 1);
+            f.get_local(0);
+            f.add_inst(WasmInst::F64Neg);
+            f.set_local(0);
+            f.end_block();
+
+            f.get_local(0);
+            f.add_const_f64(f64::INFINITY);
+            f.add_inst(WasmInst::F64Eq);
+            f.if_block();
+            add_write(f, INF, 3);
+            add_write(f, NEWLINE, 1);
+            f.add_return();
+            f.end_block();
+
+            // the digits of big numbers aren't exact, so drop them and write zeros instead
+            f.start_loop();
+            f.get_local(0);
+            f.add_const_f64(MAX_EXACT_F64);
+            f.add_inst(WasmInst::F64Ge);
+            f.if_block();
+            f.get_local(0);
+            f.add_const_f64(10.0);
+            f.add_inst(WasmInst::F64Div);
+            f.add_inst(WasmInst::F64Floor);
+            f.set_local(0);
+            add_add_i32(f, 5, 1);
+            f.add_break(1);
+            f.end_block();
+            f.close_loop();
+
+            // the whole part
+            f.get_local(0);
+            f.add_inst(WasmInst::F64Floor);
+            f.set_local(4);
+
+            // the decimal places, rounded
+            f.get_local(0);
+            f.get_local(4);
+            f.add_inst(WasmInst::F64Sub);
+            f.add_const_f64(1e6);
+            f.add_inst(WasmInst::F64Mul);
+            f.add_inst(WasmInst::F64Nearest);
+            f.add_inst(WasmInst::I32TruncSatF64U);
+            f.set_local(2);
+
+            // rounding up can carry into the whole part
+            f.get_local(2);
+            f.add_const_i32(1_000_000);
+            f.add_inst(WasmInst::I32GeS);
+            f.if_block();
+            f.get_local(4);
+            f.add_const_f64(1.0);
+            f.add_inst(WasmInst::F64Add);
+            f.set_local(4);
+            add_add_i32(f, 2, -1_000_000);
+            f.end_block();
+
+            // drop the zeros at the end, but keep one decimal place
+            f.add_const_i32(6);
+            f.set_local(3);
+            f.start_loop();
+            f.get_local(3);
+            f.add_const_i32(1);
+            f.add_inst(WasmInst::I32GtS);
+            f.get_local(2);
+            f.add_const_i32(10);
+            f.add_inst(WasmInst::I32RemU);
+            f.add_const_i32(0);
+            f.add_inst(WasmInst::I32Eq);
+            f.add_inst(WasmInst::I32And);
+            f.if_block();
+            f.get_local(2);
+            f.add_const_i32(10);
+            f.add_inst(WasmInst::I32DivU);
+            f.set_local(2);
+            add_add_i32(f, 3, -1);
+            f.add_break(1);
+            f.end_block();
+            f.close_loop();
+
+            // the whole part doesn't fit in an i32, so split it in to the digits
+            // before the last 9 and the last 9, the division can round up
+            f.get_local(4);
+            f.add_const_f64(1e9);
+            f.add_inst(WasmInst::F64Div);
+            f.add_inst(WasmInst::I32TruncSatF64U);
+            f.set_local(1);
+            f.get_local(4);
+            f.get_local(1);
+            f.add_inst(WasmInst::F64ConvertI32U);
+            f.add_const_f64(1e9);
+            f.add_inst(WasmInst::F64Mul);
+            f.add_inst(WasmInst::F64Sub);
+            f.set_local(4);
+            f.get_local(4);
+            f.add_const_f64(0.0);
+            f.add_inst(WasmInst::F64Lt);
+            f.if_block();
+            add_add_i32(f, 1, -1);
+            f.get_local(4);
+            f.add_const_f64(1e9);
+            f.add_inst(WasmInst::F64Add);
+            f.set_local(4);
+            f.end_block();
+
+            f.get_local(1);
+            f.add_const_i32(0);
+            f.add_inst(WasmInst::I32Ne);
+            f.if_block();
+            f.get_local(1);
+            f.add_const_i32(1);
+            f.add_call(f.layout().index_of("atlas_write_u32"));
+            f.get_local(4);
+            f.add_inst(WasmInst::I32TruncSatF64U);
+            f.add_const_i32(9);
+            f.add_call(f.layout().index_of("atlas_write_u32"));
+            f.else_block();
+            f.get_local(4);
+            f.add_inst(WasmInst::I32TruncSatF64U);
+            f.add_const_i32(1);
+            f.add_call(f.layout().index_of("atlas_write_u32"));
+            f.end_block();
+
+            f.start_loop();
+            f.get_local(5);
+            f.add_const_i32(0);
+            f.add_inst(WasmInst::I32GtS);
+            f.if_block();
+            add_write(f, ZERO, 1);
+            add_add_i32(f, 5, -1);
+            f.add_break(1);
+            f.end_block();
+            f.close_loop();
+
+            add_write(f, DOT, 1);
+            f.get_local(2);
+            f.get_local(3);
+            f.add_call(f.layout().index_of("atlas_write_u32"));
+            add_write(f, NEWLINE, 1);
+        }
+        // (str), write the string, which starts with its length, and a newline
+        "print" => {
+            f.get_local(0);
+            f.add_const_i32(4);
+            f.add_inst(WasmInst::I32Add);
+            f.get_local(0);
+            f.add_inst(WasmInst::I32Load);
+            f.add_call(f.layout().index_of("atlas_write"));
+            add_write(f, NEWLINE, 1);
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Add the code of `_start`, which runs main and exits with its result.
+pub(super) fn build_start(f: &mut impl WasmOrWatBuilder, module: &Module) {
+    match module.scope.get_func("main") {
+        Some((Callee::Func(main), return_type, _)) => {
+            f.add_call(f.layout().first_func + main);
+            match return_type {
+                TypeDef::F64 => f.add_inst(WasmInst::I32TruncSatF64S),
+                TypeDef::Unit => f.add_const_i32(0),
+                _ => {}
+            }
+        }
+        _ => f.add_const_i32(0),
+    }
+
+    f.add_call(f.layout().index_of("proc_exit"));
+}
+
+/// Write the <len> bytes at <address> to stdout.
+fn add_write(f: &mut impl WasmOrWatBuilder, address: i32, len: i32) {
+    f.add_const_i32(address);
+    f.add_const_i32(len);
+    f.add_call(f.layout().index_of("atlas_write"));
+}
+
+/// Add <value> to the i32 in <local>.
+fn add_add_i32(f: &mut impl WasmOrWatBuilder, local: usize, value: i32) {
+    f.get_local(local);
+    f.add_const_i32(value);
+    f.add_inst(WasmInst::I32Add);
+    f.set_local(local);
+}
@@ -1,62 +1,260 @@
 use crate::core::*;
 use crate::utils::*;
 
+use std::collections::HashMap;
 use std::io::Write;
 
+use super::wasi;
+
 impl TypeDef {
-    fn to_wat(&self) -> &str {
+    pub(super) fn to_wat(&self) -> &str {
         match self {
             TypeDef::I32 => "i32",
             TypeDef::Bool => "i32",
+            TypeDef::Str => "i32",
             TypeDef::F64 => "f64",
-            _ => unimplemented!(),
+            // unit values are never stored, and params can't be unit
+            TypeDef::Unit => unreachable!("unit has no wasm type"),
         }
     }
 
-    fn to_wasm(&self) -> u8 {
+    pub(super) fn to_wasm(&self) -> u8 {
         match self {
-            TypeDef::Bool | TypeDef::I32 => 0x7F,
+            TypeDef::Bool | TypeDef::I32 | TypeDef::Str => 0x7F,
             TypeDef::F64 => 0x7C,
-            _ => unimplemented!(),
+            TypeDef::Unit => unreachable!("unit has no wasm type"),
         }
     }
 }
 
 const TAB: &'static str = "\t";
 
+#[derive(Default)]
+pub struct WasmOptions {
+    /// Use the tail call proposal for calls right before a return.
+    pub tail_calls: bool,
+    /// Leave values that are used once on the operand stack instead of
+    /// storing them in locals.
+    pub stackify: bool,
+    /// Write wat with instructions nested inside of the ones using their
+    /// results, instead of one instruction per line.
+    pub folded: bool,
+    /// What is going to run the module.
+    pub target: Target,
+}
+
+#[derive(Default, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Target {
+    /// Anything that runs wasm, the host provides every extern.
+    #[default]
+    Wasm,
+    /// A wasi runtime, the module prints with `fd_write` and starts at `_start`.
+    Wasi,
+}
+
+/// Where the code of a wasm function comes from.
+pub(super) enum Code {
+    /// Imported from the module named.
+    Import(String),
+    /// Part of the wasi runtime.
+    Runtime,
+    Func(FuncId),
+    /// The `_start` function of a wasi module.
+    Start,
+}
+
+pub(super) struct WasmFunc {
+    pub(super) name: String,
+    pub(super) params: Vec<TypeDef>,
+    pub(super) result: TypeDef,
+    pub(super) code: Code,
+}
+
+/// Where everything goes in the wasm module. Imported functions come first,
+/// then the runtime, then the functions of the module.
+pub(super) struct Layout {
+    pub(super) funcs: Vec<WasmFunc>,
+    /// The index of each extern.
+    pub(super) externs: Vec<usize>,
+    /// The index of the first function of the module.
+    pub(super) first_func: usize,
+    /// The address of each string.
+    pub(super) strings: HashMap<String, usize>,
+    /// The bytes to put in memory, and where.
+    pub(super) data: Vec<(usize, Vec<u8>)>,
+    pub(super) memory: bool,
+}
+
+impl Layout {
+    fn new(module: &Module, options: &WasmOptions) -> Self {
+        let wasi = options.target == Target::Wasi;
+        let mut layout = Layout {
+            funcs: vec![],
+            externs: vec![usize::MAX; module.externs.len()],
+            first_func: 0,
+            strings: HashMap::new(),
+            data: vec![],
+            memory: wasi,
+        };
+
+        if wasi {
+            layout.funcs.append(&mut wasi::imports());
+        }
+
+        // the runtime provides the print functions itself
+        for (i, extern_def) in module.externs.iter().enumerate() {
+            if wasi && wasi::PRINTS.contains(&extern_def.name.as_str()) {
+                continue;
+            }
+
+            layout.externs[i] = layout.funcs.len();
+            layout.funcs.push(WasmFunc {
+                name: extern_def.name.clone(),
+                params: extern_def.params.iter().map(|p| p.param_type).collect(),
+                result: extern_def.return_type,
+                code: Code::Import(extern_def.module.clone()),
+            });
+        }
+
+        if wasi {
+            layout.funcs.append(&mut wasi::runtime());
+            layout.data.push(wasi::data());
+            for (i, extern_def) in module.externs.iter().enumerate() {
+                if layout.externs[i] == usize::MAX {
+                    layout.externs[i] = layout.index_of(&extern_def.name);
+                }
+            }
+        }
+
+        layout.first_func = layout.funcs.len();
+        for (i, func) in module.funcs().iter().enumerate() {
+            layout.funcs.push(WasmFunc {
+                name: func.name.clone(),
+                params: func.ir.var_type[..func.num_params].to_vec(),
+                result: func.return_type,
+                code: Code::Func(i),
+            });
+        }
+
+        if wasi {
+            layout.funcs.push(WasmFunc {
+                name: "_start".to_string(),
+                params: vec![],
+                result: TypeDef::Unit,
+                code: Code::Start,
+            });
+        }
+
+        // strings go in memory after their length
+        let mut address = wasi::DATA_START;
+        for inst in module.funcs().iter().flat_map(|func| &func.ir.insts) {
+            let Inst::Const(_, value) = inst else {
+                continue;
+            };
+            if value.get_type() != TypeDef::Str || layout.strings.contains_key(value.as_str()) {
+                continue;
+            }
+
+            let mut bytes = (value.as_str().len() as u32).to_le_bytes().to_vec();
+            bytes.extend_from_slice(value.as_str().as_bytes());
+            layout.strings.insert(value.as_str().to_string(), address);
+            layout.data.push((address, bytes));
+            address = (address + 4 + value.as_str().len()).next_multiple_of(4);
+            layout.memory = true;
+        }
+
+        return layout;
+    }
+
+    /// Get the index of the function named <name>, that isn't one of the module's.
+    pub(super) fn index_of(&self, name: &str) -> usize {
+        return (0..self.funcs.len())
+            .find(|i| self.funcs[*i].name == name && !matches!(self.funcs[*i].code, Code::Func(_)))
+            .expect("function isn't in the layout");
+    }
+
+    /// Get how many pages of memory are needed.
+    fn pages(&self) -> usize {
+        let end = self
+            .data
+            .iter()
+            .map(|(address, bytes)| address + bytes.len());
+        return end.max().unwrap_or(0).div_ceil(WASM_PAGE_SIZE).max(1);
+    }
+}
+
 impl<'a> Module<'a> {
     pub fn to_wat(&self) -> Vec<u8> {
+        return self.to_wat_with(&WasmOptions::default());
+    }
+
+    pub fn to_wat_with(&self, options: &WasmOptions) -> Vec<u8> {
         let mut b = vec![];
+        let layout = Layout::new(self, options);
 
         // open module
         let _ = writeln!(b, "(module");
 
-        // add the funcs
-        for (i, func) in self.funcs.iter().enumerate() {
-            // open function
-            let _ = writeln!(b, "(func ${}", i);
+        // add the imports
+        for func in &layout.funcs {
+            let Code::Import(module) = &func.code else {
+                continue;
+            };
+            let mut import = format!(
+                "(import \"{module}\" \"{}\" (func ${}",
+                func.name, func.name
+            );
+            for param in &func.params {
+                import += &format!(" (param {})", param.to_wat());
+            }
+            if func.result != TypeDef::Unit {
+                import += &format!(" (result {})", func.result.to_wat());
+            }
+            let _ = writeln!(b, "{import}))");
+        }
 
-            // all functions should be exported
-            let _ = writeln!(b, "{TAB}(export \"{}\")", func.name);
+        // add the memory
+        if layout.memory {
+            let _ = writeln!(b, "(memory (export \"memory\") {})", layout.pages());
+        }
+        for (address, bytes) in &layout.data {
+            let _ = writeln!(b, "(data (i32.const {address}) \"{}\")", escape(bytes));
+        }
 
-            // add params
-            for var in 0..func.num_params {
-                let _ = writeln!(b, "{TAB}(param ${var} {})", func.ir.var_type[var].to_wat());
-            }
+        // add the funcs
+        for func in &layout.funcs {
+            match func.code {
+                Code::Import(_) => continue,
+                Code::Runtime => {
+                    let locals = wasi::locals(&func.name);
+                    write_wat_header(&mut b, func, false, &locals);
+
+                    let all = func.params.len() + locals.len();
+                    let mut builder = WatBuilder::new(&layout, (0..all).collect(), options.folded);
+                    wasi::build_runtime(&mut builder, &func.name);
+                    b.append(&mut builder.buffer);
+                }
+                Code::Start => {
+                    write_wat_header(&mut b, func, true, &[]);
 
-            // result type
-            let _ = writeln!(b, "{TAB}(result {})", func.return_type.to_wat());
+                    let mut builder = WatBuilder::new(&layout, vec![], options.folded);
+                    wasi::build_start(&mut builder, self);
+                    b.append(&mut builder.buffer);
+                }
+                Code::Func(id) => {
+                    // all functions should be exported
+                    let func_ir = &self.funcs()[id];
+                    let stacked = get_stacked(func_ir, options);
+                    let locals = Locals::new(func_ir, &stacked);
+                    write_wat_header(&mut b, func, true, &locals.types);
 
-            // add locals
-            for var in func.num_params..func.ir.num_vars {
-                let _ = writeln!(b, "{TAB}(local ${var} {})", func.ir.var_type[var].to_wat());
+                    // add code
+                    let mut builder = WatBuilder::new(&layout, locals.of_var, options.folded);
+                    build(&mut builder, func_ir, options, &stacked);
+                    b.append(&mut builder.buffer);
+                }
             }
 
-            // add code
-            let mut builder = WatBuilder::new();
-            build(&mut builder, func);
-            b.append(&mut builder.buffer);
-
             // close function
             let _ = writeln!(b, ")");
         }
@@ -68,65 +266,135 @@ impl<'a> Module<'a> {
     }
 
     pub fn to_wasm(&self) -> Vec<u8> {
+        return self.to_wasm_with(&WasmOptions::default());
+    }
+
+    pub fn to_wasm_with(&self, options: &WasmOptions) -> Vec<u8> {
         let mut b = vec![];
+        let layout = Layout::new(self, options);
 
         b.append(&mut vec![0x00, 0x61, 0x73, 0x6D]); // magic number
         b.append(&mut vec![0x01, 0x00, 0x00, 0x00]); // version number
 
+        // every function has its own type
         add_section(&mut b, WASM_TYPE_SECTION, |b| {
-            self.funcs.len().write_leb128(b); // how many types?
+            layout.funcs.len().write_leb128(b); // how many types?
 
-            for func in &self.funcs {
-                b.push(0x60);
+            for func in &layout.funcs {
+                write_func_type(b, &func.params, func.result);
+            }
+        });
 
-                func.num_params.write_leb128(b); // how many params?
-                for i in 0..func.num_params {
-                    b.push(func.ir.var_type[i].to_wasm());
-                }
+        let imports = || {
+            layout
+                .funcs
+                .iter()
+                .enumerate()
+                .filter_map(|(i, func)| match &func.code {
+                    Code::Import(module) => Some((i, module, func)),
+                    _ => None,
+                })
+        };
+        let defined = || {
+            (0..layout.funcs.len()).filter(|i| !matches!(layout.funcs[*i].code, Code::Import(_)))
+        };
+
+        add_section(&mut b, WASM_IMPORT_SECTION, |b| {
+            imports().count().write_leb128(b); // how many imports?
 
-                1usize.write_leb128(b); // how many values returns?
-                b.push(func.return_type.to_wasm());
+            for (i, module, func) in imports() {
+                write_name(b, module);
+                write_name(b, &func.name);
+
+                b.push(0x00); // were importing a function
+                i.write_leb128(b); // type id
             }
         });
 
         add_section(&mut b, WASM_FUNCTION_SECTION, |b| {
-            self.funcs.len().write_leb128(b); // how many functions?
+            defined().count().write_leb128(b); // how many functions?
 
-            for i in 0..self.funcs.len() {
-                i.write_leb128(b);
+            for i in defined() {
+                i.write_leb128(b); // type id
             }
         });
 
+        if layout.memory {
+            add_section(&mut b, WASM_MEMORY_SECTION, |b| {
+                1usize.write_leb128(b); // how many memories?
+                b.push(0x00); // with only a minimum size
+                layout.pages().write_leb128(b);
+            });
+        }
+
+        // the module's functions and _start are exported
+        let exports = || defined().filter(|i| !matches!(layout.funcs[*i].code, Code::Runtime));
+
         add_section(&mut b, WASM_EXPORT_SECTION, |b| {
-            self.funcs.len().write_leb128(b); // how many functions exported?
-
-            for i in 0..self.funcs.len() {
-                // write the name
-                let name = &self.funcs[i].name;
-                name.as_bytes().len().write_leb128(b);
-                for byte in name.bytes() {
-                    b.push(byte);
-                }
+            (exports().count() + layout.memory as usize).write_leb128(b); // how many exports?
+
+            for i in exports() {
+                write_name(b, &layout.funcs[i].name);
 
                 b.push(0x00); // were exporting a function
                 i.write_leb128(b); // function id
             }
+
+            if layout.memory {
+                write_name(b, "memory");
+
+                b.push(0x02); // were exporting a memory
+                0usize.write_leb128(b); // memory id
+            }
         });
 
+        // where the vars of each function go
+        let funcs = self
+            .funcs()
+            .iter()
+            .map(|func| {
+                let stacked = get_stacked(func, options);
+                let locals = Locals::new(func, &stacked);
+                (func, stacked, locals)
+            })
+            .collect::<Vec<_>>();
+
         add_section(&mut b, WASM_CODE_SECTION, |b| {
-            self.funcs.len().write_leb128(b); // how many functions?
+            defined().count().write_leb128(b); // how many functions?
 
-            for func in &self.funcs {
+            for i in defined() {
+                let func = &layout.funcs[i];
                 write_with_length(b, |b| {
-                    func.ir.num_vars.write_leb128(b); // how many locals?
-                    for i in 0..func.ir.num_vars {
-                        1usize.write_leb128(b); // how many of this type
-                        b.push(func.ir.var_type[i].to_wasm()); // local type
-                    }
+                    let mut builder = match func.code {
+                        Code::Runtime => {
+                            let locals = wasi::locals(&func.name);
+                            write_local_groups(b, &group_locals(&locals));
+
+                            let all = func.params.len() + locals.len();
+                            let mut builder = WasmBuilder::new((0..all).collect(), &layout);
+                            wasi::build_runtime(&mut builder, &func.name);
+                            builder
+                        }
+                        Code::Start => {
+                            write_local_groups(b, &[]);
+
+                            let mut builder = WasmBuilder::new(vec![], &layout);
+                            wasi::build_start(&mut builder, self);
+                            builder
+                        }
+                        Code::Func(id) => {
+                            // params are already locals
+                            let (func, stacked, locals) = &funcs[id];
+                            write_local_groups(b, &group_locals(&locals.types));
+
+                            let mut builder = WasmBuilder::new(locals.of_var.clone(), &layout);
+                            build(&mut builder, func, options, stacked);
+                            builder
+                        }
+                        Code::Import(_) => unreachable!(),
+                    };
 
                     // add code
-                    let mut builder = WasmBuilder::new();
-                    build(&mut builder, func);
                     b.append(&mut builder.buffer);
 
                     // end inst
@@ -135,10 +403,118 @@ impl<'a> Module<'a> {
             }
         });
 
+        if !layout.data.is_empty() {
+            add_section(&mut b, WASM_DATA_SECTION, |b| {
+                layout.data.len().write_leb128(b); // how many segments?
+
+                for (address, bytes) in &layout.data {
+                    b.push(0x00); // in memory 0
+
+                    // at the address
+                    b.push(0x41);
+                    (*address as i32).write_leb128(b);
+                    b.push(0x0B);
+
+                    bytes.len().write_leb128(b);
+                    b.extend_from_slice(bytes);
+                }
+            });
+        }
+
+        add_section(&mut b, WASM_CUSTOM_SECTION, |b| {
+            write_name(b, "name");
+
+            if !self.name.is_empty() {
+                b.push(WASM_MODULE_NAME);
+                write_with_length(b, |b| write_name(b, &self.name));
+            }
+
+            b.push(WASM_FUNCTION_NAMES);
+            write_with_length(b, |b| {
+                layout.funcs.len().write_leb128(b); // how many functions?
+                for (i, func) in layout.funcs.iter().enumerate() {
+                    i.write_leb128(b);
+                    write_name(b, &func.name);
+                }
+            });
+
+            b.push(WASM_LOCAL_NAMES);
+            write_with_length(b, |b| {
+                funcs.len().write_leb128(b); // how many functions?
+                for (i, (func, _, locals)) in funcs.iter().enumerate() {
+                    (layout.first_func + i).write_leb128(b);
+
+                    let names = locals.names(func);
+                    names.len().write_leb128(b); // how many named locals?
+                    for (local, name) in names {
+                        local.write_leb128(b);
+                        write_name(b, &name);
+                    }
+                }
+            });
+        });
+
         return b;
     }
 }
 
+/// Write the start of a function in wat, up to its code.
+fn write_wat_header(b: &mut Vec<u8>, func: &WasmFunc, export: bool, locals: &[TypeDef]) {
+    if export {
+        let _ = writeln!(b, "(func ${} (export \"{}\")", func.name, func.name);
+    } else {
+        let _ = writeln!(b, "(func ${}", func.name);
+    }
+
+    // add params
+    for (local, typ) in func.params.iter().enumerate() {
+        let _ = writeln!(b, "{TAB}(param {} {})", local_name(local), typ.to_wat());
+    }
+
+    // result type
+    if func.result != TypeDef::Unit {
+        let _ = writeln!(b, "{TAB}(result {})", func.result.to_wat());
+    }
+
+    // add locals
+    for (i, typ) in locals.iter().enumerate() {
+        let name = local_name(func.params.len() + i);
+        let _ = writeln!(b, "{TAB}(local {name} {})", typ.to_wat());
+    }
+}
+
+/// Write <bytes> as a wat string.
+fn escape(bytes: &[u8]) -> String {
+    return bytes
+        .iter()
+        .map(|byte| match *byte {
+            b'"' | b'\\' => format!("\\{byte:02x}"),
+            b' '..=b'~' => (*byte as char).to_string(),
+            _ => format!("\\{byte:02x}"),
+        })
+        .collect();
+}
+
+fn write_local_groups(b: &mut Vec<u8>, groups: &[(usize, TypeDef)]) {
+    groups.len().write_leb128(b); // how many groups of locals?
+    for (count, typ) in groups {
+        count.write_leb128(b); // how many of this type
+        b.push(typ.to_wasm()); // local type
+    }
+}
+
+/// Get how many locals there are of each type, in order.
+fn group_locals(types: &[TypeDef]) -> Vec<(usize, TypeDef)> {
+    let mut groups: Vec<(usize, TypeDef)> = vec![];
+    for typ in types {
+        match groups.last_mut() {
+            Some((count, last)) if last == typ => *count += 1,
+            _ => groups.push((1, *typ)),
+        }
+    }
+    return groups;
+}
+
 fn write_with_length(b: &mut Vec<u8>, builder: impl FnOnce(&mut Vec<u8>) -> ()) {
     let mut content = vec![];
     builder(&mut content);
@@ -146,162 +522,565 @@ fn write_with_length(b: &mut Vec<u8>, builder: impl FnOnce(&mut Vec<u8>) -> ())
     b.append(&mut content);
 }
 
+fn write_func_type(b: &mut Vec<u8>, params: &[TypeDef], result: TypeDef) {
+    b.push(0x60);
+
+    params.len().write_leb128(b); // how many params?
+    for param in params {
+        b.push(param.to_wasm());
+    }
+
+    if result == TypeDef::Unit {
+        0usize.write_leb128(b); // how many values returns?
+    } else {
+        1usize.write_leb128(b); // how many values returns?
+        b.push(result.to_wasm());
+    }
+}
+
+fn write_name(b: &mut Vec<u8>, name: &str) {
+    name.len().write_leb128(b);
+    b.extend_from_slice(name.as_bytes());
+}
+
 fn add_section(b: &mut Vec<u8>, section_id: u8, builder: impl FnOnce(&mut Vec<u8>) -> ()) {
     b.push(section_id);
     write_with_length(b, builder);
 }
 
+const WASM_CUSTOM_SECTION: u8 = 0;
 const WASM_TYPE_SECTION: u8 = 1;
+const WASM_IMPORT_SECTION: u8 = 2;
 const WASM_FUNCTION_SECTION: u8 = 3;
+const WASM_MEMORY_SECTION: u8 = 5;
 const WASM_EXPORT_SECTION: u8 = 7;
 const WASM_CODE_SECTION: u8 = 10;
-
-fn build(builder: &mut impl WasmOrWatBuilder, func: &Func) {
-    reloop(builder, func, 0);
-    builder.add_inst(WasmInst::Unreachable)
+const WASM_DATA_SECTION: u8 = 11;
+
+const WASM_PAGE_SIZE: usize = 65536;
+
+// the parts of the name section
+const WASM_MODULE_NAME: u8 = 0;
+const WASM_FUNCTION_NAMES: u8 = 1;
+const WASM_LOCAL_NAMES: u8 = 2;
+
+/// Where the vars of a function are stored, vars that are never live at the
+/// same time share a local.
+struct Locals {
+    /// The local each var is stored in, usize::MAX for vars that are left on the stack.
+    of_var: Vec<usize>,
+    /// The types of the locals after the params, all locals of the same type
+    /// are next to each other.
+    types: Vec<TypeDef>,
 }
 
-fn reloop(f: &mut impl WasmOrWatBuilder, func: &Func, block: usize) -> Option<usize> {
-    let next_block = if is_loop(func, block) {
-        f.start_loop();
-        let next_block = add_block(f, func, block);
-        f.close_loop();
-        next_block
-    } else {
-        add_block(f, func, block)
-    };
+impl Locals {
+    fn new(func: &Func, stacked: &[Option<&Inst>]) -> Self {
+        let graph = get_interference(func);
 
-    return next_block;
-}
+        // i32, bool and str are all stored in i32 locals
+        let local_type = |var: Var| match func.ir.var_type[var] {
+            TypeDef::Bool | TypeDef::Str => TypeDef::I32,
+            typ => typ,
+        };
 
-fn add_block(f: &mut impl WasmOrWatBuilder, func: &Func, block: usize) -> Option<usize> {
-    for inst in &func.ir.insts[func.ir.blocks[block]..] {
-        match inst {
-            Inst::Call(var, call, args) => {
-                for arg in args {
-                    f.get_local(*arg);
-                }
-                f.add_func_call(*call);
-                f.set_local(*var);
+        // params are already locals, so they can be reused too
+        let mut slots = (0..func.num_params)
+            .map(|var| (local_type(var), var))
+            .collect::<Vec<(TypeDef, usize)>>();
+        let mut slot_of_var = (0..func.num_params)
+            .map(Some)
+            .collect::<Vec<Option<usize>>>();
+
+        for (var, others) in graph.iter().enumerate().skip(func.num_params) {
+            // vars left on the stack, or that hold nothing, don't need a local
+            if stacked[var].is_some() || func.ir.var_type[var] == TypeDef::Unit {
+                slot_of_var.push(None);
+                continue;
             }
-            Inst::Op(var, op, a, b) => {
-                f.get_local(*a);
-                f.get_local(*b);
-                match (op, func.ir.var_type[*a].clone()) {
-                    (Op::Add, TypeDef::I32) => f.add_inst(WasmInst::I32Add),
-                    (Op::Add, TypeDef::F64) => f.add_inst(WasmInst::F64Add),
-                    (Op::Sub, TypeDef::I32) => f.add_inst(WasmInst::I32Sub),
-                    (Op::Sub, TypeDef::F64) => f.add_inst(WasmInst::F64Sub),
-                    (Op::Mul, TypeDef::I32) => f.add_inst(WasmInst::I32Mul),
-                    (Op::Mul, TypeDef::F64) => f.add_inst(WasmInst::F64Mul),
-                    (Op::Div, TypeDef::I32) => f.add_inst(WasmInst::I32DivS),
-                    (Op::Div, TypeDef::F64) => f.add_inst(WasmInst::F64DivS),
-                    (Op::Eq, TypeDef::Bool) => f.add_inst(WasmInst::I32Eq),
-                    (Op::Eq, TypeDef::I32) => f.add_inst(WasmInst::I32Eq),
-                    (Op::Eq, TypeDef::F64) => f.add_inst(WasmInst::F64Eq),
-                    (Op::Ne, TypeDef::Bool) => f.add_inst(WasmInst::I32Ne),
-                    (Op::Ne, TypeDef::I32) => f.add_inst(WasmInst::I32Ne),
-                    (Op::Ne, TypeDef::F64) => f.add_inst(WasmInst::F64Ne),
-                    (Op::Ge, TypeDef::I32) => f.add_inst(WasmInst::I32GeS),
-                    (Op::Ge, TypeDef::F64) => f.add_inst(WasmInst::F64GeS),
-                    (Op::Gt, TypeDef::I32) => f.add_inst(WasmInst::I32GtS),
-                    (Op::Gt, TypeDef::F64) => f.add_inst(WasmInst::F64GtS),
-                    (Op::Le, TypeDef::I32) => f.add_inst(WasmInst::I32LeS),
-                    (Op::Le, TypeDef::F64) => f.add_inst(WasmInst::F64LeS),
-                    (Op::Lt, TypeDef::I32) => f.add_inst(WasmInst::I32LtS),
-                    (Op::Lt, TypeDef::F64) => f.add_inst(WasmInst::F64LtS),
-
-                    _ => unimplemented!(),
-                }
-                f.set_local(*var);
-            }
-            Inst::UOp(var, op, a) => {
-                match op {
-                    UOp::Neg => match func.ir.var_type[*a] {
-                        TypeDef::I32 => {
-                            f.add_const_i32(0);
-                            f.get_local(*a);
-                            f.add_inst(WasmInst::I32Sub);
-                        }
-                        TypeDef::F64 => {
-                            f.get_local(*a);
-                            f.add_inst(WasmInst::F64Neg);
-                        }
-                        _ => unimplemented!(),
-                    },
-                    UOp::Not => unimplemented!(),
+
+            let typ = local_type(var);
+            let taken = others
+                .iter()
+                .filter(|other| **other < var)
+                .filter_map(|other| slot_of_var[*other])
+                .collect::<Vec<usize>>();
+
+            let free = (0..slots.len()).find(|slot| slots[*slot].0 == typ && !taken.contains(slot));
+            let slot = free.unwrap_or_else(|| {
+                slots.push((typ, slots.len()));
+                slots.len() - 1
+            });
+            slot_of_var.push(Some(slot));
+        }
+
+        // group the new locals by type
+        let mut types = slots[func.num_params..]
+            .iter()
+            .map(|(typ, _)| *typ)
+            .collect::<Vec<TypeDef>>();
+        types.sort_by_key(|typ| typ.to_wasm());
+        types.dedup();
+
+        let mut local_of_slot = (0..func.num_params).collect::<Vec<usize>>();
+        local_of_slot.resize(slots.len(), 0);
+        let mut next = func.num_params;
+        let mut ordered = vec![];
+        for typ in types {
+            for slot in func.num_params..slots.len() {
+                if slots[slot].0 == typ {
+                    local_of_slot[slot] = next;
+                    ordered.push(typ);
+                    next += 1;
                 }
-                f.set_local(*var);
             }
-            Inst::Const(var, val) => {
-                match val.get_type() {
-                    TypeDef::Bool => f.add_const_i32(if val.as_bool() { 1 } else { 0 }),
-                    TypeDef::F64 => f.add_const_f64(val.as_f64()),
-                    TypeDef::I32 => f.add_const_i32(val.as_i32()),
-                    _ => unimplemented!(),
-                }
-                f.set_local(*var);
+        }
+
+        return Locals {
+            of_var: slot_of_var
+                .iter()
+                .map(|slot| slot.map_or(usize::MAX, |slot| local_of_slot[slot]))
+                .collect(),
+            types: ordered,
+        };
+    }
+
+    /// Get the names of the locals that have any, from the names of the vars
+    /// stored in them.
+    fn names(&self, func: &Func) -> Vec<(usize, String)> {
+        let mut names = vec![vec![]; func.num_params + self.types.len()];
+        for (var, local) in self.of_var.iter().enumerate() {
+            let (Some(names), Some(name)) = (names.get_mut(*local), &func.ir.var_name[var]) else {
+                continue;
+            };
+            if !names.contains(name) {
+                names.push(name.clone());
             }
-            Inst::Return(var) => {
-                f.get_local(*var);
-                f.add_return();
+        }
+
+        return names
+            .into_iter()
+            .enumerate()
+            .filter(|(_, names)| !names.is_empty())
+            .map(|(local, names)| (local, names.join("/")))
+            .collect();
+    }
+}
 
-                return None;
+/// Get the instruction computing each var that can be left on the stack, if
+/// <options.stackify> is set.
+///
+/// A var can be left on the stack if it is used once, later on in the same
+/// block, and everything in between is left on the stack too. Then it is
+/// computed right where it is used, and no locals are assigned in between.
+fn get_stacked<'a>(func: &'a Func, options: &WasmOptions) -> Vec<Option<&'a Inst>> {
+    let mut stacked = vec![None; func.ir.num_vars];
+    if !options.stackify {
+        return stacked;
+    }
+
+    let mut num_uses = vec![0; func.ir.num_vars];
+    for inst in &func.ir.insts {
+        for var in inst.uses() {
+            if var < func.ir.num_vars {
+                num_uses[var] += 1;
             }
-            Inst::Branch(cond, (a, b)) => {
-                f.get_local(*cond);
+        }
+    }
 
-                f.if_block();
-                let a = reloop(f, func, *a);
-                f.else_block();
-                let b = reloop(f, func, *b);
-                f.end_block();
+    for block in 0..func.ir.blocks.len() {
+        let insts = func.ir.block_insts(block);
+        let start = func.ir.blocks[block];
 
-                return match (a, b) {
-                    _ => None,
-                };
+        // later instructions first, whether they are stacked decides the earlier ones
+        for (i, inst) in insts.iter().enumerate().rev() {
+            let Some(var) = inst.def() else {
+                continue;
+            };
+
+            // unit values leave nothing on the stack to use
+            if num_uses[var] != 1 || func.ir.var_type[var] == TypeDef::Unit {
+                continue;
             }
-            Inst::JumpTo(target, args) => {
-                // pass the paramaters
-                let start = func.ir.block_params[*target].0;
-                for i in 0..args.len() {
-                    f.get_local(args[i]);
-                    f.set_local(start + i);
-                }
 
-                // move on to the next block
-                if is_parent_of(func, *target, block) {
-                    f.add_break(1);
-                    return None;
-                } else if dominates(func, block, *target) {
-                    return reloop(f, func, *target);
-                } else {
-                    return Some(*target);
+            let Some(j) = (i + 1..insts.len()).find(|j| insts[*j].uses().contains(&var)) else {
+                continue;
+            };
+
+            let is_stacked = |inst: &Inst| inst.def().is_some_and(|var| stacked[var].is_some());
+
+            let can_stack = match inst {
+                // calls can't be moved past anything, or they might happen in a different order
+                Inst::Call(..) | Inst::CallExtern(..) => {
+                    j == i + 1
+                        && !is_stacked(&insts[j])
+                        && !(options.tail_calls && is_tail_call(func, block, i))
                 }
+                _ => is_pure(func, inst) && insts[i + 1..j].iter().all(is_stacked),
+            };
+
+            if can_stack {
+                stacked[var] = Some(&func.ir.insts[start + i]);
             }
+        }
+    }
+
+    return stacked;
+}
+
+fn build(
+    builder: &mut impl WasmOrWatBuilder,
+    func: &Func,
+    options: &WasmOptions,
+    stacked: &[Option<&Inst>],
+) {
+    let mut reloop = Reloop::new(builder, func, options, stacked);
+    reloop.do_tree(0);
+
+    // every path ends in a return or a break, but wasm doesn't know that after a loop or an if
+    if reloop.falls_off {
+        reloop.f.add_inst(WasmInst::Unreachable)
+    }
+}
+
+/// What a wasm label belongs to, breaking to it goes to the start of a loop or
+/// to the end of a block.
+#[derive(PartialEq)]
+enum Label {
+    If,
+    Loop(Block),
+    Block(Block),
+}
+
+/// Turns the blocks of a function into wasm's structured control flow using
+/// the dominator tree, as in "Beyond Relooper" (Ramsey, 2022). This works for
+/// any reducible control flow graph, which is all the ir can have.
+///
+/// A block jumped to from more than one block before it gets a wasm `block`
+/// that ends right before it, and a block jumped back to gets a wasm `loop`.
+struct Reloop<'a, B: WasmOrWatBuilder> {
+    f: &'a mut B,
+    func: &'a Func,
+    options: &'a WasmOptions,
+    stacked: &'a [Option<&'a Inst>],
+    /// The children of each block in the dominator tree.
+    tree: Vec<Vec<Block>>,
+    /// The position of each block in reverse postorder.
+    order: Vec<usize>,
+    /// Is the block jumped to from more than one block before it?
+    is_merge: Vec<bool>,
+    /// Is the block jumped back to?
+    is_loop: Vec<bool>,
+    /// The labels the code is in, innermost last.
+    labels: Vec<Label>,
+    /// Does wasm think the code so far can run past its end?
+    falls_off: bool,
+}
+
+impl<'a, B: WasmOrWatBuilder> Reloop<'a, B> {
+    fn new(
+        f: &'a mut B,
+        func: &'a Func,
+        options: &'a WasmOptions,
+        stacked: &'a [Option<&'a Inst>],
+    ) -> Self {
+        let mut order = vec![usize::MAX; func.ir.blocks.len()];
+        for (i, block) in get_reverse_postorder(func).into_iter().enumerate() {
+            order[block] = i;
+        }
+
+        let parents = get_parents(func);
+        let forward = |block: Block| {
+            parents[block]
+                .iter()
+                .filter(|parent| order[**parent] < order[block])
+                .count()
         };
+
+        let is_merge = (0..func.ir.blocks.len())
+            .map(|block| forward(block) > 1)
+            .collect();
+        let is_loop = (0..func.ir.blocks.len())
+            .map(|block| forward(block) < parents[block].len())
+            .collect();
+
+        return Reloop {
+            f,
+            func,
+            options,
+            stacked,
+            tree: get_dom_tree(func),
+            order,
+            is_merge,
+            is_loop,
+            labels: vec![],
+            falls_off: true,
+        };
+    }
+
+    /// Add <block> and every block it dominates.
+    fn do_tree(&mut self, block: Block) {
+        // the latest one has to be the outermost, since its code goes last
+        let mut merges = self.tree[block]
+            .iter()
+            .filter(|child| self.is_merge[**child])
+            .copied()
+            .collect::<Vec<Block>>();
+        merges.sort_by_key(|child| std::cmp::Reverse(self.order[*child]));
+
+        if self.is_loop[block] {
+            self.f.start_loop();
+            self.labels.push(Label::Loop(block));
+            self.node_within(block, &merges);
+            self.labels.pop();
+            self.f.close_loop();
+            self.falls_off = true;
+        } else {
+            self.node_within(block, &merges);
+        }
     }
 
-    panic!("Block didn't end!")
+    /// Add <block>, with <merges> following it in the order they're in.
+    fn node_within(&mut self, block: Block, merges: &[Block]) {
+        match merges {
+            [] => self.add_block(block),
+            [merge, rest @ ..] => {
+                self.f.start_block();
+                self.labels.push(Label::Block(*merge));
+                self.node_within(block, rest);
+                self.labels.pop();
+                self.f.close_block();
+                self.falls_off = true;
+
+                self.do_tree(*merge);
+            }
+        }
+    }
+
+    /// Go from the end of <from> to the start of <to>.
+    fn do_branch(&mut self, from: Block, to: Block) {
+        if self.order[to] <= self.order[from] {
+            self.add_break(Label::Loop(to));
+        } else if self.is_merge[to] {
+            self.add_break(Label::Block(to));
+        } else {
+            // only <from> jumps here, so it goes right here
+            self.do_tree(to);
+        }
+    }
+
+    fn add_break(&mut self, label: Label) {
+        let depth = self.labels.iter().rev().position(|l| *l == label);
+        self.f
+            .add_break(depth.expect("the control flow isn't reducible"));
+        self.falls_off = false;
+    }
+
+    fn add_block(&mut self, block: Block) {
+        let (f, func, stacked) = (&mut *self.f, self.func, self.stacked);
+
+        for (index, inst) in func.ir.block_insts(block).iter().enumerate() {
+            match inst {
+                Inst::Call(_, call, args)
+                    if self.options.tail_calls && is_tail_call(func, block, index) =>
+                {
+                    for arg in args {
+                        add_var(f, func, stacked, *arg);
+                    }
+                    f.add_tail_call(f.layout().first_func + *call);
+                    self.falls_off = false;
+
+                    return;
+                }
+                Inst::Call(var, ..)
+                | Inst::CallExtern(var, ..)
+                | Inst::Op(var, ..)
+                | Inst::UOp(var, ..)
+                | Inst::Const(var, _) => {
+                    // this is computed where it is used instead
+                    if stacked[*var].is_some() {
+                        continue;
+                    }
+
+                    add_value(f, func, stacked, inst);
+
+                    // externs returning unit leave nothing to store
+                    if func.ir.var_type[*var] != TypeDef::Unit {
+                        f.set_local(*var);
+                    }
+                }
+                Inst::Return(var) => {
+                    // functions returning unit have nothing to return
+                    if func.return_type != TypeDef::Unit {
+                        add_var(f, func, stacked, *var);
+                    }
+                    f.add_return();
+                    self.falls_off = false;
+
+                    return;
+                }
+                Inst::Branch(cond, (a, b)) => {
+                    add_var(f, func, stacked, *cond);
+
+                    f.if_block();
+                    self.labels.push(Label::If);
+                    self.do_branch(block, *a);
+                    self.f.else_block();
+                    self.do_branch(block, *b);
+                    self.labels.pop();
+                    self.f.end_block();
+                    self.falls_off = true;
+
+                    return;
+                }
+                Inst::JumpTo(target, args) => {
+                    // pass the paramaters, all of them go on the stack first
+                    // because a param can be passed to another param
+                    let start = func.ir.block_params[*target].0;
+                    for arg in args {
+                        add_var(f, func, stacked, *arg);
+                    }
+                    for i in (0..args.len()).rev() {
+                        f.set_local(start + i);
+                    }
+
+                    self.do_branch(block, *target);
+
+                    return;
+                }
+            };
+        }
+
+        panic!("Block didn't end!")
+    }
+}
+
+/// Push the value of <var> onto the stack.
+fn add_var(f: &mut impl WasmOrWatBuilder, func: &Func, stacked: &[Option<&Inst>], var: Var) {
+    match stacked[var] {
+        Some(inst) => add_value(f, func, stacked, inst),
+        None => f.get_local(var),
+    }
+}
+
+/// Push the value <inst> computes onto the stack.
+fn add_value(f: &mut impl WasmOrWatBuilder, func: &Func, stacked: &[Option<&Inst>], inst: &Inst) {
+    match inst {
+        Inst::Call(_, call, args) => {
+            for arg in args {
+                add_var(f, func, stacked, *arg);
+            }
+            f.add_call(f.layout().first_func + *call);
+        }
+        Inst::CallExtern(_, call, args) => {
+            for arg in args {
+                add_var(f, func, stacked, *arg);
+            }
+            f.add_call(f.layout().externs[*call]);
+        }
+        Inst::Op(_, op, a, b) => {
+            let types = (func.ir.var_type[*a], func.ir.var_type[*b]);
+            let inst = match (op, types.0) {
+                _ if types.0 != types.1 => None,
+                (Op::Add, TypeDef::I32) => Some(WasmInst::I32Add),
+                (Op::Add, TypeDef::F64) => Some(WasmInst::F64Add),
+                (Op::Sub, TypeDef::I32) => Some(WasmInst::I32Sub),
+                (Op::Sub, TypeDef::F64) => Some(WasmInst::F64Sub),
+                (Op::Mul, TypeDef::I32) => Some(WasmInst::I32Mul),
+                (Op::Mul, TypeDef::F64) => Some(WasmInst::F64Mul),
+                (Op::Div, TypeDef::I32) => Some(WasmInst::I32DivS),
+                (Op::Div, TypeDef::F64) => Some(WasmInst::F64Div),
+                (Op::Eq, TypeDef::Bool) => Some(WasmInst::I32Eq),
+                (Op::Eq, TypeDef::I32) => Some(WasmInst::I32Eq),
+                (Op::Eq, TypeDef::F64) => Some(WasmInst::F64Eq),
+                (Op::Ne, TypeDef::Bool) => Some(WasmInst::I32Ne),
+                (Op::Ne, TypeDef::I32) => Some(WasmInst::I32Ne),
+                (Op::Ne, TypeDef::F64) => Some(WasmInst::F64Ne),
+                (Op::Ge, TypeDef::I32) => Some(WasmInst::I32GeS),
+                (Op::Ge, TypeDef::F64) => Some(WasmInst::F64Ge),
+                (Op::Gt, TypeDef::I32) => Some(WasmInst::I32GtS),
+                (Op::Gt, TypeDef::F64) => Some(WasmInst::F64Gt),
+                (Op::Le, TypeDef::I32) => Some(WasmInst::I32LeS),
+                (Op::Le, TypeDef::F64) => Some(WasmInst::F64Le),
+                (Op::Lt, TypeDef::I32) => Some(WasmInst::I32LtS),
+                (Op::Lt, TypeDef::F64) => Some(WasmInst::F64Lt),
+                _ => None,
+            };
+
+            // the interpreter traps on ops it has no types for, so wasm does too
+            let Some(inst) = inst else {
+                return f.add_inst(WasmInst::Unreachable);
+            };
+            add_var(f, func, stacked, *a);
+            add_var(f, func, stacked, *b);
+            f.add_inst(inst);
+        }
+        Inst::UOp(_, op, a) => match (op, func.ir.var_type[*a]) {
+            (UOp::Neg, TypeDef::I32) => {
+                f.add_const_i32(0);
+                add_var(f, func, stacked, *a);
+                f.add_inst(WasmInst::I32Sub);
+            }
+            (UOp::Neg, TypeDef::F64) => {
+                add_var(f, func, stacked, *a);
+                f.add_inst(WasmInst::F64Neg);
+            }
+            (UOp::Not, TypeDef::Bool) => {
+                add_var(f, func, stacked, *a);
+                f.add_const_i32(0);
+                f.add_inst(WasmInst::I32Eq);
+            }
+            _ => f.add_inst(WasmInst::Unreachable),
+        },
+        Inst::Const(_, val) => match val.get_type() {
+            TypeDef::Bool => f.add_const_i32(if val.as_bool() { 1 } else { 0 }),
+            TypeDef::Str => f.add_const_i32(f.layout().strings[val.as_str()] as i32),
+            TypeDef::F64 => f.add_const_f64(val.as_f64()),
+            TypeDef::I32 => f.add_const_i32(val.as_i32()),
+            // unit has nothing to push
+            TypeDef::Unit => {}
+        },
+        _ => unreachable!(),
+    }
 }
 
 ///
-struct WasmBuilder {
+struct WasmBuilder<'a> {
     buffer: Vec<u8>,
+    /// The local each var is stored in.
+    locals: Vec<usize>,
+    layout: &'a Layout,
 }
 
-impl WasmBuilder {
-    fn new() -> Self {
-        return WasmBuilder { buffer: vec![] };
+impl<'a> WasmBuilder<'a> {
+    fn new(locals: Vec<usize>, layout: &'a Layout) -> Self {
+        return WasmBuilder {
+            buffer: vec![],
+            locals,
+            layout,
+        };
     }
 }
 
-impl WasmOrWatBuilder for WasmBuilder {
+impl<'a> WasmOrWatBuilder for WasmBuilder<'a> {
+    fn layout(&self) -> &Layout {
+        return self.layout;
+    }
+
     fn start_loop(&mut self) {
         self.buffer.push(0x03); // this is a loop
         self.buffer.push(0x40); // that returns nothing
     }
 
+    fn start_block(&mut self) {
+        self.buffer.push(0x02); // this is a block
+        self.buffer.push(0x40); // that returns nothing
+    }
+
+    fn close_block(&mut self) {
+        self.end_block()
+    }
+
     fn if_block(&mut self) {
         self.buffer.push(0x04); // if
         self.buffer.push(0x40); // returns nothing
@@ -321,12 +1100,12 @@ impl WasmOrWatBuilder for WasmBuilder {
 
     fn get_local(&mut self, var: usize) {
         self.buffer.push(0x20);
-        var.write_leb128(&mut self.buffer);
+        self.locals[var].write_leb128(&mut self.buffer);
     }
 
     fn set_local(&mut self, var: usize) {
         self.buffer.push(0x21);
-        var.write_leb128(&mut self.buffer);
+        self.locals[var].write_leb128(&mut self.buffer);
     }
 
     fn add_break(&mut self, label: usize) {
@@ -334,9 +1113,14 @@ impl WasmOrWatBuilder for WasmBuilder {
         label.write_leb128(&mut self.buffer);
     }
 
-    fn add_func_call(&mut self, func_id: usize) {
+    fn add_call(&mut self, index: usize) {
         self.buffer.push(0x10); // func call inst
-        func_id.write_leb128(&mut self.buffer);
+        index.write_leb128(&mut self.buffer);
+    }
+
+    fn add_tail_call(&mut self, index: usize) {
+        self.buffer.push(0x12); // return_call inst
+        index.write_leb128(&mut self.buffer);
     }
 
     fn add_const_f64(&mut self, value: f64) {
@@ -365,7 +1149,7 @@ impl WasmOrWatBuilder for WasmBuilder {
             WasmInst::F64Add => self.buffer.push(0xA0),
             WasmInst::F64Sub => self.buffer.push(0xA1),
             WasmInst::F64Mul => self.buffer.push(0xA2),
-            WasmInst::F64DivS => self.buffer.push(0xA3),
+            WasmInst::F64Div => self.buffer.push(0xA3),
 
             WasmInst::I32Eq => self.buffer.push(0x46),
             WasmInst::F64Eq => self.buffer.push(0x61),
@@ -378,29 +1162,62 @@ impl WasmOrWatBuilder for WasmBuilder {
             WasmInst::I32LeS => self.buffer.push(0x4C),
             WasmInst::I32LtS => self.buffer.push(0x48),
 
-            WasmInst::F64GeS => self.buffer.push(0x66),
-            WasmInst::F64GtS => self.buffer.push(0x64),
-            WasmInst::F64LeS => self.buffer.push(0x65),
-            WasmInst::F64LtS => self.buffer.push(0x63),
+            WasmInst::F64Ge => self.buffer.push(0x66),
+            WasmInst::F64Gt => self.buffer.push(0x64),
+            WasmInst::F64Le => self.buffer.push(0x65),
+            WasmInst::F64Lt => self.buffer.push(0x63),
 
             WasmInst::F64Neg => self.buffer.push(0x9A),
 
+            WasmInst::I32DivU => self.buffer.push(0x6E),
+            WasmInst::I32RemU => self.buffer.push(0x70),
+            WasmInst::I32And => self.buffer.push(0x71),
+            WasmInst::I32Or => self.buffer.push(0x72),
+
+            WasmInst::I32TruncSatF64S => self.buffer.extend([0xFC, 0x02]),
+            WasmInst::I32TruncSatF64U => self.buffer.extend([0xFC, 0x03]),
+            WasmInst::F64ConvertI32U => self.buffer.push(0xB8),
+            WasmInst::F64Floor => self.buffer.push(0x9C),
+            WasmInst::F64Nearest => self.buffer.push(0x9E),
+
+            // with the alignment and offset
+            WasmInst::I32Load => self.buffer.extend([0x28, 0x02, 0x00]),
+            WasmInst::I32Store => self.buffer.extend([0x36, 0x02, 0x00]),
+            WasmInst::I32Store8 => self.buffer.extend([0x3A, 0x00, 0x00]),
+
+            WasmInst::Drop => self.buffer.push(0x1A),
+
             WasmInst::Unreachable => self.buffer.push(0x00),
         };
     }
 }
 
+/// Get the name of a local in wat.
+fn local_name(local: usize) -> String {
+    return format!("$l{local}");
+}
+
 ///
-struct WatBuilder {
+struct WatBuilder<'a> {
     buffer: Vec<u8>,
     tab: usize,
+    /// The local each var is stored in.
+    locals: Vec<usize>,
+    layout: &'a Layout,
+    folded: bool,
+    /// The folded instructions whose values haven't been used yet.
+    stack: Vec<String>,
 }
 
-impl WatBuilder {
-    fn new() -> Self {
+impl<'a> WatBuilder<'a> {
+    fn new(layout: &'a Layout, locals: Vec<usize>, folded: bool) -> Self {
         return WatBuilder {
             buffer: vec![],
             tab: 2,
+            locals,
+            layout,
+            folded,
+            stack: vec![],
         };
     }
 
@@ -410,96 +1227,201 @@ impl WatBuilder {
         }
         let _ = writeln!(self.buffer, "{content}");
     }
+
+    /// Add an instruction that uses <operands> values, and leaves one if <result>.
+    fn add(&mut self, inst: &str, operands: usize, result: bool) {
+        if !self.folded {
+            self.write(inst);
+            return;
+        }
+
+        let operands = self.take(operands);
+        let folded = if operands.is_empty() {
+            format!("({inst})")
+        } else {
+            format!("({inst} {})", operands.join(" "))
+        };
+
+        if result {
+            self.stack.push(folded);
+        } else {
+            self.flush();
+            self.write(&folded);
+        }
+    }
+
+    /// Take up to <count> of the latest unused values.
+    fn take(&mut self, count: usize) -> Vec<String> {
+        return self.stack.split_off(self.stack.len().saturating_sub(count));
+    }
+
+    /// Write all the unused values, so they run before what comes next.
+    fn flush(&mut self) {
+        for value in std::mem::take(&mut self.stack) {
+            self.write(&value);
+        }
+    }
+
+    fn open(&mut self, flat: &str, folded: &str) {
+        self.flush();
+        self.write(if self.folded { folded } else { flat });
+        self.tab += 1;
+    }
+
+    fn close(&mut self, flat: &str, folded: &str) {
+        self.flush();
+        self.tab -= 1;
+        self.write(if self.folded { folded } else { flat });
+    }
 }
 
-impl WasmOrWatBuilder for WatBuilder {
+impl<'a> WasmOrWatBuilder for WatBuilder<'a> {
+    fn layout(&self) -> &Layout {
+        return self.layout;
+    }
+
     fn start_loop(&mut self) {
-        self.write("(loop");
-        self.tab += 1;
+        self.open("loop", "(loop");
+    }
+
+    fn close_loop(&mut self) {
+        self.close("end", ")");
+    }
+
+    fn start_block(&mut self) {
+        self.open("block", "(block");
+    }
+
+    fn close_block(&mut self) {
+        self.close("end", ")");
     }
 
     fn if_block(&mut self) {
-        self.write("(if (then");
-        self.tab += 1;
+        if self.folded {
+            let cond = self.take(1).join(" ");
+            self.flush();
+            self.write(format!("(if {cond}").trim_end());
+            self.tab += 1;
+        }
+        self.open("if", "(then");
     }
 
     fn else_block(&mut self) {
-        self.tab -= 1;
-        self.write(") (else");
+        self.close("else", ")");
+        if self.folded {
+            self.write("(else");
+        }
         self.tab += 1;
     }
 
     fn end_block(&mut self) {
-        self.tab -= 1;
-        self.write("))");
-    }
-
-    fn close_loop(&mut self) {
-        self.tab -= 1;
-        self.write(")");
+        self.close("end", ")");
+        if self.folded {
+            self.close("", ")");
+        }
     }
 
     fn get_local(&mut self, var: usize) {
-        self.write(&format!("get_local {var}"));
+        self.add(
+            &format!("local.get {}", local_name(self.locals[var])),
+            0,
+            true,
+        );
     }
 
     fn set_local(&mut self, var: usize) {
-        self.write(&format!("set_local {var}"));
+        self.add(
+            &format!("local.set {}", local_name(self.locals[var])),
+            1,
+            false,
+        );
     }
 
     fn add_break(&mut self, label: usize) {
-        self.write(&format!("br {label}"));
+        self.add(&format!("br {label}"), 0, false);
+    }
+
+    fn add_call(&mut self, index: usize) {
+        let func = &self.layout.funcs[index];
+        let inst = format!("call ${}", func.name);
+        self.add(&inst, func.params.len(), func.result != TypeDef::Unit);
     }
 
-    fn add_func_call(&mut self, func_id: usize) {
-        self.write(&format!("call {func_id}"));
+    fn add_tail_call(&mut self, index: usize) {
+        let func = &self.layout.funcs[index];
+        let inst = format!("return_call ${}", func.name);
+        self.add(&inst, func.params.len(), false);
     }
 
     fn add_const_f64(&mut self, value: f64) {
-        self.write(&format!("f64.const {value}"));
+        // wat wants nan in lower case
+        let value = if value.is_nan() {
+            "nan".to_string()
+        } else {
+            format!("{value}")
+        };
+        self.add(&format!("f64.const {value}"), 0, true);
     }
 
     fn add_const_i32(&mut self, value: i32) {
-        self.write(&format!("i32.const {value}"));
+        self.add(&format!("i32.const {value}"), 0, true);
     }
 
     fn add_return(&mut self) {
-        self.write(&format!("return"));
+        self.add("return", 1, false);
     }
 
     fn add_inst(&mut self, inst: WasmInst) {
         match inst {
-            WasmInst::I32Add => self.write("i32.add"),
-            WasmInst::F64Add => self.write("f64.add"),
-            WasmInst::I32Sub => self.write("i32.sub"),
-            WasmInst::F64Sub => self.write("f64.sub"),
-            WasmInst::I32Mul => self.write("i32.mul"),
-            WasmInst::F64Mul => self.write("f64.mul"),
-            WasmInst::I32DivS => self.write("i32.div_s"),
-            WasmInst::F64DivS => self.write("f64.div_s"),
-            WasmInst::I32Eq => self.write("i32.eq"),
-            WasmInst::F64Eq => self.write("f64.eq"),
-            WasmInst::I32Ne => self.write("i32.new"),
-            WasmInst::F64Ne => self.write("f64.ne"),
-            WasmInst::I32GeS => self.write("i32.ge_s"),
-            WasmInst::F64GeS => self.write("f64.ge_s"),
-            WasmInst::I32GtS => self.write("i32.gt_s"),
-            WasmInst::F64GtS => self.write("f64.gt_s"),
-            WasmInst::I32LeS => self.write("i32.le_s"),
-            WasmInst::F64LeS => self.write("f64.le_s"),
-            WasmInst::I32LtS => self.write("i32.lt_s"),
-            WasmInst::F64LtS => self.write("f64.lt_s"),
-            WasmInst::F64Neg => self.write("f64.neg"),
-            WasmInst::Unreachable => self.write("unreachable"),
+            WasmInst::I32Add => self.add("i32.add", 2, true),
+            WasmInst::F64Add => self.add("f64.add", 2, true),
+            WasmInst::I32Sub => self.add("i32.sub", 2, true),
+            WasmInst::F64Sub => self.add("f64.sub", 2, true),
+            WasmInst::I32Mul => self.add("i32.mul", 2, true),
+            WasmInst::F64Mul => self.add("f64.mul", 2, true),
+            WasmInst::I32DivS => self.add("i32.div_s", 2, true),
+            WasmInst::F64Div => self.add("f64.div", 2, true),
+            WasmInst::I32Eq => self.add("i32.eq", 2, true),
+            WasmInst::F64Eq => self.add("f64.eq", 2, true),
+            WasmInst::I32Ne => self.add("i32.ne", 2, true),
+            WasmInst::F64Ne => self.add("f64.ne", 2, true),
+            WasmInst::I32GeS => self.add("i32.ge_s", 2, true),
+            WasmInst::F64Ge => self.add("f64.ge", 2, true),
+            WasmInst::I32GtS => self.add("i32.gt_s", 2, true),
+            WasmInst::F64Gt => self.add("f64.gt", 2, true),
+            WasmInst::I32LeS => self.add("i32.le_s", 2, true),
+            WasmInst::F64Le => self.add("f64.le", 2, true),
+            WasmInst::I32LtS => self.add("i32.lt_s", 2, true),
+            WasmInst::F64Lt => self.add("f64.lt", 2, true),
+            WasmInst::F64Neg => self.add("f64.neg", 1, true),
+            WasmInst::I32DivU => self.add("i32.div_u", 2, true),
+            WasmInst::I32RemU => self.add("i32.rem_u", 2, true),
+            WasmInst::I32And => self.add("i32.and", 2, true),
+            WasmInst::I32Or => self.add("i32.or", 2, true),
+            WasmInst::I32TruncSatF64S => self.add("i32.trunc_sat_f64_s", 1, true),
+            WasmInst::I32TruncSatF64U => self.add("i32.trunc_sat_f64_u", 1, true),
+            WasmInst::F64ConvertI32U => self.add("f64.convert_i32_u", 1, true),
+            WasmInst::F64Floor => self.add("f64.floor", 1, true),
+            WasmInst::F64Nearest => self.add("f64.nearest", 1, true),
+            WasmInst::I32Load => self.add("i32.load", 1, true),
+            WasmInst::I32Store => self.add("i32.store", 2, false),
+            WasmInst::I32Store8 => self.add("i32.store8", 2, false),
+            WasmInst::Drop => self.add("drop", 1, false),
+            WasmInst::Unreachable => self.add("unreachable", 0, false),
         };
     }
 }
 
 ///
-trait WasmOrWatBuilder {
+pub(super) trait WasmOrWatBuilder {
+    fn layout(&self) -> &Layout;
+
     fn start_loop(&mut self);
     fn close_loop(&mut self);
 
+    fn start_block(&mut self);
+    fn close_block(&mut self);
+
     fn if_block(&mut self);
     fn else_block(&mut self);
     fn end_block(&mut self);
@@ -508,7 +1430,8 @@ trait WasmOrWatBuilder {
     fn set_local(&mut self, var: usize);
 
     fn add_break(&mut self, label: usize);
-    fn add_func_call(&mut self, func_id: usize);
+    fn add_call(&mut self, index: usize);
+    fn add_tail_call(&mut self, index: usize);
     fn add_const_i32(&mut self, value: i32);
     fn add_const_f64(&mut self, value: f64);
     fn add_return(&mut self);
@@ -516,7 +1439,7 @@ trait WasmOrWatBuilder {
 }
 
 ///
-enum WasmInst {
+pub(super) enum WasmInst {
     I32Add,
     F64Add,
     I32Sub,
@@ -524,19 +1447,32 @@ enum WasmInst {
     I32Mul,
     F64Mul,
     I32DivS,
-    F64DivS,
+    F64Div,
     I32Eq,
     F64Eq,
     I32Ne,
     F64Ne,
     I32GeS,
-    F64GeS,
+    F64Ge,
     I32GtS,
-    F64GtS,
+    F64Gt,
     I32LeS,
-    F64LeS,
+    F64Le,
     I32LtS,
-    F64LtS,
+    F64Lt,
     F64Neg,
+    I32DivU,
+    I32RemU,
+    I32And,
+    I32Or,
+    I32TruncSatF64S,
+    I32TruncSatF64U,
+    F64ConvertI32U,
+    F64Floor,
+    F64Nearest,
+    I32Load,
+    I32Store,
+    I32Store8,
+    Drop,
     Unreachable,
 }
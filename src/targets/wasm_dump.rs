@@ -0,0 +1,762 @@
+use crate::utils::*;
+
+use std::io::Write;
+
+// value types
+const I32: u8 = 0x7F;
+const I64: u8 = 0x7E;
+const F32: u8 = 0x7D;
+const F64: u8 = 0x7C;
+/// What popping from an unreachable stack gives, it matches any type.
+const ANY: u8 = 0;
+
+/// The most locals a function can have, the same limit web browsers have.
+const MAX_LOCALS: usize = 50000;
+
+fn type_name(typ: u8) -> &'static str {
+    match typ {
+        I32 => "i32",
+        I64 => "i64",
+        F32 => "f32",
+        F64 => "f64",
+        _ => "any",
+    }
+}
+
+fn section_name(id: u8) -> &'static str {
+    match id {
+        0 => "custom",
+        1 => "type",
+        2 => "import",
+        3 => "function",
+        4 => "table",
+        5 => "memory",
+        6 => "global",
+        7 => "export",
+        8 => "start",
+        9 => "element",
+        10 => "code",
+        11 => "data",
+        12 => "data count",
+        _ => "unknown",
+    }
+}
+
+/// Where a section has to be, the data count section goes between the
+/// element and code sections.
+fn section_order(id: u8) -> usize {
+    match id {
+        12 => 95,
+        id => id as usize * 10,
+    }
+}
+
+/// Decode a wasm module, writing every section and instruction with its
+/// offset to <out>, and check that it is well formed and that every function
+/// uses the types on the stack right.
+pub fn dump_wasm(wasm: &[u8], out: &mut impl Write) -> Result<(), String> {
+    let mut dump = Dump {
+        r: Reader::new(wasm),
+        out,
+        types: vec![],
+        funcs: vec![],
+        num_imports: 0,
+        num_memories: 0,
+    };
+
+    let magic = dump.r.read_bytes(4)?;
+    if magic != [0x00, 0x61, 0x73, 0x6D] {
+        return Err(format!("0x0000: bad magic number {magic:02x?}"));
+    }
+    let version = dump.r.read_bytes(4)?;
+    if version != [0x01, 0x00, 0x00, 0x00] {
+        return Err(format!("0x0004: unknown version {version:02x?}"));
+    }
+    dump.line(0, "magic \\0asm, version 1");
+
+    let mut last = 0;
+    let mut num_bodies = None;
+    while !dump.r.is_done() {
+        let start = dump.r.offset;
+        let id = dump.r.read_byte()?;
+        let size = dump.r.read_usize()?;
+        dump.line(
+            start,
+            &format!("section {id} {}, {size} bytes", section_name(id)),
+        );
+
+        if id != 0 {
+            if section_order(id) <= last {
+                return Err(format!(
+                    "{start:#06x}: {} section is out of order",
+                    section_name(id)
+                ));
+            }
+            last = section_order(id);
+        }
+
+        // read the section on its own, so it can't read past its size
+        let mut r = dump.r.split(size)?;
+        std::mem::swap(&mut dump.r, &mut r);
+        match id {
+            0 => dump.custom_section()?,
+            1 => dump.type_section()?,
+            2 => dump.import_section()?,
+            3 => dump.function_section()?,
+            5 => dump.memory_section()?,
+            7 => dump.export_section()?,
+            10 => num_bodies = Some(dump.code_section()?),
+            11 => dump.data_section()?,
+            _ => {
+                return Err(format!(
+                    "{start:#06x}: can't read {} sections",
+                    section_name(id)
+                ))
+            }
+        }
+        std::mem::swap(&mut dump.r, &mut r);
+
+        if !r.is_done() {
+            return Err(r.error(&format!("section ends before its size of {size} bytes")));
+        }
+    }
+
+    let num_funcs = dump.funcs.len() - dump.num_imports;
+    if num_bodies.unwrap_or(0) != num_funcs {
+        return Err(format!(
+            "there are {num_funcs} functions but {} function bodies",
+            num_bodies.unwrap_or(0)
+        ));
+    }
+
+    return Ok(());
+}
+
+/// A function type, its params and results.
+struct FuncType {
+    params: Vec<u8>,
+    results: Vec<u8>,
+}
+
+impl std::fmt::Display for FuncType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names = |types: &[u8]| {
+            types
+                .iter()
+                .map(|typ| type_name(*typ))
+                .collect::<Vec<&str>>()
+                .join(" ")
+        };
+        write!(f, "({}) -> ({})", names(&self.params), names(&self.results))
+    }
+}
+
+struct Dump<'a, W: Write> {
+    r: Reader<'a>,
+    out: &'a mut W,
+    types: Vec<FuncType>,
+    /// The type of every function, the imported ones first.
+    funcs: Vec<usize>,
+    num_imports: usize,
+    num_memories: usize,
+}
+
+impl<'a, W: Write> Dump<'a, W> {
+    fn line(&mut self, offset: usize, content: &str) {
+        let _ = writeln!(self.out, "{offset:06x}: {content}");
+    }
+
+    fn read_type(&mut self) -> Result<u8, String> {
+        let typ = self.r.read_byte()?;
+        return match typ {
+            I32 | I64 | F32 | F64 => Ok(typ),
+            _ => Err(format!(
+                "{:#06x}: unknown value type {typ:#04x}",
+                self.r.offset - 1
+            )),
+        };
+    }
+
+    fn read_type_id(&mut self) -> Result<usize, String> {
+        let id = self.r.read_usize()?;
+        if id >= self.types.len() {
+            return Err(self.r.error(&format!("there is no type {id}")));
+        }
+        return Ok(id);
+    }
+
+    fn read_limits(&mut self) -> Result<String, String> {
+        return match self.r.read_byte()? {
+            0x00 => Ok(format!("min {}", self.r.read_usize()?)),
+            0x01 => Ok(format!(
+                "min {}, max {}",
+                self.r.read_usize()?,
+                self.r.read_usize()?
+            )),
+            flag => Err(self.r.error(&format!("unknown limits {flag:#04x}"))),
+        };
+    }
+
+    fn type_section(&mut self) -> Result<(), String> {
+        let count = self.r.read_usize()?;
+        for i in 0..count {
+            let start = self.r.offset;
+            if self.r.read_byte()? != 0x60 {
+                return Err(format!("{start:#06x}: expected a function type"));
+            }
+
+            let mut typ = FuncType {
+                params: vec![],
+                results: vec![],
+            };
+            for _ in 0..self.r.read_usize()? {
+                typ.params.push(self.read_type()?);
+            }
+            for _ in 0..self.r.read_usize()? {
+                typ.results.push(self.read_type()?);
+            }
+
+            self.line(start, &format!("  type {i}: {typ}"));
+            self.types.push(typ);
+        }
+        return Ok(());
+    }
+
+    fn import_section(&mut self) -> Result<(), String> {
+        let count = self.r.read_usize()?;
+        for _ in 0..count {
+            let start = self.r.offset;
+            let module = self.r.read_name()?;
+            let name = self.r.read_name()?;
+            match self.r.read_byte()? {
+                0x00 => {
+                    let typ = self.read_type_id()?;
+                    let line = format!("  func {} {module}.{name}: type {typ}", self.funcs.len());
+                    self.line(start, &line);
+                    self.funcs.push(typ);
+                    self.num_imports += 1;
+                }
+                0x02 => {
+                    let limits = self.read_limits()?;
+                    self.line(start, &format!("  memory {module}.{name}: {limits}"));
+                    self.num_memories += 1;
+                }
+                kind => return Err(self.r.error(&format!("can't import kind {kind:#04x}"))),
+            }
+        }
+        return Ok(());
+    }
+
+    fn function_section(&mut self) -> Result<(), String> {
+        let count = self.r.read_usize()?;
+        for _ in 0..count {
+            let start = self.r.offset;
+            let typ = self.read_type_id()?;
+            self.line(start, &format!("  func {}: type {typ}", self.funcs.len()));
+            self.funcs.push(typ);
+        }
+        return Ok(());
+    }
+
+    fn memory_section(&mut self) -> Result<(), String> {
+        let count = self.r.read_usize()?;
+        for _ in 0..count {
+            let start = self.r.offset;
+            let limits = self.read_limits()?;
+            self.line(start, &format!("  memory {}: {limits}", self.num_memories));
+            self.num_memories += 1;
+        }
+        return Ok(());
+    }
+
+    fn export_section(&mut self) -> Result<(), String> {
+        let count = self.r.read_usize()?;
+        for _ in 0..count {
+            let start = self.r.offset;
+            let name = self.r.read_name()?;
+            let (kind, len) = match self.r.read_byte()? {
+                0x00 => ("func", self.funcs.len()),
+                0x02 => ("memory", self.num_memories),
+                kind => return Err(self.r.error(&format!("can't export kind {kind:#04x}"))),
+            };
+            let id = self.r.read_usize()?;
+            if id >= len {
+                return Err(self.r.error(&format!("there is no {kind} {id} to export")));
+            }
+            self.line(start, &format!("  export \"{name}\": {kind} {id}"));
+        }
+        return Ok(());
+    }
+
+    fn data_section(&mut self) -> Result<(), String> {
+        let count = self.r.read_usize()?;
+        for i in 0..count {
+            let start = self.r.offset;
+            if self.r.read_byte()? != 0x00 {
+                return Err(self.r.error("can only read active data in memory 0"));
+            }
+            if self.num_memories == 0 {
+                return Err(self.r.error("there is no memory for the data"));
+            }
+
+            // the address is a constant expression
+            if self.r.read_byte()? != 0x41 {
+                return Err(self.r.error("expected an i32.const address"));
+            }
+            let address = self.r.read_i32()?;
+            if self.r.read_byte()? != 0x0B {
+                return Err(self.r.error("expected the end of the address"));
+            }
+
+            let len = self.r.read_usize()?;
+            self.r.read_bytes(len)?;
+            self.line(start, &format!("  data {i}: {len} bytes at {address}"));
+        }
+        return Ok(());
+    }
+
+    fn custom_section(&mut self) -> Result<(), String> {
+        let start = self.r.offset;
+        let name = self.r.read_name()?;
+        self.line(start, &format!("  name \"{name}\""));
+
+        if name != "name" {
+            while !self.r.is_done() {
+                self.r.read_byte()?;
+            }
+            return Ok(());
+        }
+
+        while !self.r.is_done() {
+            let start = self.r.offset;
+            let id = self.r.read_byte()?;
+            let size = self.r.read_usize()?;
+            let mut r = self.r.split(size)?;
+            std::mem::swap(&mut self.r, &mut r);
+
+            match id {
+                0 => {
+                    let name = self.r.read_name()?;
+                    self.line(start, &format!("  module name \"{name}\""));
+                }
+                1 => {
+                    self.line(start, "  function names");
+                    self.name_map("    func")?;
+                }
+                2 => {
+                    self.line(start, "  local names");
+                    for _ in 0..self.r.read_usize()? {
+                        let start = self.r.offset;
+                        let func = self.r.read_usize()?;
+                        self.line(start, &format!("    func {func}"));
+                        self.name_map("      local")?;
+                    }
+                }
+                _ => {
+                    self.line(start, &format!("  names {id}, {size} bytes"));
+                    self.r.read_bytes(size)?;
+                }
+            }
+
+            std::mem::swap(&mut self.r, &mut r);
+            if !r.is_done() {
+                return Err(r.error("name subsection ends before its size"));
+            }
+        }
+        return Ok(());
+    }
+
+    fn name_map(&mut self, kind: &str) -> Result<(), String> {
+        for _ in 0..self.r.read_usize()? {
+            let start = self.r.offset;
+            let id = self.r.read_usize()?;
+            let name = self.r.read_name()?;
+            self.line(start, &format!("{kind} {id} \"{name}\""));
+        }
+        return Ok(());
+    }
+
+    /// Read the code section, and get how many function bodies there are.
+    fn code_section(&mut self) -> Result<usize, String> {
+        let count = self.r.read_usize()?;
+        for i in 0..count {
+            let func = self.num_imports + i;
+            let Some(typ) = self.funcs.get(func).copied() else {
+                return Err(self.r.error(&format!("function {func} has no type")));
+            };
+
+            let start = self.r.offset;
+            let size = self.r.read_usize()?;
+            self.line(start, &format!("  func {func}, {size} bytes"));
+
+            let mut r = self.r.split(size)?;
+            std::mem::swap(&mut self.r, &mut r);
+            let result = self.function_body(typ);
+            std::mem::swap(&mut self.r, &mut r);
+            result?;
+
+            if !r.is_done() {
+                return Err(r.error("function ends before its size"));
+            }
+        }
+        return Ok(count);
+    }
+
+    fn function_body(&mut self, typ: usize) -> Result<(), String> {
+        let mut locals = self.types[typ].params.clone();
+        for _ in 0..self.r.read_usize()? {
+            let start = self.r.offset;
+            let count = self.r.read_usize()?;
+            let typ = self.read_type()?;
+            self.line(start, &format!("    locals {count} {}", type_name(typ)));
+            if locals.len().saturating_add(count) > MAX_LOCALS {
+                return Err(format!("{start:#06x}: more than {MAX_LOCALS} locals"));
+            }
+            locals.resize(locals.len() + count, typ);
+        }
+
+        let mut check = Check {
+            stack: vec![],
+            frames: vec![Frame {
+                kind: Kind::Func,
+                results: self.types[typ].results.clone(),
+                height: 0,
+                unreachable: false,
+            }],
+        };
+
+        while !check.frames.is_empty() {
+            let start = self.r.offset;
+            let inst = self.instruction(&mut check, &locals, typ);
+            match inst {
+                Ok(inst) => self.line(start, &format!("    {inst}")),
+                Err(err) if err.starts_with("0x") => return Err(err),
+                Err(err) => return Err(format!("{start:#06x}: {err}")),
+            }
+        }
+
+        if !self.r.is_done() {
+            return Err(self.r.error("code after the end of the function"));
+        }
+        return Ok(());
+    }
+
+    /// Read an instruction and check its types, then get it as text.
+    fn instruction(
+        &mut self,
+        c: &mut Check,
+        locals: &[u8],
+        func_type: usize,
+    ) -> Result<String, String> {
+        let op = self.r.read_byte()?;
+
+        // instructions that only work on values
+        if let Some((name, params, results)) = numeric(op) {
+            c.pop_all(params)?;
+            c.stack.extend_from_slice(results);
+            return Ok(name.to_string());
+        }
+
+        let inst = match op {
+            0x00 => {
+                c.set_unreachable();
+                "unreachable".to_string()
+            }
+            0x01 => "nop".to_string(),
+            0x02..=0x04 => {
+                let results = match self.r.read_byte()? {
+                    0x40 => vec![],
+                    typ @ (I32 | I64 | F32 | F64) => vec![typ],
+                    typ => return Err(format!("can't read block type {typ:#04x}")),
+                };
+                if op == 0x04 {
+                    c.pop(I32)?;
+                }
+                let name = ["block", "loop", "if"][op as usize - 2];
+                let text = format!(
+                    "{name} {}",
+                    FuncType {
+                        params: vec![],
+                        results: results.clone()
+                    }
+                );
+                c.frames.push(Frame {
+                    kind: [Kind::Block, Kind::Loop, Kind::If][op as usize - 2],
+                    results,
+                    height: c.stack.len(),
+                    unreachable: false,
+                });
+                text
+            }
+            0x05 => {
+                if c.frames.last().unwrap().kind != Kind::If {
+                    return Err("else without an if".to_string());
+                }
+                c.end_frame()?;
+                let frame = c.frames.last_mut().unwrap();
+                frame.kind = Kind::Else;
+                frame.unreachable = false;
+                c.stack.truncate(frame.height);
+                "else".to_string()
+            }
+            0x0B => {
+                // without an else nothing is left on the stack when the condition is false
+                let frame = c.frames.last().unwrap();
+                if frame.kind == Kind::If && !frame.results.is_empty() {
+                    return Err("an if with results needs an else".to_string());
+                }
+                let results = c.end_frame()?;
+                c.frames.pop();
+                c.stack.extend(results);
+                "end".to_string()
+            }
+            0x0C | 0x0D => {
+                let depth = self.r.read_usize()?;
+                if depth >= c.frames.len() {
+                    return Err(format!("there is no label {depth}"));
+                }
+                if op == 0x0D {
+                    c.pop(I32)?;
+                }
+
+                let frame = &c.frames[c.frames.len() - 1 - depth];
+                let types = if frame.kind == Kind::Loop {
+                    vec![]
+                } else {
+                    frame.results.clone()
+                };
+                c.pop_all(&types)?;
+                if op == 0x0D {
+                    c.stack.extend(types);
+                } else {
+                    c.set_unreachable();
+                }
+                format!("{} {depth}", if op == 0x0C { "br" } else { "br_if" })
+            }
+            0x0F => {
+                c.pop_all(&self.types[func_type].results)?;
+                c.set_unreachable();
+                "return".to_string()
+            }
+            0x10 | 0x12 => {
+                let func = self.r.read_usize()?;
+                let Some(typ) = self.funcs.get(func) else {
+                    return Err(format!("there is no function {func}"));
+                };
+                let typ = &self.types[*typ];
+                c.pop_all(&typ.params)?;
+                if op == 0x10 {
+                    c.stack.extend_from_slice(&typ.results);
+                    format!("call {func}")
+                } else {
+                    if typ.results != self.types[func_type].results {
+                        return Err(format!(
+                            "function {func} returns the wrong type for a tail call"
+                        ));
+                    }
+                    c.set_unreachable();
+                    format!("return_call {func}")
+                }
+            }
+            0x1A => {
+                c.pop(ANY)?;
+                "drop".to_string()
+            }
+            0x20..=0x22 => {
+                let local = self.r.read_usize()?;
+                let Some(typ) = locals.get(local).copied() else {
+                    return Err(format!("there is no local {local}"));
+                };
+                match op {
+                    0x20 => c.stack.push(typ),
+                    0x21 => c.pop(typ)?,
+                    _ => {
+                        c.pop(typ)?;
+                        c.stack.push(typ);
+                    }
+                }
+                let name = ["local.get", "local.set", "local.tee"][op as usize - 0x20];
+                format!("{name} {local}")
+            }
+            0x28 | 0x2B | 0x36 | 0x39 | 0x3A => {
+                if self.num_memories == 0 {
+                    return Err("there is no memory".to_string());
+                }
+                let align = self.r.read_usize()?;
+                let offset = self.r.read_usize()?;
+                let (name, params, results): (&str, &[u8], &[u8]) = match op {
+                    0x28 => ("i32.load", &[I32], &[I32]),
+                    0x2B => ("f64.load", &[I32], &[F64]),
+                    0x36 => ("i32.store", &[I32, I32], &[]),
+                    0x39 => ("f64.store", &[I32, F64], &[]),
+                    _ => ("i32.store8", &[I32, I32], &[]),
+                };
+                c.pop_all(params)?;
+                c.stack.extend_from_slice(results);
+                format!("{name} align={align} offset={offset}")
+            }
+            0x41 => {
+                c.stack.push(I32);
+                format!("i32.const {}", self.r.read_i32()?)
+            }
+            0x44 => {
+                c.stack.push(F64);
+                format!("f64.const {}", self.r.read_f64()?)
+            }
+            0xFC => {
+                let (name, params, results): (&str, &[u8], &[u8]) = match self.r.read_usize()? {
+                    0x02 => ("i32.trunc_sat_f64_s", &[F64], &[I32]),
+                    0x03 => ("i32.trunc_sat_f64_u", &[F64], &[I32]),
+                    op => return Err(format!("unknown instruction 0xfc {op:#04x}")),
+                };
+                c.pop_all(params)?;
+                c.stack.extend_from_slice(results);
+                name.to_string()
+            }
+            _ => return Err(format!("unknown instruction {op:#04x}")),
+        };
+
+        return Ok(inst);
+    }
+}
+
+/// Get the name and types of an instruction that only works on values.
+fn numeric(op: u8) -> Option<(&'static str, &'static [u8], &'static [u8])> {
+    const I32_CMP: [&str; 10] = [
+        "i32.eq", "i32.ne", "i32.lt_s", "i32.lt_u", "i32.gt_s", "i32.gt_u", "i32.le_s", "i32.le_u",
+        "i32.ge_s", "i32.ge_u",
+    ];
+    const F64_CMP: [&str; 6] = ["f64.eq", "f64.ne", "f64.lt", "f64.gt", "f64.le", "f64.ge"];
+    const I32_BIN: [&str; 15] = [
+        "i32.add",
+        "i32.sub",
+        "i32.mul",
+        "i32.div_s",
+        "i32.div_u",
+        "i32.rem_s",
+        "i32.rem_u",
+        "i32.and",
+        "i32.or",
+        "i32.xor",
+        "i32.shl",
+        "i32.shr_s",
+        "i32.shr_u",
+        "i32.rotl",
+        "i32.rotr",
+    ];
+    const F64_UN: [&str; 7] = [
+        "f64.abs",
+        "f64.neg",
+        "f64.ceil",
+        "f64.floor",
+        "f64.trunc",
+        "f64.nearest",
+        "f64.sqrt",
+    ];
+    const F64_BIN: [&str; 7] = [
+        "f64.add",
+        "f64.sub",
+        "f64.mul",
+        "f64.div",
+        "f64.min",
+        "f64.max",
+        "f64.copysign",
+    ];
+
+    let i = op as usize;
+    return match op {
+        0x45 => Some(("i32.eqz", &[I32], &[I32])),
+        0x46..=0x4F => Some((I32_CMP[i - 0x46], &[I32, I32], &[I32])),
+        0x61..=0x66 => Some((F64_CMP[i - 0x61], &[F64, F64], &[I32])),
+        0x6A..=0x78 => Some((I32_BIN[i - 0x6A], &[I32, I32], &[I32])),
+        0x99..=0x9F => Some((F64_UN[i - 0x99], &[F64], &[F64])),
+        0xA0..=0xA6 => Some((F64_BIN[i - 0xA0], &[F64, F64], &[F64])),
+        0xAA => Some(("i32.trunc_f64_s", &[F64], &[I32])),
+        0xAB => Some(("i32.trunc_f64_u", &[F64], &[I32])),
+        0xB7 => Some(("f64.convert_i32_s", &[I32], &[F64])),
+        0xB8 => Some(("f64.convert_i32_u", &[I32], &[F64])),
+        _ => None,
+    };
+}
+
+/// What a frame was started by.
+#[derive(Clone, Copy, PartialEq)]
+enum Kind {
+    Func,
+    Block,
+    Loop,
+    If,
+    /// The else of an if.
+    Else,
+}
+
+/// A block, loop, if or the function itself.
+struct Frame {
+    kind: Kind,
+    results: Vec<u8>,
+    /// How many values were on the stack when it started.
+    height: usize,
+    /// Has it branched away or returned, so anything can be on the stack?
+    unreachable: bool,
+}
+
+/// The types on the stack while checking a function.
+struct Check {
+    stack: Vec<u8>,
+    frames: Vec<Frame>,
+}
+
+impl Check {
+    fn pop(&mut self, expected: u8) -> Result<(), String> {
+        let frame = self.frames.last().unwrap();
+        if self.stack.len() == frame.height {
+            if frame.unreachable {
+                return Ok(());
+            }
+            return Err(format!(
+                "expected {} but the stack is empty",
+                type_name(expected)
+            ));
+        }
+
+        let found = self.stack.pop().unwrap();
+        if expected != ANY && found != expected {
+            return Err(format!(
+                "expected {} but found {}",
+                type_name(expected),
+                type_name(found)
+            ));
+        }
+        return Ok(());
+    }
+
+    /// Pop values of <types>, the last one is on top of the stack.
+    fn pop_all(&mut self, types: &[u8]) -> Result<(), String> {
+        for typ in types.iter().rev() {
+            self.pop(*typ)?;
+        }
+        return Ok(());
+    }
+
+    fn set_unreachable(&mut self) {
+        let frame = self.frames.last_mut().unwrap();
+        frame.unreachable = true;
+        self.stack.truncate(frame.height);
+    }
+
+    /// Check that exactly the results of the innermost frame are on the stack,
+    /// and get them.
+    fn end_frame(&mut self) -> Result<Vec<u8>, String> {
+        let results = self.frames.last().unwrap().results.clone();
+        self.pop_all(&results)?;
+
+        let frame = self.frames.last().unwrap();
+        if self.stack.len() != frame.height {
+            let extra = self.stack.len() - frame.height;
+            return Err(format!(
+                "{extra} values are left on the stack at the end of a block"
+            ));
+        }
+        return Ok(results);
+    }
+}
@@ -0,0 +1,173 @@
+use crate::core::*;
+
+/// What the externs of a running wasm module can get at.
+struct Host<'m, 'a> {
+    module: &'m Module<'a>,
+}
+
+impl<'a> Module<'a> {
+    /// Compile the module to wasm and run the function <name> with wasmtime,
+    /// calling the host functions registered for the externs.
+    ///
+    /// Wasmtime charges about one fuel per wasm instruction, and has its own
+    /// limit on how deep calls go, so only the fuel of the limits is used.
+    pub fn exec_wasm(&self, name: &str, args: Vec<Value>) -> Result<Value, RuntimeError> {
+        let func = self.get_callable(name, &args)?;
+        let error = |trap| RuntimeError {
+            trap,
+            stack: vec![],
+        };
+
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(self.limits.fuel.is_some());
+        let engine = wasmtime::Engine::new(&config).map_err(|err| error(wasm_trap(err)))?;
+        let wasm_module =
+            wasmtime::Module::new(&engine, self.to_wasm()).map_err(|err| error(wasm_trap(err)))?;
+
+        let mut store = wasmtime::Store::new(&engine, Host { module: self });
+        if let Some(fuel) = self.limits.fuel {
+            store.add_fuel(fuel).map_err(|err| error(wasm_trap(err)))?;
+        }
+
+        // every extern calls the host function the interpreter would
+        let mut linker = wasmtime::Linker::new(&engine);
+        for (extern_id, extern_def) in self.externs.iter().enumerate() {
+            let params = extern_def
+                .params
+                .iter()
+                .flat_map(|param| val_type(param.param_type));
+            let results = val_type(extern_def.return_type);
+            let ty = wasmtime::FuncType::new(params, results);
+
+            linker
+                .func_new(
+                    &extern_def.module,
+                    &extern_def.name,
+                    ty,
+                    move |mut caller: wasmtime::Caller<'_, Host>, params, results| {
+                        let module = caller.data().module;
+                        let memory = caller
+                            .get_export("memory")
+                            .and_then(|export| export.into_memory());
+                        let memory = match memory {
+                            Some(memory) => memory.data(&caller),
+                            None => &[],
+                        };
+
+                        let extern_def = &module.externs[extern_id];
+                        let args = extern_def
+                            .params
+                            .iter()
+                            .zip(params)
+                            .map(|(param, val)| from_val(param.param_type, val, memory))
+                            .collect::<Vec<Value>>();
+
+                        let value = call_extern(module, extern_id, &args)?;
+                        if let Some(result) = results.first_mut() {
+                            *result = to_val(&value)?;
+                        }
+                        return Ok(());
+                    },
+                )
+                .map_err(|err| error(wasm_trap(err)))?;
+        }
+
+        let instance = linker
+            .instantiate(&mut store, &wasm_module)
+            .map_err(|err| error(wasm_trap(err)))?;
+        let Some(wasm_func) = instance.get_func(&mut store, name) else {
+            return Err(error(Trap::UnknownFunction(name.to_string())));
+        };
+
+        let params = args
+            .iter()
+            .map(to_val)
+            .collect::<Result<Vec<wasmtime::Val>, Trap>>()
+            .map_err(error)?;
+        // wasmtime writes over the results, so what they start as doesn't matter
+        let mut results = vec![wasmtime::Val::I32(0); val_type(func.return_type).iter().count()];
+
+        if let Err(err) = wasm_func.call(&mut store, &params, &mut results) {
+            // the functions that were running, the one that trapped first
+            let stack = match err.downcast_ref::<wasmtime::WasmBacktrace>() {
+                Some(backtrace) => backtrace
+                    .frames()
+                    .iter()
+                    .filter_map(|frame| frame.func_name())
+                    .map(|name| name.to_string())
+                    .collect(),
+                None => vec![],
+            };
+            return Err(RuntimeError {
+                trap: wasm_trap(err),
+                stack,
+            });
+        }
+
+        let memory = match instance.get_memory(&mut store, "memory") {
+            Some(memory) => memory.data(&store),
+            None => &[],
+        };
+        return Ok(match results.first() {
+            Some(result) => from_val(func.return_type, result, memory),
+            None => Value::Unit,
+        });
+    }
+}
+
+/// Get the wasm type of an atlas type, unit has none.
+fn val_type(typ: TypeDef) -> Option<wasmtime::ValType> {
+    return match typ {
+        TypeDef::Unit => None,
+        TypeDef::F64 => Some(wasmtime::ValType::F64),
+        _ => Some(wasmtime::ValType::I32),
+    };
+}
+
+fn to_val(value: &Value) -> Result<wasmtime::Val, Trap> {
+    return match value {
+        Value::I32(value) => Ok(wasmtime::Val::I32(*value)),
+        Value::Bool(value) => Ok(wasmtime::Val::I32(*value as i32)),
+        Value::F64(value) => Ok(wasmtime::Val::F64(value.to_bits())),
+        // there's nowhere to put them in the module's memory
+        Value::Str(_) | Value::Unit => {
+            let msg = format!("{:?} can't be passed to wasm", value.get_type());
+            Err(Trap::TypeMismatch(msg))
+        }
+    };
+}
+
+/// Get the atlas value of <val>, strings are read from <memory>.
+fn from_val(typ: TypeDef, val: &wasmtime::Val, memory: &[u8]) -> Value {
+    return match (typ, val) {
+        (TypeDef::I32, wasmtime::Val::I32(value)) => Value::i32(*value),
+        (TypeDef::Bool, wasmtime::Val::I32(value)) => Value::bool(*value != 0),
+        (TypeDef::F64, wasmtime::Val::F64(bits)) => Value::f64(f64::from_bits(*bits)),
+        (TypeDef::Str, wasmtime::Val::I32(address)) => {
+            // strings start with their length
+            let start = *address as usize + 4;
+            let len = memory.get(start - 4..start).map_or(0, |len| {
+                u32::from_le_bytes(len.try_into().unwrap()) as usize
+            });
+            let bytes = memory.get(start..start + len).unwrap_or(&[]);
+            Value::str(&String::from_utf8_lossy(bytes))
+        }
+        _ => Value::Unit,
+    };
+}
+
+/// Get the trap the interpreter would raise for <err>, or the error wasmtime gave.
+fn wasm_trap(err: anyhow::Error) -> Trap {
+    if let Some(trap) = err.downcast_ref::<Trap>() {
+        return trap.clone();
+    }
+    return match err.downcast_ref::<wasmtime::Trap>() {
+        Some(wasmtime::Trap::IntegerDivisionByZero) => Trap::DivideByZero,
+        Some(wasmtime::Trap::IntegerOverflow) => Trap::IntegerOverflow,
+        Some(wasmtime::Trap::StackOverflow) => Trap::StackOverflow,
+        Some(wasmtime::Trap::OutOfFuel) => Trap::OutOfFuel,
+        Some(wasmtime::Trap::UnreachableCodeReached) => Trap::Unreachable,
+        Some(trap) => Trap::Wasm(trap.to_string()),
+        None => Trap::Wasm(err.to_string()),
+    };
+}
@@ -1,6 +1,4 @@
-use crate::ir::*;
-
-use std::collections::HashSet;
+use crate::core::*;
 
 /// Get the list of immidate children of <block>.
 pub fn get_children(func: &Func, block: Block) -> Vec<usize> {
@@ -12,59 +10,208 @@ pub fn get_children(func: &Func, block: Block) -> Vec<usize> {
     }
 }
 
-/// Does <a> lead into <b>?
-pub fn is_parent_of(func: &Func, a: Block, b: Block) -> bool {
-    let mut todo = vec![a];
-    let mut seen = HashSet::new();
+/// Get the final instruction of a block.
+pub fn get_exit_inst(func: &Func, block: Block) -> Inst {
+    for inst in &func.ir.insts[func.ir.blocks[block]..] {
+        match inst {
+            Inst::Return(..) | Inst::Branch(..) | Inst::JumpTo(..) => return inst.clone(),
+            _ => {}
+        };
+    }
 
-    while let Some(block) = todo.pop() {
+    panic!("Block didn't end!")
+}
+
+/// Get the blocks that can jump to each block.
+pub fn get_parents(func: &Func) -> Vec<Vec<Block>> {
+    let mut parents = vec![vec![]; func.ir.blocks.len()];
+
+    for block in get_reverse_postorder(func) {
         for child in get_children(func, block) {
-            if child == b {
-                return true;
-            } else if !seen.contains(&b) {
-                seen.insert(b);
-                todo.push(b);
-            }
+            parents[child].push(block);
         }
     }
 
-    return false;
+    return parents;
 }
 
-/// Do all path to <b> go throght <a>?
-pub fn dominates(_f: &Func, _a: Block, _b: Block) -> bool {
-    return true; // TODO: !!!
+/// Get the reachable blocks, each one before all of its children (ignoring back edges).
+pub fn get_reverse_postorder(func: &Func) -> Vec<Block> {
+    let mut order = vec![];
+    let mut seen = vec![false; func.ir.blocks.len()];
+    let mut todo = vec![(0, false)];
+
+    while let Some((block, done)) = todo.pop() {
+        if done {
+            order.push(block);
+        } else if !seen[block] {
+            seen[block] = true;
+            todo.push((block, true));
+            for child in get_children(func, block).into_iter().rev() {
+                todo.push((child, false));
+            }
+        }
+    }
+
+    order.reverse();
+    return order;
 }
 
-/// Does a child of <block> point towards <block>?
-pub fn is_loop(f: &Func, block: Block) -> bool {
-    for inst in &f.ir.insts[f.ir.blocks[block]..] {
-        match inst {
-            Inst::Branch(_, (a, b)) => {
-                if *a == block || *b == block {
-                    return true;
+/// Get the immediate dominator of every block.
+///
+/// The entry block and unreachable blocks don't have one.
+pub fn get_idoms(func: &Func) -> Vec<Option<Block>> {
+    let order = get_reverse_postorder(func);
+    let parents = get_parents(func);
+
+    let mut index = vec![usize::MAX; func.ir.blocks.len()];
+    for (i, block) in order.iter().enumerate() {
+        index[*block] = i;
+    }
+
+    let mut idoms = vec![None; func.ir.blocks.len()];
+    idoms[0] = Some(0);
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for block in order.iter().skip(1) {
+            let mut new_idom: Option<Block> = None;
+
+            for parent in &parents[*block] {
+                if idoms[*parent].is_none() {
+                    continue;
                 }
+
+                new_idom = Some(match new_idom {
+                    None => *parent,
+                    Some(mut a) => {
+                        // walk up the tree until the two paths meet
+                        let mut b = *parent;
+                        while a != b {
+                            while index[a] > index[b] {
+                                a = idoms[a].unwrap();
+                            }
+                            while index[b] > index[a] {
+                                b = idoms[b].unwrap();
+                            }
+                        }
+                        a
+                    }
+                });
+            }
+
+            if new_idom != idoms[*block] {
+                idoms[*block] = new_idom;
+                changed = true;
+            }
+        }
+    }
+
+    idoms[0] = None;
+    return idoms;
+}
+
+/// Get the blocks immediately dominated by each block.
+pub fn get_dom_tree(func: &Func) -> Vec<Vec<Block>> {
+    let mut tree = vec![vec![]; func.ir.blocks.len()];
+    let idoms = get_idoms(func);
+
+    for block in get_reverse_postorder(func) {
+        if let Some(idom) = idoms[block] {
+            tree[idom].push(block);
+        }
+    }
+
+    return tree;
+}
+
+/// Get the natural loops, each one is the header block and every block in the
+/// loop (including the header).
+pub fn get_loops(func: &Func) -> Vec<(Block, Vec<Block>)> {
+    let idoms = get_idoms(func);
+    let parents = get_parents(func);
+
+    let mut loops: Vec<(Block, Vec<Block>)> = vec![];
+
+    for block in get_reverse_postorder(func) {
+        for header in get_children(func, block) {
+            // a jump back to a block that dominates this one is a back edge
+            if !is_dominated_by(&idoms, block, header) {
+                continue;
             }
-            Inst::JumpTo(target, _) => {
-                if *target == block {
-                    return true;
+
+            let body = match loops.iter_mut().find(|(h, _)| *h == header) {
+                Some((_, body)) => body,
+                None => {
+                    loops.push((header, vec![header]));
+                    &mut loops.last_mut().unwrap().1
+                }
+            };
+
+            // everything that can reach the back edge without going through the header
+            let mut todo = vec![block];
+            while let Some(block) = todo.pop() {
+                if !body.contains(&block) {
+                    body.push(block);
+                    todo.extend(&parents[block]);
                 }
             }
-            _ => {}
         }
     }
 
-    return false;
+    return loops;
 }
 
-/// Get the final instruction of a block.
-pub fn get_exit_inst(func: &Func, block: Block) -> Inst {
-    for inst in &func.ir.insts[func.ir.blocks[block]..] {
-        match inst {
-            Inst::Return(..) | Inst::Branch(..) | Inst::JumpTo(..) => return inst.clone(),
-            _ => {}
-        };
+/// Does every path from the entry block to <block> go through <dom>?
+pub fn is_dominated_by(idoms: &[Option<Block>], mut block: Block, dom: Block) -> bool {
+    loop {
+        if block == dom {
+            return true;
+        }
+
+        match idoms[block] {
+            Some(idom) => block = idom,
+            None => return false,
+        }
     }
+}
 
-    panic!("Block didn't end!")
+/// Can the instruction be removed or moved around without changing what the program does?
+pub fn is_pure(func: &Func, inst: &Inst) -> bool {
+    match inst {
+        // integer division traps when dividing by zero
+        Inst::Op(_, Op::Div, a, _) => func.ir.var_type[*a] != TypeDef::I32,
+        Inst::Op(..) | Inst::UOp(..) | Inst::Const(..) => true,
+        _ => false,
+    }
+}
+
+/// Is the call at <index> in <block> the last thing the function does before
+/// returning its result?
+pub fn is_tail_call(func: &Func, block: Block, index: usize) -> bool {
+    let insts = func.ir.block_insts(block);
+    match (&insts[index], insts.get(index + 1)) {
+        (Inst::Call(var, ..), Some(next)) => returns_var(func, next, *var, func.ir.blocks.len()),
+        _ => false,
+    }
+}
+
+/// Does <inst> return <var> straight away, or jump to blocks that only pass it along until
+/// it gets returned? <depth> limits how many blocks are followed.
+fn returns_var(func: &Func, inst: &Inst, var: Var, depth: usize) -> bool {
+    match inst {
+        Inst::Return(ret) => *ret == var,
+        Inst::JumpTo(target, args) if depth > 0 => {
+            let insts = func.ir.block_insts(*target);
+            let first_param = func.ir.block_params[*target].0;
+
+            insts.len() == 1
+                && args.iter().enumerate().any(|(i, arg)| {
+                    *arg == var && returns_var(func, &insts[0], first_param + i, depth - 1)
+                })
+        }
+        _ => false,
+    }
 }
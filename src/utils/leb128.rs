@@ -46,3 +46,102 @@ impl Leb128 for i32 {
         }
     }
 }
+
+/// Reads the values written to a wasm module back, keeping track of where it is.
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    pub offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        return Reader { bytes, offset: 0 };
+    }
+
+    pub fn is_done(&self) -> bool {
+        return self.offset >= self.bytes.len();
+    }
+
+    /// Make an error that says where it happened.
+    pub fn error(&self, message: &str) -> String {
+        return format!("{:#06x}: {message}", self.offset);
+    }
+
+    pub fn read_byte(&mut self) -> Result<u8, String> {
+        let Some(byte) = self.bytes.get(self.offset) else {
+            return Err(self.error("unexpected end"));
+        };
+        self.offset += 1;
+        return Ok(*byte);
+    }
+
+    pub fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.offset < len {
+            return Err(self.error(&format!("expected {len} more bytes")));
+        }
+        self.offset += len;
+        return Ok(&self.bytes[self.offset - len..self.offset]);
+    }
+
+    /// Read an unsigned LEB128 number of up to 32 bits.
+    pub fn read_usize(&mut self) -> Result<usize, String> {
+        let mut value = 0;
+        for shift in (0..35).step_by(7) {
+            let byte = self.read_byte()?;
+            value |= ((byte & !CONTINUATION_BIT) as usize) << shift;
+
+            if byte & CONTINUATION_BIT == 0 {
+                return match u32::try_from(value) {
+                    Ok(_) => Ok(value),
+                    Err(_) => Err(self.error("number is too big")),
+                };
+            }
+        }
+        return Err(self.error("number is too long"));
+    }
+
+    /// Read a signed LEB128 number of up to 32 bits.
+    pub fn read_i32(&mut self) -> Result<i32, String> {
+        let mut value: i64 = 0;
+        for shift in (0..35).step_by(7) {
+            let byte = self.read_byte()?;
+            value |= ((byte & !CONTINUATION_BIT) as i64) << shift;
+
+            if byte & CONTINUATION_BIT == 0 {
+                // the sign is the highest bit that was read
+                if byte & 0b1000000 != 0 {
+                    value |= -1 << (shift + 7);
+                }
+                return match i32::try_from(value) {
+                    Ok(value) => Ok(value),
+                    Err(_) => Err(self.error("number is too big")),
+                };
+            }
+        }
+        return Err(self.error("number is too long"));
+    }
+
+    pub fn read_f64(&mut self) -> Result<f64, String> {
+        let bytes = self.read_bytes(8)?;
+        return Ok(f64::from_le_bytes(bytes.try_into().unwrap()));
+    }
+
+    pub fn read_name(&mut self) -> Result<String, String> {
+        let len = self.read_usize()?;
+        let bytes = self.read_bytes(len)?;
+        return match std::str::from_utf8(bytes) {
+            Ok(name) => Ok(name.to_string()),
+            Err(_) => Err(self.error("name isn't utf-8")),
+        };
+    }
+
+    /// Split off a reader for the next <len> bytes.
+    pub fn split(&mut self, len: usize) -> Result<Reader<'a>, String> {
+        let start = self.offset;
+        self.read_bytes(len)?;
+        return Ok(Reader {
+            bytes: &self.bytes[..start + len],
+            offset: start,
+        });
+    }
+}
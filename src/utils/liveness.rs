@@ -0,0 +1,93 @@
+use crate::core::*;
+use crate::utils::*;
+
+use std::collections::HashSet;
+
+/// Get the vars that are live at the start of each block, that is the vars
+/// that might be read before they are assigned again.
+pub fn get_live_in(func: &Func) -> Vec<HashSet<Var>> {
+    let mut live_in = vec![HashSet::new(); func.ir.blocks.len()];
+    let order = get_reverse_postorder(func);
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+
+        for block in order.iter().rev() {
+            let mut live = get_live_out(func, &live_in, *block);
+
+            for inst in func.ir.block_insts(*block).iter().rev() {
+                if let Some(var) = inst.def() {
+                    live.remove(&var);
+                }
+                live.extend(inst.uses());
+            }
+
+            let (first_param, num_params) = func.ir.block_params[*block];
+            for param in first_param..first_param + num_params {
+                live.remove(&param);
+            }
+
+            if live != live_in[*block] {
+                live_in[*block] = live;
+                changed = true;
+            }
+        }
+    }
+
+    return live_in;
+}
+
+/// Get the vars that are live at the end of <block>.
+fn get_live_out(func: &Func, live_in: &[HashSet<Var>], block: Block) -> HashSet<Var> {
+    return get_children(func, block)
+        .into_iter()
+        .flat_map(|child| live_in[child].iter().copied())
+        .collect();
+}
+
+/// Get the vars each var is live at the same time as, two vars that interfere
+/// can't be stored in the same place.
+pub fn get_interference(func: &Func) -> Vec<HashSet<Var>> {
+    let live_in = get_live_in(func);
+    let mut graph = vec![HashSet::new(); func.ir.num_vars];
+
+    let mut add_edge = |a: Var, b: Var| {
+        if a != b && a < func.ir.num_vars && b < func.ir.num_vars {
+            graph[a].insert(b);
+            graph[b].insert(a);
+        }
+    };
+
+    // the function params are all assigned at once
+    for a in 0..func.num_params {
+        for b in 0..func.num_params {
+            add_edge(a, b);
+        }
+    }
+
+    for block in get_reverse_postorder(func) {
+        let mut live = get_live_out(func, &live_in, block);
+
+        for inst in func.ir.block_insts(block).iter().rev() {
+            if let Some(var) = inst.def() {
+                for other in &live {
+                    add_edge(var, *other);
+                }
+                live.remove(&var);
+            }
+            live.extend(inst.uses());
+        }
+
+        // block params are all assigned at once, when the block is jumped to
+        let (first_param, num_params) = func.ir.block_params[block];
+        live.extend(first_param..first_param + num_params);
+        for param in first_param..first_param + num_params {
+            for other in &live {
+                add_edge(param, *other);
+            }
+        }
+    }
+
+    return graph;
+}
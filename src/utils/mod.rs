@@ -1,7 +1,7 @@
 mod func_utils;
 mod leb128;
-mod mem;
+mod liveness;
 
 pub use func_utils::*;
 pub use leb128::*;
-pub use mem::*;
+pub use liveness::*;